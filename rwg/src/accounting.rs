@@ -0,0 +1,465 @@
+//! Persistent traffic accounting and transfer quotas.
+//!
+//! An `Accounting` database adds the traffic of each peer to daily and monthly usage buckets every
+//! time it records a device. It keeps the last counters of each peer, so that counter resets, such
+//! as the ones caused by a reboot, do not lose or double count traffic. Periods follow UTC dates.
+//!
+//! The database is a text file, replaced atomically when saved:
+//!
+//! ```text
+//! peer <public key> <last rx bytes> <last tx bytes>
+//! usage <public key> <YYYY-MM-DD | YYYY-MM> <rx bytes> <tx bytes>
+//! quota <public key> <daily | monthly> <bytes> <remove | clear>
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backend::{Backend, System};
+use crate::device::Device;
+use crate::key::Key;
+use crate::peer::Peer;
+
+/// A period over which usage is accounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Period {
+    /// A UTC day.
+    Daily,
+    /// A UTC month.
+    Monthly,
+}
+
+impl Period {
+    /// Get the identifier of the period containing `time`: `YYYY-MM-DD` or `YYYY-MM`.
+    pub fn id(&self, time: SystemTime) -> String {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let (year, month, day) = civil_date(secs / 86400);
+
+        match self {
+            Period::Daily => format!("{:04}-{:02}-{:02}", year, month, day),
+            Period::Monthly => format!("{:04}-{:02}", year, month),
+        }
+    }
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Period::Daily => "daily",
+            Period::Monthly => "monthly",
+        })
+    }
+}
+
+impl FromStr for Period {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Period> {
+        match s {
+            "daily" => Ok(Period::Daily),
+            "monthly" => Ok(Period::Monthly),
+            _ => Err(invalid(format!("Invalid period: {}", s))),
+        }
+    }
+}
+
+/// An amount of traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl Usage {
+    /// Get the amount of bytes received from the peer.
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx_bytes
+    }
+
+    /// Get the amount of bytes sent to the peer.
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes
+    }
+
+    /// Get the amount of bytes received and sent.
+    pub fn total(&self) -> u64 {
+        self.rx_bytes + self.tx_bytes
+    }
+}
+
+/// How a peer is disabled once it exceeds its quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Remove the peer from the device.
+    Remove,
+    /// Keep the peer, but clear its allowed IPs so that no traffic goes through it.
+    ClearAllowedIps,
+}
+
+/// A limit to the traffic of a peer, received and sent, over a period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    period: Period,
+    limit: u64,
+    action: Action,
+}
+
+impl Quota {
+    /// Create a quota of `limit` bytes per period.
+    pub fn new(period: Period, limit: u64, action: Action) -> Quota {
+        Quota {
+            period: period,
+            limit: limit,
+            action: action,
+        }
+    }
+
+    /// Get the period over which the quota applies.
+    pub fn period(&self) -> Period {
+        self.period
+    }
+
+    /// Get the amount of bytes allowed per period.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Get what is done to the peer once the quota is exceeded.
+    pub fn action(&self) -> Action {
+        self.action
+    }
+}
+
+/// What is known of a peer.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Account {
+    counters: Option<(u64, u64)>,
+    usage: BTreeMap<String, Usage>,
+    quota: Option<Quota>,
+}
+
+/// The accounting database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accounting {
+    path: PathBuf,
+    accounts: HashMap<Key, Account>,
+}
+
+impl Accounting {
+    /// Open the database stored at `path`. A missing file is an empty database, created when
+    /// saved.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Accounting> {
+        let mut accounting = Accounting {
+            path: path.into(),
+            accounts: HashMap::new(),
+        };
+
+        let file = match File::open(&accounting.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(accounting),
+            Err(err) => return Err(err),
+        };
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            accounting
+                .load_line(&line?)
+                .map_err(|err| invalid(format!("Line {}: {}", i + 1, err)))?;
+        }
+
+        Ok(accounting)
+    }
+
+    /// Get the path of the database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the database, replacing the previous file atomically.
+    pub fn save(&self) -> io::Result<()> {
+        let mut keys = self.accounts.keys().collect::<Vec<_>>();
+        keys.sort_by_key(|key| key.to_base64());
+
+        let mut buf = String::new();
+
+        for key in keys {
+            let account = &self.accounts[key];
+            let key = key.to_base64();
+
+            if let Some((rx, tx)) = account.counters {
+                buf.push_str(&format!("peer {} {} {}\n", key, rx, tx));
+            }
+
+            for (period, usage) in &account.usage {
+                buf.push_str(&format!(
+                    "usage {} {} {} {}\n",
+                    key, period, usage.rx_bytes, usage.tx_bytes
+                ));
+            }
+
+            if let Some(quota) = account.quota {
+                let action = match quota.action {
+                    Action::Remove => "remove",
+                    Action::ClearAllowedIps => "clear",
+                };
+
+                buf.push_str(&format!(
+                    "quota {} {} {} {}\n",
+                    key, quota.period, quota.limit, action
+                ));
+            }
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+
+        file.write_all(buf.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Add the traffic of the peers of a device since the previous record to the periods
+    /// containing `now`. Traffic counted by a peer before it was first recorded is included.
+    pub fn record(&mut self, device: &Device, now: SystemTime) {
+        let day = Period::Daily.id(now);
+        let month = Period::Monthly.id(now);
+
+        for peer in device.peers() {
+            let key = match peer.public_key() {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let account = self.accounts.entry(key.clone()).or_default();
+            let (rx, tx) = (peer.rx_bytes(), peer.tx_bytes());
+
+            let delta = match account.counters {
+                Some((last_rx, last_tx)) if rx >= last_rx && tx >= last_tx => {
+                    (rx - last_rx, tx - last_tx)
+                }
+
+                // Reset, or first record.
+                _ => (rx, tx),
+            };
+
+            account.counters = Some((rx, tx));
+
+            for period in &[&day, &month] {
+                let usage = account.usage.entry(period.to_string()).or_default();
+
+                usage.rx_bytes += delta.0;
+                usage.tx_bytes += delta.1;
+            }
+        }
+    }
+
+    /// Get the usage of a peer during the period containing `time`.
+    pub fn usage(&self, key: &Key, period: Period, time: SystemTime) -> Usage {
+        self.accounts
+            .get(key)
+            .and_then(|account| account.usage.get(&period.id(time)))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get the usage of a peer for every recorded period, by identifier.
+    pub fn history(&self, key: &Key) -> Vec<(&str, Usage)> {
+        self.accounts
+            .get(key)
+            .map(|account| {
+                account
+                    .usage
+                    .iter()
+                    .map(|(period, usage)| (period.as_str(), *usage))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drop the usage of the periods that ended before the one containing `time`, of both kinds.
+    pub fn prune(&mut self, time: SystemTime) {
+        let (day, month) = (Period::Daily.id(time), Period::Monthly.id(time));
+
+        for account in self.accounts.values_mut() {
+            account.usage.retain(|period, _| {
+                let current = if period.len() == day.len() {
+                    &day
+                } else {
+                    &month
+                };
+                period >= current
+            });
+        }
+    }
+
+    /// Set the quota of a peer.
+    pub fn set_quota(&mut self, key: Key, quota: Quota) {
+        self.accounts.entry(key).or_default().quota = Some(quota);
+    }
+
+    /// Remove the quota of a peer.
+    pub fn remove_quota(&mut self, key: &Key) {
+        if let Some(account) = self.accounts.get_mut(key) {
+            account.quota = None;
+        }
+    }
+
+    /// Get the quota of a peer.
+    pub fn quota(&self, key: &Key) -> Option<Quota> {
+        self.accounts.get(key).and_then(|account| account.quota)
+    }
+
+    /// Get the peers that exceeded their quota in the period containing `time`.
+    pub fn exceeded(&self, time: SystemTime) -> Vec<Key> {
+        let mut keys = self
+            .accounts
+            .iter()
+            .filter_map(|(key, account)| {
+                let quota = account.quota?;
+                let usage = self.usage(key, quota.period, time);
+
+                if usage.total() > quota.limit {
+                    Some(key.clone())
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        keys.sort_by_key(|key| key.to_base64());
+        keys
+    }
+
+    /// Build the changes that disable the peers of `device` that exceeded their quota at the time
+    /// `now`. Peers already disabled are left out.
+    pub fn disable_changes(&self, device: &Device, now: SystemTime) -> Device {
+        let mut changes = Device::new(device.name());
+        changes.set_replace_peers(false);
+
+        for key in self.exceeded(now) {
+            let peer = match device.peers().iter().find(|p| p.public_key() == Some(&key)) {
+                Some(peer) => peer,
+                None => continue,
+            };
+
+            let mut change = Peer::new(key.clone(), None);
+
+            match self.quota(&key).map(|quota| quota.action) {
+                Some(Action::Remove) => change.set_remove(true),
+
+                Some(Action::ClearAllowedIps) if !peer.allowed_ips().is_empty() => {
+                    change.set_update_only(true);
+                    change.set_replace_allowed_ips(true);
+                }
+
+                _ => continue,
+            }
+
+            changes.add_peer(change);
+        }
+
+        changes
+    }
+
+    /// Disable the peers of `device` that exceeded their quota at the time `now`. Returns the
+    /// public keys of the disabled peers.
+    pub fn enforce(&self, device: &Device, now: SystemTime) -> io::Result<Vec<Key>> {
+        self.enforce_with(&System::new(), device, now)
+    }
+
+    /// Disable the peers of `device` that exceeded their quota using the specified backend.
+    pub fn enforce_with<B: Backend + ?Sized>(
+        &self,
+        backend: &B,
+        device: &Device,
+        now: SystemTime,
+    ) -> io::Result<Vec<Key>> {
+        let changes = self.disable_changes(device, now);
+        let keys = changes
+            .peers()
+            .iter()
+            .filter_map(|peer| peer.public_key().cloned())
+            .collect::<Vec<_>>();
+
+        if !keys.is_empty() {
+            changes.save_with(backend)?;
+        }
+
+        Ok(keys)
+    }
+
+    /// Load a line of the database file.
+    fn load_line(&mut self, line: &str) -> io::Result<()> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        let (kind, key, rest) = match fields.as_slice() {
+            [] => return Ok(()),
+            [kind, key, rest @ ..] => (*kind, *key, rest),
+            _ => return Err(invalid(String::from("Missing public key"))),
+        };
+
+        let key = Key::from_base64(key).map_err(|_| invalid(String::from("Invalid key")))?;
+        let account = self.accounts.entry(key).or_default();
+
+        match (kind, rest) {
+            ("peer", [rx, tx]) => account.counters = Some((number(rx)?, number(tx)?)),
+
+            ("usage", [period, rx, tx]) => {
+                let usage = Usage {
+                    rx_bytes: number(rx)?,
+                    tx_bytes: number(tx)?,
+                };
+
+                account.usage.insert(period.to_string(), usage);
+            }
+
+            ("quota", [period, limit, action]) => {
+                let action = match *action {
+                    "remove" => Action::Remove,
+                    "clear" => Action::ClearAllowedIps,
+                    _ => return Err(invalid(format!("Invalid action: {}", action))),
+                };
+
+                account.quota = Some(Quota::new(period.parse()?, number(limit)?, action));
+            }
+
+            _ => return Err(invalid(format!("Invalid record: {}", kind))),
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse an amount of bytes.
+fn number(s: &str) -> io::Result<u64> {
+    s.parse()
+        .map_err(|_| invalid(format!("Invalid number: {}", s)))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Convert a number of days since the UNIX epoch to a year, month and day.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
@@ -0,0 +1,404 @@
+//! Kernel devices, managed through the embeddable wg C library.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::ptr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use libwg_sys as sys;
+
+use crate::backend::Backend;
+use crate::device::Device;
+use crate::key::Key;
+use crate::net;
+use crate::peer::{AllowedIp, Peer};
+
+/// Backend talking to the kernel module through the embeddable wg library.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LibWg;
+
+impl LibWg {
+    /// Construct the backend.
+    pub fn new() -> LibWg {
+        LibWg
+    }
+}
+
+impl Backend for LibWg {
+    fn list(&self) -> io::Result<Vec<String>> {
+        let names = unsafe {
+            let mut names = Vec::new();
+            let mut pointer = sys::wg_list_device_names();
+
+            if pointer.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+
+            while *pointer != 0 {
+                let name = CStr::from_ptr(pointer);
+
+                pointer = pointer.add(name.to_bytes().len() + 1);
+                names.push(name.to_string_lossy().into_owned());
+            }
+
+            names
+        };
+
+        Ok(names)
+    }
+
+    fn get(&self, name: &str) -> io::Result<Device> {
+        let name = c_name(name)?;
+
+        unsafe {
+            let mut h: *mut sys::wg_device = ptr::null_mut();
+
+            if sys::wg_get_device(&mut h, name.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let device = device_from_handle(h);
+            sys::wg_free_device(h);
+
+            Ok(device)
+        }
+    }
+
+    fn set(&self, device: &Device) -> io::Result<()> {
+        let existing;
+        let device = if device.peers().iter().any(Peer::update_only) {
+            existing = existing_peers_only(self, device)?;
+            &existing
+        } else {
+            device
+        };
+
+        let mut handle = DeviceHandle::new(device);
+
+        unsafe {
+            if sys::wg_set_device(&mut handle.h) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create(&self, name: &str) -> io::Result<()> {
+        let name = c_name(name)?;
+
+        unsafe {
+            if sys::wg_add_device(name.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let name = c_name(name)?;
+
+        unsafe {
+            if sys::wg_del_device(name.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Drop the changes to the peers marked update-only that the device does not have, as the C
+/// library has no flag to leave that to the kernel. The peers are read from `backend` first, so
+/// a peer added concurrently may still be skipped.
+pub(crate) fn existing_peers_only<B: Backend + ?Sized>(
+    backend: &B,
+    device: &Device,
+) -> io::Result<Device> {
+    let existing = if device.replace_peers() {
+        Vec::new()
+    } else {
+        backend
+            .get(device.name())?
+            .peers()
+            .iter()
+            .filter_map(|peer| peer.public_key().cloned())
+            .collect()
+    };
+
+    let mut device = device.clone();
+
+    device.peers_mut().retain(|peer| {
+        !peer.update_only() || existing.iter().any(|key| peer.public_key() == Some(key))
+    });
+
+    Ok(device)
+}
+
+/// Convert a device name into a C string.
+fn c_name(name: &str) -> io::Result<CString> {
+    CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid device name"))
+}
+
+/// Create a `Device` object from the C library handle.
+fn device_from_handle(h: *mut sys::wg_device) -> Device {
+    let name = unsafe { CStr::from_ptr((*h).name.as_ptr()) };
+
+    let mut device = Device::new(
+        name.to_str()
+            .expect("Get an invalid interface name from wg"),
+    );
+
+    unsafe {
+        if (*h).flags & sys::wg_device_flags_WGDEVICE_HAS_PRIVATE_KEY != 0 {
+            device.set_private_key(Key::from_bytes((*h).private_key));
+        }
+
+        if (*h).listen_port > 0 {
+            device.set_listen_port((*h).listen_port);
+        }
+
+        if (*h).fwmark > 0 {
+            device.set_fwmark((*h).fwmark);
+        }
+
+        let mut peer = (*h).first_peer;
+
+        while !peer.is_null() {
+            device.add_peer(peer_from_handle(peer));
+            peer = (*peer).next_peer;
+        }
+    }
+
+    device
+}
+
+/// Construct a `Peer` object from a C library handle.
+fn peer_from_handle(h: *mut sys::wg_peer) -> Peer {
+    unsafe {
+        let endpoint = net::sockaddr_to_endpoint(&(*h).endpoint.addr);
+        let mut peer = Peer::new(Key::from_bytes((*h).public_key), endpoint);
+
+        if (*h).flags & sys::wg_peer_flags_WGPEER_HAS_PRESHARED_KEY != 0 {
+            peer.set_preshared_key(Key::from_bytes((*h).preshared_key));
+        }
+
+        if (*h).persistent_keepalive_interval > 0 {
+            peer.set_persistent_keepalive((*h).persistent_keepalive_interval);
+        }
+
+        let time = (*h).last_handshake_time;
+
+        if time.tv_sec > 0 || time.tv_nsec > 0 {
+            peer.set_last_handshake(Some(
+                UNIX_EPOCH + Duration::new(time.tv_sec as u64, time.tv_nsec as u32),
+            ));
+        }
+
+        peer.set_transfer((*h).rx_bytes, (*h).tx_bytes);
+
+        let mut ip = (*h).first_allowedip;
+
+        while !ip.is_null() {
+            peer.add_allowed_ip(allowed_ip_from_handle(ip));
+            ip = (*ip).next_allowedip;
+        }
+
+        peer
+    }
+}
+
+/// Construct an `AllowedIp` object from a C library handle.
+fn allowed_ip_from_handle(h: *mut sys::wg_allowedip) -> AllowedIp {
+    let addr = unsafe {
+        match (*h).family as u32 {
+            sys::AF_INET => {
+                let addr = net::read_ip4_from_in_addr(&(*h).__bindgen_anon_1.ip4);
+                IpAddr::V4(addr)
+            }
+
+            sys::AF_INET6 => {
+                let addr = net::read_ip6_from_in6_addr(&(*h).__bindgen_anon_1.ip6);
+                IpAddr::V6(addr)
+            }
+
+            wtf => panic!("Got an invalid AF address family: {}", wtf),
+        }
+    };
+
+    AllowedIp::new(addr, unsafe { (*h).cidr })
+}
+
+/// Get the C library handle for an allowed IP.
+fn allowed_ip_handle(ip: &AllowedIp) -> sys::wg_allowedip {
+    let mut allowed_ip = unsafe {
+        let mut allowed_ip: sys::wg_allowedip = mem::zeroed();
+
+        match ip.addr() {
+            IpAddr::V4(ref ip4) => {
+                allowed_ip.family = sys::AF_INET as u16;
+                net::write_ip4_to_in_addr(ip4, &mut allowed_ip.__bindgen_anon_1.ip4);
+            }
+
+            IpAddr::V6(ref ip6) => {
+                allowed_ip.family = sys::AF_INET6 as u16;
+                net::write_ip6_to_in6_addr(ip6, &mut allowed_ip.__bindgen_anon_1.ip6);
+            }
+        };
+
+        allowed_ip
+    };
+
+    allowed_ip.cidr = ip.mask();
+    allowed_ip
+}
+
+/// Handle to a device that can be used by the C library. The peers and allowed IPs pointed to by
+/// the handle are owned by this struct.
+struct DeviceHandle {
+    h: sys::wg_device,
+    _peers: Vec<PeerHandle>,
+}
+
+impl DeviceHandle {
+    /// Get the C library handle that corresponds to a device.
+    fn new(device: &Device) -> DeviceHandle {
+        unsafe {
+            let mut h: sys::wg_device = mem::zeroed();
+            let name = device.name().as_bytes();
+
+            ptr::copy_nonoverlapping(
+                name.as_ptr() as *const _,
+                h.name.as_mut_ptr(),
+                name.len().min(h.name.len() - 1),
+            );
+
+            if let Some(key) = device.private_key() {
+                h.flags |= sys::wg_device_flags_WGDEVICE_HAS_PRIVATE_KEY;
+
+                h.private_key.copy_from_slice(key.as_bytes());
+                h.public_key.copy_from_slice(key.derive_public().as_bytes());
+            }
+
+            if let Some(listen_port) = device.listen_port() {
+                h.flags |= sys::wg_device_flags_WGDEVICE_HAS_LISTEN_PORT;
+                h.listen_port = listen_port;
+            }
+
+            if let Some(fwmark) = device.fwmark() {
+                h.flags |= sys::wg_device_flags_WGDEVICE_HAS_FWMARK;
+                h.fwmark = fwmark;
+            }
+
+            if device.replace_peers() {
+                h.flags |= sys::wg_device_flags_WGDEVICE_REPLACE_PEERS;
+            }
+
+            let mut peers = device
+                .peers()
+                .iter()
+                .map(PeerHandle::new)
+                .collect::<Vec<_>>();
+
+            if !peers.is_empty() {
+                let peers_len = peers.len();
+                let peers_ptr = peers.as_mut_ptr();
+
+                for i in 0..peers_len {
+                    (*peers_ptr.add(i)).h.next_peer = if i + 1 < peers_len {
+                        &mut (*peers_ptr.add(i + 1)).h
+                    } else {
+                        ptr::null_mut()
+                    };
+                }
+
+                h.first_peer = &mut (*peers_ptr).h;
+                h.last_peer = &mut (*peers_ptr.add(peers_len - 1)).h;
+            } else {
+                h.first_peer = ptr::null_mut();
+                h.last_peer = ptr::null_mut();
+            }
+
+            DeviceHandle {
+                h: h,
+                _peers: peers,
+            }
+        }
+    }
+}
+
+/// Handle to a peer for the C library.
+struct PeerHandle {
+    h: sys::wg_peer,
+    _allowed_ips: Vec<sys::wg_allowedip>,
+}
+
+impl PeerHandle {
+    /// Get the C library handle for a peer.
+    fn new(peer: &Peer) -> PeerHandle {
+        unsafe {
+            let mut h: sys::wg_peer = mem::zeroed();
+
+            if let Some(key) = peer.public_key() {
+                h.flags |= sys::wg_peer_flags_WGPEER_HAS_PUBLIC_KEY;
+                h.public_key.copy_from_slice(key.as_bytes());
+            }
+
+            if let Some(key) = peer.preshared_key() {
+                h.flags |= sys::wg_peer_flags_WGPEER_HAS_PRESHARED_KEY;
+                h.preshared_key.copy_from_slice(key.as_bytes());
+            }
+
+            if let Some(interval) = peer.persistent_keepalive() {
+                h.flags |= sys::wg_peer_flags_WGPEER_HAS_PERSISTENT_KEEPALIVE_INTERVAL;
+                h.persistent_keepalive_interval = interval;
+            }
+
+            if peer.remove() {
+                h.flags |= sys::wg_peer_flags_WGPEER_REMOVE_ME;
+            }
+
+            if let Some(endpoint) = peer.endpoint() {
+                net::endpoint_to_sockaddr(endpoint, &mut h.endpoint.addr);
+            } else {
+                h.endpoint = mem::zeroed();
+            }
+
+            if peer.replace_allowed_ips() {
+                h.flags |= sys::wg_peer_flags_WGPEER_REPLACE_ALLOWEDIPS;
+            }
+
+            let mut allowed_ips = peer
+                .allowed_ips()
+                .iter()
+                .map(allowed_ip_handle)
+                .collect::<Vec<_>>();
+
+            let allowed_ips_len = allowed_ips.len();
+            let allowed_ips_ptr = allowed_ips.as_mut_ptr();
+
+            if !allowed_ips.is_empty() {
+                for (i, ip) in allowed_ips.iter_mut().enumerate() {
+                    if i + 1 < allowed_ips_len {
+                        ip.next_allowedip = allowed_ips_ptr.add(i + 1);
+                    }
+                }
+
+                h.first_allowedip = allowed_ips_ptr;
+                h.last_allowedip = allowed_ips_ptr.add(allowed_ips_len - 1);
+            } else {
+                h.first_allowedip = ptr::null_mut();
+                h.last_allowedip = ptr::null_mut();
+            }
+
+            PeerHandle {
+                h: h,
+                _allowed_ips: allowed_ips,
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! In-memory devices, for testing without privileges or a kernel module.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+#[cfg(feature = "tokio")]
+use crate::backend::AsyncBackend;
+use crate::backend::Backend;
+use crate::device::Device;
+use crate::key::Key;
+use crate::peer::{Endpoint, Peer};
+
+const ENOENT: i32 = 2;
+const EEXIST: i32 = 17;
+const ENODEV: i32 = 19;
+
+/// Backend keeping its devices in memory. Changes are applied with the same semantics as the
+/// kernel module: peers replacement and removal, allowed IPs replacement, and allowed IPs being
+/// unique across the peers of a device. Traffic and handshakes can be simulated.
+#[derive(Debug, Default)]
+pub struct Mock {
+    devices: Mutex<BTreeMap<String, Device>>,
+}
+
+impl Mock {
+    /// Construct a backend with no devices.
+    pub fn new() -> Mock {
+        Mock::default()
+    }
+
+    /// Simulate a handshake with a peer at the specified time.
+    pub fn handshake(&self, device: &str, peer: &Key, time: SystemTime) -> io::Result<()> {
+        self.with_peer(device, peer, |peer| peer.set_last_handshake(Some(time)))
+    }
+
+    /// Simulate traffic with a peer, adding to its transfer counters.
+    pub fn transfer(
+        &self,
+        device: &str,
+        peer: &Key,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    ) -> io::Result<()> {
+        self.with_peer(device, peer, |peer| {
+            let (rx, tx) = (peer.rx_bytes(), peer.tx_bytes());
+            peer.set_transfer(rx + rx_bytes, tx + tx_bytes);
+        })
+    }
+
+    /// Simulate a peer roaming to a new endpoint.
+    pub fn roam(&self, device: &str, peer: &Key, endpoint: Endpoint) -> io::Result<()> {
+        self.with_peer(device, peer, |peer| peer.set_endpoint(endpoint))
+    }
+
+    /// Apply a modification to a peer of a device.
+    fn with_peer<F: FnOnce(&mut Peer)>(&self, device: &str, key: &Key, f: F) -> io::Result<()> {
+        let mut devices = self.lock();
+        let device = devices.get_mut(device).ok_or_else(|| errno(ENODEV))?;
+
+        let peer = device
+            .peers_mut()
+            .iter_mut()
+            .find(|peer| peer.public_key() == Some(key))
+            .ok_or_else(|| errno(ENOENT))?;
+
+        f(peer);
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Device>> {
+        self.devices.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Backend for Mock {
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.lock().keys().cloned().collect())
+    }
+
+    fn get(&self, name: &str) -> io::Result<Device> {
+        self.lock().get(name).cloned().ok_or_else(|| errno(ENODEV))
+    }
+
+    fn set(&self, changes: &Device) -> io::Result<()> {
+        let mut devices = self.lock();
+        let device = devices
+            .get_mut(changes.name())
+            .ok_or_else(|| errno(ENODEV))?;
+
+        if changes
+            .peers()
+            .iter()
+            .any(|peer| peer.public_key().is_none())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "peer without a public key",
+            ));
+        }
+
+        if let Some(key) = changes.private_key() {
+            let public_key = key.derive_public();
+
+            device.set_private_key(key.clone());
+            device
+                .peers_mut()
+                .retain(|peer| peer.public_key() != Some(&public_key));
+        }
+
+        if let Some(port) = changes.listen_port() {
+            device.set_listen_port(port);
+        }
+
+        match changes.fwmark() {
+            Some(0) => device.clear_fwmark(),
+            Some(fwmark) => device.set_fwmark(fwmark),
+            None => {}
+        }
+
+        if changes.replace_peers() {
+            device.peers_mut().clear();
+        }
+
+        for change in changes.peers() {
+            apply_peer(device, change);
+        }
+
+        Ok(())
+    }
+
+    fn create(&self, name: &str) -> io::Result<()> {
+        let mut devices = self.lock();
+
+        if devices.contains_key(name) {
+            return Err(errno(EEXIST));
+        }
+
+        devices.insert(name.to_owned(), Device::new(name));
+
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.lock()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| errno(ENODEV))
+    }
+}
+
+/// Devices are in memory, so the asynchronous operations complete immediately.
+#[cfg(feature = "tokio")]
+impl AsyncBackend for Mock {
+    async fn list(&self) -> io::Result<Vec<String>> {
+        Backend::list(self)
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Device> {
+        Backend::get(self, name)
+    }
+
+    async fn set(&self, device: &Device) -> io::Result<()> {
+        Backend::set(self, device)
+    }
+
+    async fn create(&self, name: &str) -> io::Result<()> {
+        Backend::create(self, name)
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        Backend::delete(self, name)
+    }
+}
+
+/// Apply the changes to a single peer, the way the kernel does.
+fn apply_peer(device: &mut Device, change: &Peer) {
+    let key = change.public_key().expect("peer without a public key");
+    let position = device
+        .peers()
+        .iter()
+        .position(|peer| peer.public_key() == Some(key));
+
+    if change.remove() {
+        if let Some(position) = position {
+            device.peers_mut().remove(position);
+        }
+
+        return;
+    }
+
+    let position = match position {
+        Some(position) => position,
+        None if change.update_only() => return,
+
+        None => {
+            device.add_peer(Peer::new(key.clone(), None));
+            device.peers().len() - 1
+        }
+    };
+
+    for ip in change.allowed_ips() {
+        let network = ip.network();
+
+        for (i, peer) in device.peers_mut().iter_mut().enumerate() {
+            if i != position {
+                peer.allowed_ips_mut().retain(|other| *other != network);
+            }
+        }
+    }
+
+    let peer = &mut device.peers_mut()[position];
+
+    match change.preshared_key() {
+        Some(key) if key.is_zero() => peer.clear_preshared_key(),
+        Some(key) => peer.set_preshared_key(key.clone()),
+        None => {}
+    }
+
+    if let Some(endpoint) = change.endpoint() {
+        peer.set_endpoint(*endpoint);
+    }
+
+    match change.persistent_keepalive() {
+        Some(0) => peer.clear_persistent_keepalive(),
+        Some(interval) => peer.set_persistent_keepalive(interval),
+        None => {}
+    }
+
+    if change.replace_allowed_ips() {
+        peer.allowed_ips_mut().clear();
+    }
+
+    for ip in change.allowed_ips() {
+        let network = ip.network();
+
+        if !peer.allowed_ips().contains(&network) {
+            peer.add_allowed_ip(network);
+        }
+    }
+}
+
+/// Construct the I/O error corresponding to an error number.
+fn errno(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
@@ -0,0 +1,254 @@
+//! Backends through which WireGuard devices are managed.
+//!
+//! A `Backend` knows where devices live and how to read and write them. `Device` and `Peer` only
+//! describe the configuration, and every operation that touches the system goes through a
+//! backend.
+
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::io;
+
+use crate::device::Device;
+
+#[cfg(feature = "libwg")]
+pub use self::libwg::LibWg;
+pub use self::mock::Mock;
+pub use self::netlink::Netlink;
+pub use self::userspace::{Userspace, SOCKET_DIR};
+
+#[cfg(feature = "libwg")]
+pub(crate) mod libwg;
+mod mock;
+pub(crate) mod netlink;
+mod userspace;
+
+/// The backend used to manage kernel devices: the embeddable wg C library when the `libwg`
+/// feature is enabled, and the pure Rust netlink implementation otherwise.
+#[cfg(feature = "libwg")]
+pub type Kernel = LibWg;
+
+/// The backend used to manage kernel devices: the embeddable wg C library when the `libwg`
+/// feature is enabled, and the pure Rust netlink implementation otherwise.
+#[cfg(not(feature = "libwg"))]
+pub type Kernel = Netlink;
+
+/// A place where WireGuard devices live.
+pub trait Backend {
+    /// List the names of the devices managed by this backend.
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// Retrieve the current configuration and statistics of a device.
+    fn get(&self, name: &str) -> io::Result<Device>;
+
+    /// Apply the changes described by `device` to the device of the same name.
+    fn set(&self, device: &Device) -> io::Result<()>;
+
+    /// Create a new, unconfigured device.
+    fn create(&self, name: &str) -> io::Result<()>;
+
+    /// Delete a device.
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+impl<B: Backend + ?Sized> Backend for &B {
+    fn list(&self) -> io::Result<Vec<String>> {
+        (**self).list()
+    }
+
+    fn get(&self, name: &str) -> io::Result<Device> {
+        (**self).get(name)
+    }
+
+    fn set(&self, device: &Device) -> io::Result<()> {
+        (**self).set(device)
+    }
+
+    fn create(&self, name: &str) -> io::Result<()> {
+        (**self).create(name)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        (**self).delete(name)
+    }
+}
+
+/// A place where WireGuard devices live, accessed without blocking the calling thread. Requires
+/// the `tokio` feature, and the futures must be polled within a tokio runtime.
+#[cfg(feature = "tokio")]
+pub trait AsyncBackend {
+    /// List the names of the devices managed by this backend.
+    fn list(&self) -> impl Future<Output = io::Result<Vec<String>>> + Send;
+
+    /// Retrieve the current configuration and statistics of a device.
+    fn get(&self, name: &str) -> impl Future<Output = io::Result<Device>> + Send;
+
+    /// Apply the changes described by `device` to the device of the same name.
+    fn set(&self, device: &Device) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Create a new, unconfigured device.
+    fn create(&self, name: &str) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Delete a device.
+    fn delete(&self, name: &str) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+#[cfg(feature = "tokio")]
+impl<B: AsyncBackend + Sync + ?Sized> AsyncBackend for &B {
+    async fn list(&self) -> io::Result<Vec<String>> {
+        AsyncBackend::list(*self).await
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Device> {
+        AsyncBackend::get(*self, name).await
+    }
+
+    async fn set(&self, device: &Device) -> io::Result<()> {
+        AsyncBackend::set(*self, device).await
+    }
+
+    async fn create(&self, name: &str) -> io::Result<()> {
+        AsyncBackend::create(*self, name).await
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        AsyncBackend::delete(*self, name).await
+    }
+}
+
+/// The devices of this machine: kernel devices, along with the userspace devices that expose a
+/// UAPI socket. Userspace devices take precedence over kernel devices of the same name, unless
+/// their socket is stale and refuses connections, and new devices are always created in the
+/// kernel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct System<K = Kernel> {
+    kernel: K,
+    userspace: Userspace,
+}
+
+impl System {
+    /// Access the kernel devices and the userspace devices in the default socket directory.
+    pub fn new() -> System {
+        System::with(Kernel::new(), Userspace::new())
+    }
+}
+
+impl<K: Backend> System<K> {
+    /// Combine the specified kernel and userspace backends.
+    pub fn with(kernel: K, userspace: Userspace) -> System<K> {
+        System {
+            kernel: kernel,
+            userspace: userspace,
+        }
+    }
+
+    /// Get the backend used for kernel devices.
+    pub fn kernel(&self) -> &K {
+        &self.kernel
+    }
+
+    /// Get the backend used for userspace devices.
+    pub fn userspace(&self) -> &Userspace {
+        &self.userspace
+    }
+}
+
+impl<K: Backend> Backend for System<K> {
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = self.kernel.list()?;
+
+        for name in Backend::list(&self.userspace)? {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn get(&self, name: &str) -> io::Result<Device> {
+        if self.userspace.exists(name) {
+            let result = Backend::get(&self.userspace, name);
+
+            if !userspace::is_stale(&result) {
+                return result;
+            }
+        }
+
+        self.kernel.get(name)
+    }
+
+    fn set(&self, device: &Device) -> io::Result<()> {
+        if self.userspace.exists(device.name()) {
+            let result = Backend::set(&self.userspace, device);
+
+            if !userspace::is_stale(&result) {
+                return result;
+            }
+        }
+
+        self.kernel.set(device)
+    }
+
+    fn create(&self, name: &str) -> io::Result<()> {
+        self.kernel.create(name)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        if self.userspace.exists(name) {
+            Backend::delete(&self.userspace, name)
+        } else {
+            self.kernel.delete(name)
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<K: AsyncBackend + Sync> AsyncBackend for System<K> {
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = self.kernel.list().await?;
+
+        for name in AsyncBackend::list(&self.userspace).await? {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Device> {
+        if self.userspace.exists_async(name).await {
+            let result = AsyncBackend::get(&self.userspace, name).await;
+
+            if !userspace::is_stale(&result) {
+                return result;
+            }
+        }
+
+        self.kernel.get(name).await
+    }
+
+    async fn set(&self, device: &Device) -> io::Result<()> {
+        if self.userspace.exists_async(device.name()).await {
+            let result = AsyncBackend::set(&self.userspace, device).await;
+
+            if !userspace::is_stale(&result) {
+                return result;
+            }
+        }
+
+        self.kernel.set(device).await
+    }
+
+    async fn create(&self, name: &str) -> io::Result<()> {
+        self.kernel.create(name).await
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        if self.userspace.exists_async(name).await {
+            AsyncBackend::delete(&self.userspace, name).await
+        } else {
+            self.kernel.delete(name).await
+        }
+    }
+}
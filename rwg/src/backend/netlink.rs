@@ -0,0 +1,600 @@
+//! Kernel devices, managed by speaking the `wireguard` generic netlink family directly.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[cfg(feature = "tokio")]
+use crate::backend::AsyncBackend;
+use crate::backend::Backend;
+use crate::device::Device;
+use crate::key::{Key, KEY_SIZE};
+#[cfg(feature = "tokio")]
+use crate::netlink::AsyncSocket;
+use crate::netlink::{self, ifinfomsg, Message, Socket};
+use crate::netlink::{
+    IFLA_IFNAME, IFLA_INFO_KIND, IFLA_LINKINFO, RTM_DELLINK, RTM_GETLINK, RTM_NEWLINK,
+};
+use crate::peer::{AllowedIp, Endpoint, Peer};
+
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_F_REPLACE_PEERS: u32 = 1;
+
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_FLAGS: u16 = 5;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+
+const WGPEER_F_REMOVE_ME: u32 = 1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+const WGPEER_F_UPDATE_ONLY: u32 = 4;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+/// Maximum size of the messages sent to configure a device. Larger configurations are split
+/// across several messages, like the C library does.
+pub(crate) const SET_MESSAGE_SIZE: usize = 4096;
+
+/// Backend talking to the kernel module over generic netlink, without the C library.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Netlink;
+
+impl Netlink {
+    /// Construct the backend.
+    pub fn new() -> Netlink {
+        Netlink
+    }
+}
+
+impl Backend for Netlink {
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        Ok(wireguard_link_names(&socket.request(&list_message())?))
+    }
+
+    fn get(&self, name: &str) -> io::Result<Device> {
+        let mut socket = Socket::new(libc::NETLINK_GENERIC)?;
+        let family = socket.family_id(WG_GENL_NAME)?;
+
+        parse_device(name, &socket.request(&get_message(family, name))?)
+    }
+
+    fn set(&self, device: &Device) -> io::Result<()> {
+        let mut socket = Socket::new(libc::NETLINK_GENERIC)?;
+        let family = socket.family_id(WG_GENL_NAME)?;
+
+        for msg in set_messages(family, device)? {
+            socket.request(&msg)?;
+        }
+
+        Ok(())
+    }
+
+    fn create(&self, name: &str) -> io::Result<()> {
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        socket.request(&create_message(name))?;
+
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        socket.request(&delete_message(name))?;
+
+        Ok(())
+    }
+}
+
+/// The same requests as the blocking implementation, sent on a non-blocking socket.
+#[cfg(feature = "tokio")]
+impl AsyncBackend for Netlink {
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut socket = AsyncSocket::new(libc::NETLINK_ROUTE)?;
+        Ok(wireguard_link_names(
+            &socket.request(&list_message()).await?,
+        ))
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Device> {
+        let mut socket = AsyncSocket::new(libc::NETLINK_GENERIC)?;
+        let family = socket.family_id(WG_GENL_NAME).await?;
+
+        parse_device(name, &socket.request(&get_message(family, name)).await?)
+    }
+
+    async fn set(&self, device: &Device) -> io::Result<()> {
+        let mut socket = AsyncSocket::new(libc::NETLINK_GENERIC)?;
+        let family = socket.family_id(WG_GENL_NAME).await?;
+
+        for msg in set_messages(family, device)? {
+            socket.request(&msg).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn create(&self, name: &str) -> io::Result<()> {
+        let mut socket = AsyncSocket::new(libc::NETLINK_ROUTE)?;
+        socket.request(&create_message(name)).await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        let mut socket = AsyncSocket::new(libc::NETLINK_ROUTE)?;
+        socket.request(&delete_message(name)).await?;
+
+        Ok(())
+    }
+}
+
+/// Build the request dumping every link.
+fn list_message() -> Message {
+    let mut msg = Message::new(RTM_GETLINK, netlink::NLM_F_DUMP);
+    msg.put(&ifinfomsg(0, 0, 0));
+    msg
+}
+
+/// Build the request retrieving a device.
+fn get_message(family: u16, name: &str) -> Message {
+    let mut msg = Message::genl(
+        family,
+        netlink::NLM_F_DUMP,
+        WG_CMD_GET_DEVICE,
+        WG_GENL_VERSION,
+    );
+
+    msg.attr_str(WGDEVICE_A_IFNAME, name);
+    msg
+}
+
+/// Build the request creating a WireGuard link.
+fn create_message(name: &str) -> Message {
+    let mut msg = Message::new(RTM_NEWLINK, netlink::NLM_F_CREATE | netlink::NLM_F_EXCL);
+
+    msg.put(&ifinfomsg(0, 0, 0));
+    msg.attr_str(IFLA_IFNAME, name);
+    msg.begin_nested(IFLA_LINKINFO);
+    msg.attr_str(IFLA_INFO_KIND, WG_GENL_NAME);
+    msg.end_nested();
+
+    msg
+}
+
+/// Build the request deleting a link.
+fn delete_message(name: &str) -> Message {
+    let mut msg = Message::new(RTM_DELLINK, 0);
+
+    msg.put(&ifinfomsg(0, 0, 0));
+    msg.attr_str(IFLA_IFNAME, name);
+
+    msg
+}
+
+/// Get the names of the WireGuard devices in the payloads of a link dump.
+fn wireguard_link_names(payloads: &[Vec<u8>]) -> Vec<String> {
+    payloads
+        .iter()
+        .filter_map(|payload| wireguard_link_name(payload))
+        .collect()
+}
+
+/// Get the name of the link described by a `RTM_NEWLINK` payload if it is a WireGuard device.
+fn wireguard_link_name(payload: &[u8]) -> Option<String> {
+    let mut name = None;
+    let mut wireguard = false;
+
+    for (ty, data) in netlink::attrs(payload.get(netlink::IFINFOMSG_SIZE..)?) {
+        match ty {
+            IFLA_IFNAME => name = netlink::attr_str(data),
+
+            IFLA_LINKINFO => {
+                wireguard = netlink::attrs(data).any(|(ty, data)| {
+                    ty == IFLA_INFO_KIND && netlink::attr_str(data).as_deref() == Some(WG_GENL_NAME)
+                })
+            }
+
+            _ => {}
+        }
+    }
+
+    if wireguard {
+        name
+    } else {
+        None
+    }
+}
+
+/// Build the messages that apply the changes described by `device`. The device attributes are
+/// only sent in the first message, and peers or allowed IPs that do not fit are continued in the
+/// following ones. Continuations keep `WGPEER_F_UPDATE_ONLY` but drop the flags that would undo
+/// what was already sent, replacing the peers or their allowed IPs and removing a peer.
+pub(crate) fn set_messages(family: u16, device: &Device) -> io::Result<Vec<Message>> {
+    let peers = device.peers();
+    let mut messages = Vec::new();
+    let mut peer_index = 0;
+    let mut ip_index = 0;
+
+    loop {
+        let mut msg = Message::genl(family, 0, WG_CMD_SET_DEVICE, WG_GENL_VERSION);
+        msg.attr_str(WGDEVICE_A_IFNAME, device.name());
+
+        if messages.is_empty() {
+            if let Some(key) = device.private_key() {
+                msg.attr(WGDEVICE_A_PRIVATE_KEY, key.as_bytes());
+            }
+
+            if let Some(port) = device.listen_port() {
+                msg.attr_u16(WGDEVICE_A_LISTEN_PORT, port);
+            }
+
+            if let Some(fwmark) = device.fwmark() {
+                msg.attr_u32(WGDEVICE_A_FWMARK, fwmark);
+            }
+
+            if device.replace_peers() {
+                msg.attr_u32(WGDEVICE_A_FLAGS, WGDEVICE_F_REPLACE_PEERS);
+            }
+        }
+
+        if peer_index < peers.len() {
+            let start = msg.len();
+            let mut full = false;
+
+            msg.begin_nested(WGDEVICE_A_PEERS);
+
+            while peer_index < peers.len() && !full {
+                let peer = &peers[peer_index];
+                let checkpoint = msg.len();
+
+                put_peer(&mut msg, peer, ip_index == 0)?;
+
+                if msg.len() > SET_MESSAGE_SIZE {
+                    msg.truncate(checkpoint);
+                    break;
+                }
+
+                if !peer.remove() && !peer.allowed_ips().is_empty() {
+                    msg.begin_nested(WGPEER_A_ALLOWEDIPS);
+
+                    while ip_index < peer.allowed_ips().len() {
+                        let checkpoint = msg.len();
+
+                        put_allowed_ip(&mut msg, &peer.allowed_ips()[ip_index]);
+
+                        if msg.len() > SET_MESSAGE_SIZE {
+                            msg.truncate(checkpoint);
+                            full = true;
+                            break;
+                        }
+
+                        ip_index += 1;
+                    }
+
+                    msg.end_nested();
+                }
+
+                msg.end_nested();
+
+                if !full {
+                    peer_index += 1;
+                    ip_index = 0;
+                }
+            }
+
+            if msg.len() == start + 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Peer too large for a netlink message",
+                ));
+            }
+
+            msg.end_nested();
+        }
+
+        messages.push(msg);
+
+        if peer_index >= peers.len() {
+            return Ok(messages);
+        }
+    }
+}
+
+/// Append a peer, leaving its nested attribute open for its allowed IPs. A continuation of a peer
+/// from a previous message only carries its public key and `WGPEER_F_UPDATE_ONLY`, so that the
+/// kernel does not create the peer again if it was removed in the meantime.
+fn put_peer(msg: &mut Message, peer: &Peer, first: bool) -> io::Result<()> {
+    let key = peer
+        .public_key()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "peer without a public key"))?;
+
+    msg.begin_nested(0);
+    msg.attr(WGPEER_A_PUBLIC_KEY, key.as_bytes());
+
+    let mut flags = 0;
+
+    if first && peer.remove() {
+        flags |= WGPEER_F_REMOVE_ME;
+    }
+
+    if peer.update_only() {
+        flags |= WGPEER_F_UPDATE_ONLY;
+    }
+
+    if first && peer.replace_allowed_ips() {
+        flags |= WGPEER_F_REPLACE_ALLOWEDIPS;
+    }
+
+    if flags != 0 {
+        msg.attr_u32(WGPEER_A_FLAGS, flags);
+    }
+
+    if !first || peer.remove() {
+        return Ok(());
+    }
+
+    if let Some(key) = peer.preshared_key() {
+        msg.attr(WGPEER_A_PRESHARED_KEY, key.as_bytes());
+    }
+
+    if let Some(endpoint) = peer.endpoint() {
+        msg.attr(WGPEER_A_ENDPOINT, &endpoint_to_sockaddr(endpoint));
+    }
+
+    if let Some(interval) = peer.persistent_keepalive() {
+        msg.attr_u16(WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL, interval);
+    }
+
+    Ok(())
+}
+
+/// Append an allowed IP to the list of allowed IPs of a peer.
+fn put_allowed_ip(msg: &mut Message, ip: &AllowedIp) {
+    msg.begin_nested(0);
+
+    match ip.addr() {
+        IpAddr::V4(ip4) => {
+            msg.attr_u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET as u16);
+            msg.attr(WGALLOWEDIP_A_IPADDR, &ip4.octets());
+        }
+
+        IpAddr::V6(ip6) => {
+            msg.attr_u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET6 as u16);
+            msg.attr(WGALLOWEDIP_A_IPADDR, &ip6.octets());
+        }
+    }
+
+    msg.attr_u8(WGALLOWEDIP_A_CIDR_MASK, ip.mask());
+    msg.end_nested();
+}
+
+/// Build a device from the payloads of the messages of a `WG_CMD_GET_DEVICE` dump. A peer whose
+/// allowed IPs did not fit in one message is continued at the start of the next one.
+pub(crate) fn parse_device(name: &str, payloads: &[Vec<u8>]) -> io::Result<Device> {
+    let mut device = Device::new(name);
+
+    for payload in payloads {
+        let attrs = payload.get(netlink::GENL_HEADER_SIZE..).unwrap_or(&[]);
+
+        for (ty, data) in netlink::attrs(attrs) {
+            match ty {
+                WGDEVICE_A_PRIVATE_KEY => {
+                    let key = parse_key(data)?;
+
+                    if !key.is_zero() {
+                        device.set_private_key(key);
+                    }
+                }
+
+                WGDEVICE_A_LISTEN_PORT => {
+                    if let Some(port) = netlink::attr_u16(data).filter(|port| *port > 0) {
+                        device.set_listen_port(port);
+                    }
+                }
+
+                WGDEVICE_A_FWMARK => {
+                    if let Some(fwmark) = netlink::attr_u32(data).filter(|fwmark| *fwmark > 0) {
+                        device.set_fwmark(fwmark);
+                    }
+                }
+
+                WGDEVICE_A_PEERS => {
+                    for (_, data) in netlink::attrs(data) {
+                        let peer = parse_peer(data)?;
+                        let peers = device.peers_mut();
+
+                        match peers.last_mut() {
+                            Some(last) if last.public_key() == peer.public_key() => {
+                                last.allowed_ips_mut().extend_from_slice(peer.allowed_ips())
+                            }
+
+                            _ => peers.push(peer),
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    Ok(device)
+}
+
+/// Build a peer from its nested attribute.
+fn parse_peer(attrs: &[u8]) -> io::Result<Peer> {
+    let mut peer = Peer::new(Key::zero(), None);
+    let mut has_public_key = false;
+    let (mut rx_bytes, mut tx_bytes) = (0, 0);
+
+    for (ty, data) in netlink::attrs(attrs) {
+        match ty {
+            WGPEER_A_PUBLIC_KEY => {
+                peer.set_public_key(parse_key(data)?);
+                has_public_key = true;
+            }
+
+            WGPEER_A_PRESHARED_KEY => {
+                let key = parse_key(data)?;
+
+                if !key.is_zero() {
+                    peer.set_preshared_key(key);
+                }
+            }
+
+            WGPEER_A_ENDPOINT => {
+                if let Some(endpoint) = sockaddr_to_endpoint(data) {
+                    peer.set_endpoint(endpoint);
+                }
+            }
+
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL => {
+                let interval = netlink::attr_u16(data).filter(|interval| *interval > 0);
+
+                if let Some(interval) = interval {
+                    peer.set_persistent_keepalive(interval);
+                }
+            }
+
+            WGPEER_A_LAST_HANDSHAKE_TIME => {
+                let sec = netlink::attr_u64(data).unwrap_or(0);
+                let nsec = data.get(8..).and_then(netlink::attr_u64).unwrap_or(0);
+
+                if sec > 0 || nsec > 0 {
+                    peer.set_last_handshake(Some(UNIX_EPOCH + Duration::new(sec, nsec as u32)));
+                }
+            }
+
+            WGPEER_A_RX_BYTES => rx_bytes = netlink::attr_u64(data).unwrap_or(0),
+            WGPEER_A_TX_BYTES => tx_bytes = netlink::attr_u64(data).unwrap_or(0),
+
+            WGPEER_A_ALLOWEDIPS => {
+                for (_, data) in netlink::attrs(data) {
+                    if let Some(ip) = parse_allowed_ip(data) {
+                        peer.add_allowed_ip(ip);
+                    }
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    if !has_public_key {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Peer without a public key",
+        ));
+    }
+
+    peer.set_transfer(rx_bytes, tx_bytes);
+
+    Ok(peer)
+}
+
+/// Build an allowed IP from its nested attribute.
+fn parse_allowed_ip(attrs: &[u8]) -> Option<AllowedIp> {
+    let mut addr = None;
+    let mut mask = None;
+
+    for (ty, data) in netlink::attrs(attrs) {
+        match ty {
+            WGALLOWEDIP_A_IPADDR if data.len() == 4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(data);
+                addr = Some(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+
+            WGALLOWEDIP_A_IPADDR if data.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                addr = Some(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+
+            WGALLOWEDIP_A_CIDR_MASK => mask = netlink::attr_u8(data),
+
+            _ => {}
+        }
+    }
+
+    Some(AllowedIp::new(addr?, mask?))
+}
+
+fn parse_key(data: &[u8]) -> io::Result<Key> {
+    Key::from_slice(data).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Key attribute is not {} bytes long", KEY_SIZE),
+        )
+    })
+}
+
+/// Encode an endpoint as a `struct sockaddr_in` or `struct sockaddr_in6`.
+fn endpoint_to_sockaddr(endpoint: &Endpoint) -> Vec<u8> {
+    let (addr, port) = endpoint;
+
+    match addr {
+        IpAddr::V4(ip4) => {
+            let mut sockaddr = vec![0u8; 16];
+
+            sockaddr[0..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            sockaddr[2..4].copy_from_slice(&port.to_be_bytes());
+            sockaddr[4..8].copy_from_slice(&ip4.octets());
+
+            sockaddr
+        }
+
+        IpAddr::V6(ip6) => {
+            let mut sockaddr = vec![0u8; 28];
+
+            sockaddr[0..2].copy_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+            sockaddr[2..4].copy_from_slice(&port.to_be_bytes());
+            sockaddr[8..24].copy_from_slice(&ip6.octets());
+
+            sockaddr
+        }
+    }
+}
+
+/// Decode a `struct sockaddr_in` or `struct sockaddr_in6` into an endpoint.
+fn sockaddr_to_endpoint(data: &[u8]) -> Option<Endpoint> {
+    let family = netlink::attr_u16(data)? as i32;
+    let port = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]);
+
+    match family {
+        libc::AF_INET => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(data.get(4..8)?);
+
+            Some((IpAddr::V4(Ipv4Addr::from(octets)), port))
+        }
+
+        libc::AF_INET6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(data.get(8..24)?);
+
+            Some((IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+
+        _ => None,
+    }
+}
@@ -0,0 +1,224 @@
+//! Userspace WireGuard devices, such as the ones run by wireguard-go or boringtun.
+
+use std::fs;
+use std::io::{self, BufReader};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+#[cfg(feature = "tokio")]
+use crate::backend::AsyncBackend;
+use crate::backend::Backend;
+use crate::device::Device;
+use crate::uapi;
+
+/// The directory in which userspace implementations create their UAPI sockets.
+pub const SOCKET_DIR: &str = "/var/run/wireguard";
+
+/// Access to the userspace WireGuard devices exposing a `<name>.sock` UAPI socket in a directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Userspace {
+    dir: PathBuf,
+}
+
+impl Userspace {
+    /// Access the userspace devices in the default socket directory.
+    pub fn new() -> Userspace {
+        Userspace::with_dir(SOCKET_DIR)
+    }
+
+    /// Access the userspace devices whose sockets live in the specified directory.
+    pub fn with_dir<P: Into<PathBuf>>(dir: P) -> Userspace {
+        Userspace { dir: dir.into() }
+    }
+
+    /// Get the directory containing the sockets.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Get the path of the socket of the specified device.
+    pub fn socket_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.sock", name))
+    }
+
+    /// Check whether a socket exists for the specified device.
+    pub fn exists(&self, name: &str) -> bool {
+        fs::metadata(self.socket_path(name))
+            .map(|meta| meta.file_type().is_socket())
+            .unwrap_or(false)
+    }
+
+    /// Check whether a socket exists for the specified device, without blocking.
+    #[cfg(feature = "tokio")]
+    pub async fn exists_async(&self, name: &str) -> bool {
+        tokio::fs::metadata(self.socket_path(name))
+            .await
+            .map(|meta| meta.file_type().is_socket())
+            .unwrap_or(false)
+    }
+
+    /// Send a request to the socket of the specified device, and read the response up to the
+    /// empty line that ends it.
+    #[cfg(feature = "tokio")]
+    async fn exchange(&self, name: &str, request: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = tokio::net::UnixStream::connect(self.socket_path(name)).await?;
+        stream.write_all(request).await?;
+
+        let mut reader = tokio::io::BufReader::new(stream);
+        let mut response = Vec::new();
+
+        loop {
+            let start = response.len();
+
+            if reader.read_until(b'\n', &mut response).await? == 0 || &response[start..] == b"\n" {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Connect to the socket of the specified device.
+    fn connect(&self, name: &str) -> io::Result<UnixStream> {
+        UnixStream::connect(self.socket_path(name))
+    }
+}
+
+/// Whether connecting to a socket failed because nothing listens on it anymore, as happens with
+/// the sockets left behind by crashed implementations.
+pub(crate) fn is_stale(result: &io::Result<impl Sized>) -> bool {
+    matches!(result, Err(err) if err.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+impl Backend for Userspace {
+    /// List the names of the devices that have a socket in the directory, skipping the stale
+    /// sockets that refuse connections. A missing directory simply means that there are no
+    /// devices.
+    fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut names = Vec::new();
+
+        for entry in entries {
+            let entry = entry?;
+
+            if !entry.file_type()?.is_socket() {
+                continue;
+            }
+
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+
+            if let Some(name) = file_name.strip_suffix(".sock") {
+                if !is_stale(&UnixStream::connect(entry.path())) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    fn get(&self, name: &str) -> io::Result<Device> {
+        let mut stream = self.connect(name)?;
+
+        uapi::write_get(&mut stream)?;
+
+        Ok(uapi::read_get(name, BufReader::new(stream))?)
+    }
+
+    fn set(&self, device: &Device) -> io::Result<()> {
+        let mut stream = self.connect(device.name())?;
+
+        uapi::write_set(device, &mut stream)?;
+
+        Ok(uapi::read_set(BufReader::new(stream))?)
+    }
+
+    /// Userspace devices are created by starting their implementation, which is out of the reach
+    /// of this library.
+    fn create(&self, _name: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "userspace devices must be created by their implementation",
+        ))
+    }
+
+    /// Remove the socket of the device, which makes userspace implementations shut it down.
+    fn delete(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.socket_path(name))
+    }
+}
+
+/// The same protocol as the blocking implementation, spoken over a non-blocking socket.
+#[cfg(feature = "tokio")]
+impl AsyncBackend for Userspace {
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut names = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_socket() {
+                continue;
+            }
+
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+
+            if let Some(name) = file_name.strip_suffix(".sock") {
+                if !is_stale(&tokio::net::UnixStream::connect(entry.path()).await) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Device> {
+        let mut request = Vec::new();
+        uapi::write_get(&mut request)?;
+
+        let response = self.exchange(name, &request).await?;
+
+        Ok(uapi::read_get(name, &response[..])?)
+    }
+
+    async fn set(&self, device: &Device) -> io::Result<()> {
+        let mut request = Vec::new();
+        uapi::write_set(device, &mut request)?;
+
+        let response = self.exchange(device.name(), &request).await?;
+
+        Ok(uapi::read_set(&response[..])?)
+    }
+
+    async fn create(&self, name: &str) -> io::Result<()> {
+        Backend::create(self, name)
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        tokio::fs::remove_file(self.socket_path(name)).await
+    }
+}
+
+impl Default for Userspace {
+    fn default() -> Userspace {
+        Userspace::new()
+    }
+}
@@ -0,0 +1,177 @@
+//! rwg-exporter - expose the metrics of WireGuard devices to Prometheus.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use rwg::metrics::{self, Names};
+use rwg::Device;
+
+/// Address listened on when none is given: local only, on the port commonly used by WireGuard
+/// exporters.
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9586";
+
+/// Time after which a client that does not finish sending its request is dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str =
+    "Usage: rwg-exporter [-l <address>:<port>] [-n <names file>] [-i <interface>]...
+
+  Serves the metrics of the WireGuard interfaces of this machine in the
+  OpenMetrics text format, at http://<address>:<port>/metrics.
+
+  -l: the address to listen on, 127.0.0.1:9586 by default.
+  -n: a file giving names to peers, with one base64 public key per line
+      followed by the name of the peer. It is read again on every scrape.
+  -i: only export this interface. May be given several times.";
+
+/// Options given on the command line.
+struct Options {
+    address: SocketAddr,
+    names: Option<PathBuf>,
+    interfaces: Vec<String>,
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = match parse(&args) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = serve(&options) {
+        eprintln!("rwg-exporter: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Parse the command line.
+fn parse(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        address: DEFAULT_ADDRESS.parse().ok()?,
+        names: None,
+        interfaces: Vec::new(),
+    };
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let value = args.next()?;
+
+        match arg.as_str() {
+            "-l" => options.address = value.parse().ok()?,
+            "-n" => options.names = Some(PathBuf::from(value)),
+            "-i" => options.interfaces.push(value.clone()),
+            _ => return None,
+        }
+    }
+
+    Some(options)
+}
+
+/// Accept connections, one at a time.
+fn serve(options: &Options) -> io::Result<()> {
+    let listener = TcpListener::bind(options.address)?;
+
+    eprintln!(
+        "rwg-exporter: listening on http://{}/metrics",
+        options.address
+    );
+
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| handle(options, stream));
+
+        if let Err(err) = result {
+            eprintln!("rwg-exporter: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer the request of a client.
+fn handle(options: &Options, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+
+    reader.read_line(&mut request)?;
+
+    // The headers are not needed, but must be read before answering.
+    loop {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or("");
+
+    match (method, path) {
+        ("GET", "/metrics") => match collect(options) {
+            Ok(body) => respond(stream, "200 OK", metrics::CONTENT_TYPE, &body),
+            Err(err) => respond(stream, "500 Internal Server Error", "text/plain", &err),
+        },
+
+        ("GET", "/") => respond(
+            stream,
+            "200 OK",
+            "text/html",
+            "<html><body><a href=\"/metrics\">Metrics</a></body></html>\n",
+        ),
+
+        ("GET", _) => respond(stream, "404 Not Found", "text/plain", "Not found\n"),
+        _ => respond(stream, "405 Method Not Allowed", "text/plain", "GET only\n"),
+    }
+}
+
+/// Retrieve the devices and render their metrics.
+fn collect(options: &Options) -> Result<String, String> {
+    let names = match &options.names {
+        Some(path) => File::open(path)
+            .and_then(|file| Names::read(BufReader::new(file)))
+            .map_err(|err| format!("{}: {}\n", path.display(), err))?,
+        None => Names::new(),
+    };
+
+    let mut devices = Device::all().map_err(|err| format!("{}\n", err))?;
+
+    if !options.interfaces.is_empty() {
+        devices.retain(|device| options.interfaces.iter().any(|name| name == device.name()));
+    }
+
+    devices.sort_by(|a, b| a.name().cmp(b.name()));
+
+    Ok(metrics::render(&devices, &names))
+}
+
+/// Send a response and close the connection.
+fn respond(mut stream: TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+
+    stream.flush()
+}
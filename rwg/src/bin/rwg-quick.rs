@@ -0,0 +1,623 @@
+//! rwg-quick - set up a WireGuard interface simply, like wg-quick(8).
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+use rwg::config::{self, Config, Hook, Table};
+use rwg::metadata::Store;
+use rwg::{Address, AllowedIp, Device, MAIN_TABLE};
+
+/// Directory of the configurations designated by interface name.
+const CONFIG_DIR: &str = "/etc/wireguard";
+
+/// Directory of the state records written by `up` and consumed by `down`.
+const STATE_DIR: &str = "/run/rwg-quick";
+
+/// MTU used when the configuration does not set one, the fallback of wg-quick.
+const DEFAULT_MTU: u32 = 1420;
+
+const USAGE: &str = "Usage: rwg-quick [ up | down | save | strip ] [ CONFIG_FILE | INTERFACE ]
+
+  CONFIG_FILE is a configuration file, whose filename is the interface name
+  followed by `.conf'. Otherwise, INTERFACE is an interface name, with
+  configuration found at /etc/wireguard/INTERFACE.conf. It is to be readable
+  by wg(8)'s `setconf' sub-command, with the exception of the following
+  additions to the [Interface] section, which are handled by rwg-quick:
+
+  - Address: may be specified one or more times and contains one or more
+    IP addresses (with an optional CIDR mask) to be set for the interface.
+  - DNS: an optional DNS server to use while the device is up.
+  - MTU: an optional MTU for the interface; if unspecified, 1420 is used.
+  - Table: an optional routing table to which routes will be added; if
+    unspecified or `auto', the default table is used. If `off', no routes
+    are added.
+  - PreUp, PostUp, PreDown, PostDown: script snippets which will be executed
+    by sh(1) at the corresponding phases of the link, most commonly used
+    to configure DNS. The string `%i' is expanded to INTERFACE.
+  - SaveConfig: if set to `true', the configuration is saved from the current
+    state of the interface upon shutdown.
+
+See wg-quick(8) for more info and examples.";
+
+/// The interface designated on the command line, and its configuration file.
+struct Target {
+    name: String,
+    path: PathBuf,
+}
+
+/// What `up` did to the system, so that `down` can undo exactly that.
+#[derive(Debug, Default)]
+struct State {
+    config: PathBuf,
+    addresses: Vec<Address>,
+    routes: Option<u32>,
+    full_tunnel: Option<u32>,
+    dns: bool,
+    pre_down: Vec<String>,
+    post_down: Vec<String>,
+    save_config: bool,
+}
+
+impl State {
+    /// Start the record of what is done for a configuration.
+    fn new(target: &Target, config: &Config) -> State {
+        State {
+            config: target.path.clone(),
+            pre_down: hooks(config, Hook::PreDown),
+            post_down: hooks(config, Hook::PostDown),
+            save_config: config.save_config(),
+            ..State::default()
+        }
+    }
+
+    /// Guess what `up` did from the configuration and the device, for interfaces that were not
+    /// brought up by rwg-quick.
+    fn guess(target: &Target, config: &Config, device: &Device) -> State {
+        let mut state = State::new(target, config);
+
+        state.addresses = config.addresses().to_vec();
+        state.dns = !config.dns().is_empty();
+
+        match config.table() {
+            Table::Off => {}
+            Table::Id(table) => state.routes = Some(table),
+
+            Table::Auto => {
+                state.routes = Some(MAIN_TABLE);
+
+                if device.routes().iter().any(|ip| ip.mask() == 0) {
+                    state.full_tunnel = device.fwmark().filter(|fwmark| *fwmark > 0);
+                }
+            }
+        }
+
+        state
+    }
+
+    /// Path of the record of an interface.
+    fn path(name: &str) -> PathBuf {
+        Path::new(STATE_DIR).join(format!("{}.state", name))
+    }
+
+    /// Load the record of an interface, if any.
+    fn load(name: &str) -> io::Result<Option<State>> {
+        let file = match File::open(State::path(name)) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut state = State::default();
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut parts = line.splitn(2, '=');
+            let (key, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid state record");
+
+            match key {
+                "config" => state.config = PathBuf::from(value),
+                "address" => state.addresses.push(value.parse().map_err(|_| invalid())?),
+                "routes" => state.routes = Some(value.parse().map_err(|_| invalid())?),
+                "full_tunnel" => state.full_tunnel = Some(value.parse().map_err(|_| invalid())?),
+                "dns" => state.dns = value == "true",
+                "pre_down" => state.pre_down.push(value.to_owned()),
+                "post_down" => state.post_down.push(value.to_owned()),
+                "save_config" => state.save_config = value == "true",
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Some(state))
+    }
+
+    /// Write the record of an interface.
+    fn store(&self, name: &str) -> io::Result<()> {
+        let mut buf = format!("config={}\n", self.config.display());
+
+        for address in &self.addresses {
+            buf.push_str(&format!("address={}\n", address));
+        }
+
+        if let Some(table) = self.routes {
+            buf.push_str(&format!("routes={}\n", table));
+        }
+
+        if let Some(table) = self.full_tunnel {
+            buf.push_str(&format!("full_tunnel={}\n", table));
+        }
+
+        buf.push_str(&format!("dns={}\n", self.dns));
+
+        for command in &self.pre_down {
+            buf.push_str(&format!("pre_down={}\n", command));
+        }
+
+        for command in &self.post_down {
+            buf.push_str(&format!("post_down={}\n", command));
+        }
+
+        buf.push_str(&format!("save_config={}\n", self.save_config));
+
+        fs::create_dir_all(STATE_DIR)?;
+        write_private(&State::path(name), buf.as_bytes())
+    }
+
+    /// Remove the record of an interface.
+    fn remove(name: &str) -> io::Result<()> {
+        match fs::remove_file(State::path(name)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let result = match args.as_slice() {
+        ["up", target] => target_of(target).and_then(|target| up(&target)),
+        ["down", target] => target_of(target).and_then(|target| down(&target)),
+        ["save", target] => target_of(target).and_then(|target| save(&target)),
+        ["strip", target] => target_of(target).and_then(|target| strip(&target)),
+
+        ["help"] | ["--help"] | ["-h"] => {
+            println!("{}", USAGE);
+            return;
+        }
+
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("rwg-quick: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Bring an interface up from its configuration.
+fn up(target: &Target) -> io::Result<()> {
+    let config = read_config(target)?;
+
+    if Device::open(target.name.as_str()).is_ok() {
+        return Err(error(format!("`{}' already exists", target.name)));
+    }
+
+    run_hooks(target, &hooks(&config, Hook::PreUp))?;
+
+    log(format!("ip link add {} type wireguard", target.name));
+    Device::create(target.name.as_str(), None)?;
+
+    let mut state = State::new(target, &config);
+
+    if let Err(err) = set_up(target, &config, &mut state) {
+        let _ = tear_down(target, &state, false);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Configure a freshly created interface, recording every change in `state`.
+fn set_up(target: &Target, config: &Config, state: &mut State) -> io::Result<()> {
+    log(format!("wg setconf {} /dev/fd/63", target.name));
+    config.device().clone().save()?;
+
+    let mut device = Device::open(target.name.as_str())?;
+
+    for address in config.addresses() {
+        log(format!("ip address add {} dev {}", address, target.name));
+        device.add_address(address)?;
+        state.addresses.push(address.clone());
+    }
+
+    let mtu = config.mtu().unwrap_or(DEFAULT_MTU);
+
+    log(format!("ip link set mtu {} up dev {}", mtu, target.name));
+    device.set_mtu(mtu)?;
+    device.set_up()?;
+
+    if !config.dns().is_empty() {
+        set_dns(target, config.dns())?;
+        state.dns = true;
+    }
+
+    let routes = device.routes();
+
+    match config.table() {
+        Table::Off => {}
+
+        Table::Id(table) => {
+            add_routes(target, &device, &routes, table)?;
+            state.routes = Some(table);
+        }
+
+        Table::Auto => {
+            let (defaults, routes): (Vec<_>, Vec<_>) =
+                routes.into_iter().partition(|ip| ip.mask() == 0);
+
+            add_routes(target, &device, &routes, MAIN_TABLE)?;
+            state.routes = Some(MAIN_TABLE);
+
+            if !defaults.is_empty() {
+                let table = device.enable_full_tunnel()?;
+
+                log(format!("wg set {} fwmark {}", target.name, table));
+
+                for default in &defaults {
+                    let family = if default.addr().is_ipv4() { "-4" } else { "-6" };
+
+                    log(format!(
+                        "ip {} route add {} dev {} table {}",
+                        family,
+                        route(default),
+                        target.name,
+                        table
+                    ));
+                    log(format!(
+                        "ip {} rule add not fwmark {} table {}",
+                        family, table, table
+                    ));
+                    log(format!(
+                        "ip {} rule add table main suppress_prefixlength 0",
+                        family
+                    ));
+                }
+
+                state.full_tunnel = Some(table);
+            }
+        }
+    }
+
+    state.store(&target.name)?;
+
+    if let Err(err) = run_hooks(target, &hooks(config, Hook::PostUp)) {
+        let _ = State::remove(&target.name);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Bring an interface down, undoing what `up` did.
+fn down(target: &Target) -> io::Result<()> {
+    let device = Device::open(target.name.as_str())
+        .map_err(|_| error(format!("`{}' is not a WireGuard interface", target.name)))?;
+
+    let state = match State::load(&target.name)? {
+        Some(state) => state,
+        None => State::guess(target, &read_config(target)?, &device),
+    };
+
+    tear_down(target, &state, true)
+}
+
+/// Undo the changes recorded in `state` and delete the interface. Hooks only run when `hooks` is
+/// set, which is not the case when rolling back a failed `up`.
+fn tear_down(target: &Target, state: &State, hooks: bool) -> io::Result<()> {
+    if hooks {
+        run_hooks(target, &state.pre_down)?;
+    }
+
+    if hooks && state.save_config {
+        save_to(target, &state.config)?;
+    }
+
+    if let Some(table) = state.full_tunnel {
+        let mut device = Device::new(target.name.as_str());
+        device.set_fwmark(table);
+
+        log(format!("ip rule delete table {}", table));
+        log("ip rule delete table main suppress_prefixlength 0");
+        device.disable_full_tunnel()?;
+    }
+
+    if state.dns {
+        unset_dns(target)?;
+    }
+
+    log(format!("ip link delete dev {}", target.name));
+    Device::new(target.name.as_str()).delete()?;
+
+    if hooks {
+        run_hooks(target, &state.post_down)?;
+    }
+
+    State::remove(&target.name)
+}
+
+/// Save the current configuration of an interface to its configuration file.
+fn save(target: &Target) -> io::Result<()> {
+    if Device::open(target.name.as_str()).is_err() {
+        return Err(error(format!(
+            "`{}' is not a WireGuard interface",
+            target.name
+        )));
+    }
+
+    save_to(target, &target.path)
+}
+
+/// Write the current configuration of an interface to `path`, keeping the wg-quick settings of
+/// the file that are not part of the state of the interface, and the metadata of its peers, from
+/// the file and its sidecar.
+fn save_to(target: &Target, path: &Path) -> io::Result<()> {
+    let old = read_config(&Target {
+        name: target.name.clone(),
+        path: path.to_owned(),
+    })?;
+
+    let mut metadata = Store::open_for(path)?;
+    metadata.collect(old.device());
+
+    let mut device = Device::open(target.name.as_str())?;
+    metadata.retain(&device);
+    metadata.attach(&mut device);
+
+    let mut config = Config::new(device.clone());
+
+    for address in device.addresses()? {
+        config.add_address(address);
+    }
+
+    for dns in old.dns() {
+        config.add_dns(dns.as_str());
+    }
+
+    if old.mtu().is_some() {
+        config.set_mtu(device.link()?.mtu());
+    }
+
+    config.set_table(old.table());
+    config.set_save_config(old.save_config());
+
+    for &hook in &[Hook::PreUp, Hook::PostUp, Hook::PreDown, Hook::PostDown] {
+        for command in old.hooks(hook) {
+            config.add_hook(hook, command);
+        }
+    }
+
+    let mut buf = Vec::new();
+    config::write(&config, &mut buf)?;
+
+    let tmp = path.with_extension("conf.tmp");
+
+    write_private(&tmp, &buf)?;
+    fs::rename(&tmp, path)?;
+
+    metadata.save()
+}
+
+/// Print the configuration of an interface without the wg-quick settings.
+fn strip(target: &Target) -> io::Result<()> {
+    let file = BufReader::new(File::open(&target.path)?);
+    let stdout = io::stdout();
+
+    config::strip(file, stdout.lock())?;
+
+    Ok(())
+}
+
+/// Find the interface and configuration file designated on the command line.
+fn target_of(arg: &str) -> io::Result<Target> {
+    if is_interface_name(arg) {
+        return Ok(Target {
+            name: arg.to_owned(),
+            path: Path::new(CONFIG_DIR).join(format!("{}.conf", arg)),
+        });
+    }
+
+    let path = PathBuf::from(arg);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.ends_with(".conf"))
+        .map(|name| name.trim_end_matches(".conf"))
+        .filter(|name| is_interface_name(name))
+        .ok_or_else(|| {
+            error("the config file must be a valid interface name, followed by .conf")
+        })?;
+
+    Ok(Target {
+        name: name.to_owned(),
+        path: path.clone(),
+    })
+}
+
+/// Whether a string is a valid interface name: 1 to 15 characters among letters, digits and
+/// `_=+.-`.
+fn is_interface_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 15
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_=+.-".contains(c))
+}
+
+/// Read the configuration file of an interface, warning if it is readable by everyone.
+fn read_config(target: &Target) -> io::Result<Config> {
+    let file = File::open(&target.path)?;
+
+    if file.metadata()?.permissions().mode() & 0o007 != 0 {
+        eprintln!("Warning: `{}' is world accessible", target.path.display());
+    }
+
+    Ok(config::read(&target.name, BufReader::new(file))?)
+}
+
+/// Get the commands of a hook.
+fn hooks(config: &Config, hook: Hook) -> Vec<String> {
+    config.hooks(hook).into_iter().map(str::to_owned).collect()
+}
+
+/// Run hook commands with sh(1), replacing `%i` with the interface name.
+fn run_hooks(target: &Target, commands: &[String]) -> io::Result<()> {
+    for command in commands {
+        let command = command.replace("%i", &target.name);
+
+        log(&command);
+
+        let status = Command::new("sh").arg("-c").arg(&command).status()?;
+
+        if !status.success() {
+            return Err(error(format!("`{}' failed: {}", command, status)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Route networks through an interface in a table.
+fn add_routes(
+    target: &Target,
+    device: &Device,
+    routes: &[AllowedIp],
+    table: u32,
+) -> io::Result<()> {
+    for ip in routes {
+        let family = if ip.addr().is_ipv4() { "-4" } else { "-6" };
+
+        if table == MAIN_TABLE {
+            log(format!(
+                "ip {} route add {} dev {}",
+                family,
+                route(ip),
+                target.name
+            ));
+        } else {
+            log(format!(
+                "ip {} route add {} dev {} table {}",
+                family,
+                route(ip),
+                target.name,
+                table
+            ));
+        }
+    }
+
+    device.reconcile_routes(routes, table)
+}
+
+/// Format the destination of a route.
+fn route(ip: &AllowedIp) -> String {
+    format!("{}/{}", ip.addr(), ip.mask())
+}
+
+/// Register the DNS servers and search domains of an interface with resolvconf(8).
+fn set_dns(target: &Target, dns: &[String]) -> io::Result<()> {
+    let iface = format!("{}{}", resolvconf_prefix(), target.name);
+    let mut input = String::new();
+
+    for entry in dns {
+        if entry.parse::<std::net::IpAddr>().is_ok() {
+            input.push_str(&format!("nameserver {}\n", entry));
+        }
+    }
+
+    let search = dns
+        .iter()
+        .filter(|entry| entry.parse::<std::net::IpAddr>().is_err())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    if !search.is_empty() {
+        input.push_str(&format!("search {}\n", search.join(" ")));
+    }
+
+    log(format!("resolvconf -a {} -m 0 -x", iface));
+
+    let mut child = Command::new("resolvconf")
+        .args(["-a", &iface, "-m", "0", "-x"])
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+
+    if !status.success() {
+        return Err(error(format!("resolvconf failed: {}", status)));
+    }
+
+    Ok(())
+}
+
+/// Remove the DNS configuration of an interface from resolvconf(8).
+fn unset_dns(target: &Target) -> io::Result<()> {
+    let iface = format!("{}{}", resolvconf_prefix(), target.name);
+
+    log(format!("resolvconf -d {} -f", iface));
+
+    let status = Command::new("resolvconf")
+        .args(["-d", &iface, "-f"])
+        .status()?;
+
+    if !status.success() {
+        return Err(error(format!("resolvconf failed: {}", status)));
+    }
+
+    Ok(())
+}
+
+/// Get the prefix resolvconf(8) expects for tunnel interfaces, if it orders interfaces.
+fn resolvconf_prefix() -> &'static str {
+    let order = fs::read_to_string("/etc/resolvconf/interface-order").unwrap_or_default();
+
+    if order.lines().any(|line| line.trim().starts_with("tun")) {
+        "tun."
+    } else {
+        ""
+    }
+}
+
+/// Write a file only readable by its owner.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    file.write_all(contents)
+}
+
+/// Print an action on the standard error, the way wg-quick does.
+fn log<S: AsRef<str>>(action: S) {
+    eprintln!("[#] {}", action.as_ref());
+}
+
+/// Construct an error from a message.
+fn error<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::other(msg.into())
+}
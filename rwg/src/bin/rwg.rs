@@ -0,0 +1,598 @@
+//! rwg - configure WireGuard devices, like wg(8).
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::MetadataExt;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rwg::backend::{Backend, System};
+use rwg::config;
+use rwg::show::Printer;
+use rwg::{Device, Key, Peer};
+
+const USAGE: &str = "Usage: rwg <cmd> [<args>]
+
+Available subcommands:
+  show: Shows the current configuration and device information
+  showconf: Shows the current configuration of a given WireGuard interface, for use with `setconf'
+  set: Change the current configuration, add peers, remove peers, or change peers
+  setconf: Applies a configuration file to a WireGuard interface
+  addconf: Appends a configuration file to a WireGuard interface
+  syncconf: Synchronizes a configuration file to a WireGuard interface
+  genkey: Generates a new private key and writes it to stdout
+  genpsk: Generates a new preshared key and writes it to stdout
+  pubkey: Reads a private key from stdin and writes a public key to stdout
+You may pass `--help' to any of these subcommands to view usage.";
+
+const SHOW_USAGE: &str = "Usage: rwg show { <interface> | all | interfaces } [public-key | private-key | listen-port | fwmark | peers | preshared-keys | endpoints | allowed-ips | latest-handshakes | transfer | persistent-keepalive | dump]";
+
+const SET_USAGE: &str = "Usage: rwg set <interface> [listen-port <port>] [fwmark <mark>] [private-key <file path>] [peer <base64 public key> [remove] [preshared-key <file path>] [endpoint <ip>:<port>] [persistent-keepalive <interval seconds>] [allowed-ips <ip1>/<cidr1>[,<ip2>/<cidr2>]...] ]...";
+
+/// Outcome of a subcommand: `Usage` prints the usage of the subcommand and fails.
+enum Error {
+    Usage(&'static str),
+    Failed(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Failed(err.to_string())
+    }
+}
+
+type Result = std::result::Result<(), Error>;
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (*command, args),
+        None => ("show", &[][..]),
+    };
+
+    if args
+        .first()
+        .is_some_and(|arg| *arg == "--help" || *arg == "-h")
+    {
+        let usage = match command {
+            "show" => SHOW_USAGE,
+            "set" => SET_USAGE,
+            "showconf" => "Usage: rwg showconf <interface>",
+            "setconf" | "addconf" | "syncconf" => {
+                eprintln!(
+                    "Usage: rwg {} <interface> <configuration filename>",
+                    command
+                );
+                process::exit(1);
+            }
+            "genkey" | "genpsk" | "pubkey" => {
+                eprintln!("Usage: rwg {}", command);
+                process::exit(1);
+            }
+            _ => USAGE,
+        };
+
+        eprintln!("{}", usage);
+        process::exit(1);
+    }
+
+    let result = match command {
+        "show" => show(args),
+        "showconf" => showconf(args),
+        "set" => set(args),
+        "setconf" => conf(command, args),
+        "addconf" => conf(command, args),
+        "syncconf" => conf(command, args),
+        "genkey" => genkey(args, Key::generate_private()),
+        "genpsk" => genkey(args, Key::generate_preshared()),
+        "pubkey" => pubkey(args),
+
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            return;
+        }
+
+        "--version" | "-v" => {
+            println!("rwg v{}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+
+        _ => {
+            eprintln!("Invalid subcommand: `{}'", command);
+            Err(Error::Usage(USAGE))
+        }
+    };
+
+    match result {
+        Ok(()) => {}
+
+        Err(Error::Usage(usage)) => {
+            eprintln!("{}", usage);
+            process::exit(1);
+        }
+
+        Err(Error::Failed(msg)) => {
+            eprintln!("{}", msg);
+            process::exit(1);
+        }
+    }
+}
+
+/// `rwg show`: print devices, or one of their fields.
+fn show(args: &[&str]) -> Result {
+    let (target, field) = match args {
+        [] => ("all", None),
+        [target] => (*target, None),
+        [target, field] => (*target, Some(*field)),
+        _ => return Err(Error::Usage(SHOW_USAGE)),
+    };
+
+    let backend = System::new();
+
+    if target == "interfaces" {
+        if field.is_some() {
+            return Err(Error::Usage(SHOW_USAGE));
+        }
+
+        let mut names = list(&backend)?;
+        names.sort();
+
+        println!("{}", names.join(" "));
+        return Ok(());
+    }
+
+    let devices = if target == "all" {
+        let mut names = list(&backend)?;
+        names.sort();
+
+        let mut devices = Vec::with_capacity(names.len());
+
+        for name in names {
+            devices.push(open(&backend, &name)?);
+        }
+
+        devices
+    } else {
+        vec![open(&backend, target)?]
+    };
+
+    let mut printer = Printer::new();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    printer.set_colors(colors());
+
+    for (i, device) in devices.iter().enumerate() {
+        let prefix = if target == "all" {
+            format!("{}\t", device.name())
+        } else {
+            String::new()
+        };
+
+        match field {
+            None => {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+
+                printer.write(device, SystemTime::now(), &mut out)?;
+            }
+
+            Some(field) => {
+                if !print_field(&mut out, device, field, &prefix)? {
+                    return Err(Error::Usage(SHOW_USAGE));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print one field of a device in the tab-separated format of `wg show`. Returns `false` for an
+/// unknown field.
+fn print_field<W: Write>(
+    out: &mut W,
+    device: &Device,
+    field: &str,
+    prefix: &str,
+) -> io::Result<bool> {
+    match field {
+        "public-key" => writeln!(
+            out,
+            "{}{}",
+            prefix,
+            key_or_none(device.public_key().as_ref())
+        )?,
+        "private-key" => writeln!(out, "{}{}", prefix, key_or_none(device.private_key()))?,
+        "listen-port" => writeln!(out, "{}{}", prefix, device.listen_port().unwrap_or(0))?,
+        "fwmark" => writeln!(out, "{}{}", prefix, fwmark(device))?,
+
+        "dump" => {
+            writeln!(
+                out,
+                "{}{}\t{}\t{}\t{}",
+                prefix,
+                key_or_none(device.private_key()),
+                key_or_none(device.public_key().as_ref()),
+                device.listen_port().unwrap_or(0),
+                fwmark(device)
+            )?;
+
+            for peer in device.peers() {
+                writeln!(
+                    out,
+                    "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    prefix,
+                    key_or_none(peer.public_key()),
+                    key_or_none(peer.preshared_key()),
+                    endpoint(peer),
+                    allowed_ips(peer, ","),
+                    latest_handshake(peer),
+                    peer.rx_bytes(),
+                    peer.tx_bytes(),
+                    keepalive(peer)
+                )?;
+            }
+        }
+
+        "peers"
+        | "preshared-keys"
+        | "endpoints"
+        | "allowed-ips"
+        | "latest-handshakes"
+        | "transfer"
+        | "persistent-keepalive" => {
+            for peer in device.peers() {
+                let public_key = key_or_none(peer.public_key());
+
+                let value = match field {
+                    "peers" => {
+                        writeln!(out, "{}{}", prefix, public_key)?;
+                        continue;
+                    }
+                    "preshared-keys" => key_or_none(peer.preshared_key()),
+                    "endpoints" => endpoint(peer),
+                    "allowed-ips" => allowed_ips(peer, " "),
+                    "latest-handshakes" => latest_handshake(peer).to_string(),
+                    "transfer" => format!("{}\t{}", peer.rx_bytes(), peer.tx_bytes()),
+                    _ => keepalive(peer),
+                };
+
+                writeln!(out, "{}{}\t{}", prefix, public_key, value)?;
+            }
+        }
+
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// `rwg showconf`: print the configuration of a device.
+fn showconf(args: &[&str]) -> Result {
+    let name = match args {
+        [name] => *name,
+        _ => return Err(Error::Usage("Usage: rwg showconf <interface>")),
+    };
+
+    let device = open(&System::new(), name)?;
+    let stdout = io::stdout();
+
+    config::write_device(&device, stdout.lock())?;
+
+    Ok(())
+}
+
+/// `rwg set`: change the configuration of a device from the command line.
+fn set(args: &[&str]) -> Result {
+    let (name, mut args) = match args.split_first() {
+        Some((name, args)) if !args.is_empty() => (*name, args),
+        _ => return Err(Error::Usage(SET_USAGE)),
+    };
+
+    let mut device = Device::new(name);
+    let mut peer: Option<Peer> = None;
+
+    device.set_replace_peers(false);
+
+    while let Some((option, rest)) = args.split_first() {
+        args = rest;
+
+        if *option == "remove" {
+            match peer.as_mut() {
+                Some(peer) => peer.set_remove(true),
+                None => return Err(Error::Usage(SET_USAGE)),
+            }
+
+            continue;
+        }
+
+        let value = match args.split_first() {
+            Some((value, rest)) => {
+                args = rest;
+                *value
+            }
+
+            None => return Err(Error::Usage(SET_USAGE)),
+        };
+
+        let invalid = || Error::Failed(format!("Invalid {}: `{}'", option, value));
+
+        match (*option, peer.as_mut()) {
+            ("listen-port", None) => device.set_listen_port(value.parse().map_err(|_| invalid())?),
+
+            ("fwmark", None) => {
+                let fwmark = if value == "off" {
+                    0
+                } else if let Some(hex) = value.strip_prefix("0x") {
+                    u32::from_str_radix(hex, 16).map_err(|_| invalid())?
+                } else {
+                    value.parse().map_err(|_| invalid())?
+                };
+
+                device.set_fwmark(fwmark);
+            }
+
+            ("private-key", None) => device.set_private_key(read_key_file(value)?),
+
+            ("peer", _) => {
+                if let Some(peer) = peer.take() {
+                    device.add_peer(peer);
+                }
+
+                let key = Key::from_base64(value).map_err(|_| invalid())?;
+                let mut new = Peer::new(key, None);
+
+                new.set_replace_allowed_ips(false);
+                peer = Some(new);
+            }
+
+            ("preshared-key", Some(peer)) => peer.set_preshared_key(read_key_file(value)?),
+
+            ("endpoint", Some(peer)) => {
+                let addr = value
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .ok_or_else(invalid)?;
+
+                peer.set_endpoint((addr.ip(), addr.port()));
+            }
+
+            ("persistent-keepalive", Some(peer)) => {
+                let interval = match value {
+                    "off" => 0,
+                    value => value.parse().map_err(|_| invalid())?,
+                };
+
+                peer.set_persistent_keepalive(interval);
+            }
+
+            ("allowed-ips", Some(peer)) => {
+                peer.set_replace_allowed_ips(true);
+
+                for ip in value.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                    peer.add_allowed_ip(ip.parse().map_err(|_| invalid())?);
+                }
+            }
+
+            _ => {
+                eprintln!("Invalid argument: {}", option);
+                return Err(Error::Usage(SET_USAGE));
+            }
+        }
+    }
+
+    if let Some(peer) = peer.take() {
+        device.add_peer(peer);
+    }
+
+    save(&System::new(), &device)
+}
+
+/// `rwg setconf`, `addconf` and `syncconf`: apply a configuration file to a device.
+fn conf(command: &str, args: &[&str]) -> Result {
+    let (name, path) = match args {
+        [name, path] => (*name, *path),
+        _ => {
+            eprintln!(
+                "Usage: rwg {} <interface> <configuration filename>",
+                command
+            );
+            process::exit(1);
+        }
+    };
+
+    let file = File::open(path).map_err(|err| Error::Failed(format!("{}: {}", path, err)))?;
+    let mut device = config::read_device(name, BufReader::new(file))
+        .map_err(|err| Error::Failed(format!("{}: {}", path, err)))?;
+
+    let backend = System::new();
+
+    match command {
+        "addconf" => {
+            device.set_replace_peers(false);
+
+            for peer in device.peers_mut() {
+                peer.set_replace_allowed_ips(false);
+            }
+        }
+
+        "syncconf" => {
+            let current = open(&backend, name)?;
+
+            device.set_replace_peers(false);
+
+            for peer in current.peers() {
+                let key = match peer.public_key() {
+                    Some(key) => key,
+                    None => continue,
+                };
+
+                let new = device
+                    .peers_mut()
+                    .iter_mut()
+                    .find(|new| new.public_key() == Some(key));
+
+                match new {
+                    // Like wg(8), clear the preshared keys that the file no longer has.
+                    Some(new) => {
+                        if peer.preshared_key().is_some() && new.preshared_key().is_none() {
+                            new.set_preshared_key(Key::zero());
+                        }
+                    }
+
+                    None => {
+                        let mut removal = Peer::new(key.clone(), None);
+
+                        removal.set_remove(true);
+                        device.add_peer(removal);
+                    }
+                }
+            }
+        }
+
+        _ => {}
+    }
+
+    save(&backend, &device)
+}
+
+/// `rwg genkey` and `genpsk`: print a new key.
+fn genkey(args: &[&str], key: Key) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage(USAGE));
+    }
+
+    if let Ok(metadata) = fs::metadata("/dev/stdout") {
+        if metadata.is_file() && metadata.mode() & 0o007 != 0 {
+            eprintln!("Warning: writing to world accessible file.");
+            eprintln!("Consider setting the umask to 077 and trying again.");
+        }
+    }
+
+    println!("{}", key);
+
+    Ok(())
+}
+
+/// `rwg pubkey`: print the public key of the private key read from the standard input.
+fn pubkey(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage(USAGE));
+    }
+
+    let mut input = String::new();
+
+    io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|_| Error::Failed("Error: unable to read private key from stdin".to_owned()))?;
+
+    let key = Key::from_base64(input.trim())
+        .map_err(|_| Error::Failed("pubkey: Key is not the correct length or format".to_owned()))?;
+
+    println!("{}", key.derive_public());
+
+    Ok(())
+}
+
+/// Whether to use terminal colors: `WG_COLOR_MODE` can be `always` or `never`, and colors are
+/// used when the standard output is a terminal otherwise.
+fn colors() -> bool {
+    match env::var("WG_COLOR_MODE").as_ref().map(String::as_str) {
+        Ok("always") => true,
+        Ok("never") => false,
+        _ => unsafe { libc::isatty(1) == 1 },
+    }
+}
+
+/// List the devices, failing like wg(8).
+fn list<B: Backend>(backend: &B) -> std::result::Result<Vec<String>, Error> {
+    backend
+        .list()
+        .map_err(|err| Error::Failed(format!("Unable to list interfaces: {}", err)))
+}
+
+/// Open a device with the metadata of its peers, failing like wg(8).
+fn open<B: Backend>(backend: &B, name: &str) -> std::result::Result<Device, Error> {
+    Device::open_annotated_with(backend, name)
+        .map_err(|err| Error::Failed(format!("Unable to access interface: {}", err)))
+}
+
+/// Apply changes to a device, failing like wg(8).
+fn save<B: Backend>(backend: &B, device: &Device) -> Result {
+    device
+        .clone()
+        .save_with(backend)
+        .map_err(|err| Error::Failed(format!("Unable to modify interface: {}", err)))
+}
+
+/// Read a base64-encoded key from a file. An empty file designates the zero key, which removes
+/// the key from the device.
+fn read_key_file(path: &str) -> std::result::Result<Key, Error> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| Error::Failed(format!("{}: {}", path, err)))?;
+
+    match contents.trim() {
+        "" => Ok(Key::zero()),
+
+        contents => Key::from_base64(contents).map_err(|_| {
+            Error::Failed(format!("{}: Key is not the correct length or format", path))
+        }),
+    }
+}
+
+/// Format a key, or `(none)`.
+fn key_or_none(key: Option<&Key>) -> String {
+    key.map(Key::to_base64)
+        .unwrap_or_else(|| String::from("(none)"))
+}
+
+/// Format the endpoint of a peer, or `(none)`.
+fn endpoint(peer: &Peer) -> String {
+    match peer.endpoint() {
+        Some(&(addr, port)) => std::net::SocketAddr::new(addr, port).to_string(),
+        None => String::from("(none)"),
+    }
+}
+
+/// Format the allowed IPs of a peer with a separator, or `(none)`.
+fn allowed_ips(peer: &Peer, separator: &str) -> String {
+    if peer.allowed_ips().is_empty() {
+        return String::from("(none)");
+    }
+
+    peer.allowed_ips()
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Get the time of the latest handshake with a peer in seconds since the epoch, 0 if none.
+fn latest_handshake(peer: &Peer) -> u64 {
+    peer.last_handshake()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Format the persistent keepalive interval of a peer, or `off`.
+fn keepalive(peer: &Peer) -> String {
+    match peer.persistent_keepalive() {
+        Some(0) | None => String::from("off"),
+        Some(interval) => interval.to_string(),
+    }
+}
+
+/// Format the firewall mark of a device, or `off`.
+fn fwmark(device: &Device) -> String {
+    match device.fwmark() {
+        Some(0) | None => String::from("off"),
+        Some(fwmark) => format!("{:#x}", fwmark),
+    }
+}
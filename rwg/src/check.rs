@@ -0,0 +1,364 @@
+//! Consistency checks of the configurations of a whole network.
+//!
+//! A `Checker` is given the devices of the nodes of a network, read from configuration files or
+//! from the kernel, and finds the mismatches between them that prevent tunnels from coming up.
+//! Nodes are identified by the public keys of their devices, so devices without a private key
+//! are only checked on their own.
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use crate::config::{self, Config};
+use crate::device::Device;
+use crate::key::Key;
+use crate::link::Address;
+use crate::peer::{AllowedIp, Peer};
+
+/// How serious a problem is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The tunnel may work, but is fragile.
+    Warning,
+    /// The tunnel cannot work as configured.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found on a node. Other nodes are given by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The node lists the peer, which does not list the node.
+    AsymmetricPeering { peer: String },
+    /// The node lists the peer with a key that is not the one of the peer, such as an old one.
+    /// The peer is recognized by its name or its address.
+    KeyMismatch { peer: String, key: Key },
+    /// The node lists a key belonging to none of the nodes.
+    UnknownKey { key: Key },
+    /// An allowed IP of the second peer overlaps one of the first: only one of them gets it.
+    OverlappingAllowedIps {
+        first: Key,
+        second: Key,
+        ip: AllowedIp,
+    },
+    /// The port of the endpoint of the peer is not the port the peer listens on.
+    EndpointPortMismatch {
+        peer: String,
+        port: u16,
+        listen_port: Option<u16>,
+    },
+    /// Neither the node nor the peer has an endpoint for the other, so no handshake can happen.
+    Unreachable { peer: String },
+    /// The peer cannot reach the node, which does not send keepalives to keep its NAT mapping
+    /// open: the peer can only answer traffic sent recently by the node.
+    MissingKeepalive { peer: String },
+}
+
+/// A problem found on a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    node: String,
+    problem: Problem,
+}
+
+impl Diagnostic {
+    /// Get the name of the node.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Get the problem.
+    pub fn problem(&self) -> &Problem {
+        &self.problem
+    }
+
+    /// Get how serious the problem is.
+    pub fn severity(&self) -> Severity {
+        match self.problem {
+            Problem::UnknownKey { .. } | Problem::MissingKeepalive { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let node = &self.node;
+
+        write!(f, "{}: {}: ", self.severity(), node)?;
+
+        match &self.problem {
+            Problem::AsymmetricPeering { peer } => {
+                write!(f, "peer {} does not list {} as a peer", peer, node)
+            }
+
+            Problem::KeyMismatch { peer, key } => {
+                write!(f, "peer {} listed with the wrong public key {}", peer, key)
+            }
+
+            Problem::UnknownKey { key } => write!(f, "unknown peer {}", key),
+
+            Problem::OverlappingAllowedIps { first, second, ip } => write!(
+                f,
+                "allowed IP {} of peer {} overlaps those of peer {}",
+                ip, second, first
+            ),
+
+            Problem::EndpointPortMismatch {
+                peer,
+                port,
+                listen_port: Some(listen_port),
+            } => write!(
+                f,
+                "endpoint port {} of peer {} is not its listen port {}",
+                port, peer, listen_port
+            ),
+
+            Problem::EndpointPortMismatch { peer, port, .. } => write!(
+                f,
+                "endpoint port {} of peer {}, which has no fixed listen port",
+                port, peer
+            ),
+
+            Problem::Unreachable { peer } => {
+                write!(
+                    f,
+                    "neither {} nor {} has an endpoint for the other",
+                    node, peer
+                )
+            }
+
+            Problem::MissingKeepalive { peer } => {
+                write!(
+                    f,
+                    "no persistent keepalive to {}, which cannot reach {}",
+                    peer, node
+                )
+            }
+        }
+    }
+}
+
+/// A node of the network being checked.
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    name: String,
+    device: Device,
+    public_key: Option<Key>,
+    addresses: Vec<Address>,
+}
+
+impl Node {
+    /// Get the peer of this node with the given public key.
+    fn peer(&self, key: &Key) -> Option<&Peer> {
+        self.device
+            .peers()
+            .iter()
+            .find(|peer| peer.public_key() == Some(key))
+    }
+}
+
+/// Checks the consistency of the configurations of the nodes of a network.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checker {
+    nodes: Vec<Node>,
+}
+
+impl Checker {
+    /// Create a checker without nodes.
+    pub fn new() -> Checker {
+        Checker::default()
+    }
+
+    /// Add the device of a node, such as one read from the kernel of a machine.
+    pub fn add_device<S: Into<String>>(&mut self, name: S, device: Device) {
+        self.nodes.push(Node {
+            name: name.into(),
+            public_key: device.public_key(),
+            device: device,
+            addresses: Vec::new(),
+        });
+    }
+
+    /// Add the wg-quick configuration of a node. Its addresses help recognizing the peers listed
+    /// with a wrong key.
+    pub fn add_config<S: Into<String>>(&mut self, name: S, config: &Config) {
+        self.add_device(name, config.device().clone());
+
+        if let Some(node) = self.nodes.last_mut() {
+            node.addresses = config.addresses().to_vec();
+        }
+    }
+
+    /// Add a node from its configuration file, named after the file, `wg0` for `wg0.conf`.
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let config = config::read(&name, BufReader::new(File::open(path)?))?;
+        self.add_config(name, &config);
+
+        Ok(())
+    }
+
+    /// Check the nodes, giving the problems of each node in the order the nodes were added.
+    pub fn check(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut report = |problem| {
+                diagnostics.push(Diagnostic {
+                    node: node.name.clone(),
+                    problem: problem,
+                })
+            };
+
+            let peers = node.device.peers();
+
+            for (j, peer) in peers.iter().enumerate() {
+                let key = match peer.public_key() {
+                    Some(key) => key,
+                    None => continue,
+                };
+
+                for ip in peer.allowed_ips() {
+                    let first = peers[..j]
+                        .iter()
+                        .find(|other| other.allowed_ips().iter().any(|other| other.overlaps(ip)));
+
+                    if let Some(first) = first.and_then(Peer::public_key) {
+                        report(Problem::OverlappingAllowedIps {
+                            first: first.clone(),
+                            second: key.clone(),
+                            ip: ip.clone(),
+                        });
+                    }
+                }
+
+                let position = match self.position(key) {
+                    Some(position) => position,
+                    None => {
+                        report(match self.recognize(i, peer) {
+                            Some(other) => Problem::KeyMismatch {
+                                peer: other.name.clone(),
+                                key: key.clone(),
+                            },
+                            None => Problem::UnknownKey { key: key.clone() },
+                        });
+
+                        continue;
+                    }
+                };
+
+                let other = &self.nodes[position];
+
+                if let Some(&(_, port)) = peer.endpoint() {
+                    let listen_port = other.device.listen_port();
+
+                    if listen_port != Some(port) {
+                        report(Problem::EndpointPortMismatch {
+                            peer: other.name.clone(),
+                            port: port,
+                            listen_port: listen_port,
+                        });
+                    }
+                }
+
+                let back = match node.public_key.as_ref().and_then(|key| other.peer(key)) {
+                    Some(back) => back,
+                    None => {
+                        report(Problem::AsymmetricPeering {
+                            peer: other.name.clone(),
+                        });
+
+                        continue;
+                    }
+                };
+
+                match (peer.endpoint(), back.endpoint()) {
+                    // Reported once per pair, by the node added first.
+                    (None, None) if position > i => {
+                        report(Problem::Unreachable {
+                            peer: other.name.clone(),
+                        });
+                    }
+
+                    (Some(_), None) if peer.persistent_keepalive().unwrap_or(0) == 0 => {
+                        report(Problem::MissingKeepalive {
+                            peer: other.name.clone(),
+                        });
+                    }
+
+                    _ => {}
+                }
+            }
+        }
+
+        diagnostics
+    }
+
+    /// Get the position of the node with a public key.
+    fn position(&self, key: &Key) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.public_key.as_ref() == Some(key))
+    }
+
+    /// Find the node a peer of the node at `position` stands for, among the nodes it does not
+    /// list yet: the node named like the peer, or else the only node whose address is in the most
+    /// specific allowed IP of the peer.
+    fn recognize(&self, position: usize, peer: &Peer) -> Option<&Node> {
+        let node = &self.nodes[position];
+        let others = self.nodes.iter().enumerate().filter(|&(i, other)| {
+            let listed = other.public_key.as_ref().and_then(|key| node.peer(key));
+            i != position && listed.is_none()
+        });
+
+        let name = peer.metadata().and_then(|metadata| metadata.name());
+
+        if let Some((_, other)) = others
+            .clone()
+            .find(|(_, other)| Some(other.name.as_str()) == name)
+        {
+            return Some(other);
+        }
+
+        let mut candidates = others
+            .filter_map(|(_, other)| {
+                let mask = other
+                    .addresses
+                    .iter()
+                    .flat_map(|address| {
+                        peer.allowed_ips()
+                            .iter()
+                            .filter(move |ip| ip.contains(address.addr()))
+                    })
+                    .map(AllowedIp::mask)
+                    .max()?;
+
+                Some((mask, other))
+            })
+            .collect::<Vec<_>>();
+
+        candidates.sort_by_key(|&(mask, _)| Reverse(mask));
+
+        match candidates.as_slice() {
+            [(mask, other), (next, _), ..] if mask > next => Some(*other),
+            [(_, other)] => Some(*other),
+            _ => None,
+        }
+    }
+}
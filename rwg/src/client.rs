@@ -0,0 +1,190 @@
+//! Generation of client configurations mirroring the peers of a server.
+//!
+//! A `Generator` turns a peer of a server device into the wg-quick configuration of the client at
+//! the other end: the client gets the host addresses among the allowed IPs of the peer, and the
+//! server as its only peer, with the same preshared key.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::config::Config;
+use crate::device::Device;
+use crate::key::Key;
+use crate::link::Address;
+use crate::peer::{AllowedIp, Endpoint, Peer};
+
+/// What the client routes through the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tunnel {
+    /// All the traffic, IPv4 and IPv6.
+    Full,
+    /// Only the traffic to the given networks.
+    Split(Vec<AllowedIp>),
+}
+
+impl Tunnel {
+    /// Get the allowed IPs of the server on the client.
+    pub fn allowed_ips(&self) -> Vec<AllowedIp> {
+        match self {
+            Tunnel::Full => vec![
+                AllowedIp::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                AllowedIp::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            ],
+            Tunnel::Split(ips) => ips.clone(),
+        }
+    }
+}
+
+/// Generates client configurations for the peers of a server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generator {
+    endpoint: Endpoint,
+    tunnel: Tunnel,
+    dns: Vec<String>,
+    persistent_keepalive: Option<u16>,
+}
+
+impl Generator {
+    /// Create a generator for a server reachable at `endpoint`, routing all the traffic of the
+    /// clients through it.
+    pub fn new(endpoint: Endpoint) -> Generator {
+        Generator {
+            endpoint: endpoint,
+            tunnel: Tunnel::Full,
+            dns: Vec::new(),
+            persistent_keepalive: None,
+        }
+    }
+
+    /// Choose what the clients route through the server.
+    pub fn set_tunnel(&mut self, tunnel: Tunnel) {
+        self.tunnel = tunnel;
+    }
+
+    /// Add a DNS server address or search domain to the clients.
+    pub fn add_dns<S: Into<String>>(&mut self, dns: S) {
+        self.dns.push(dns.into());
+    }
+
+    /// Set the interval in seconds at which the clients send keepalive packets to the server,
+    /// which keeps NAT mappings open. An interval of zero disables them.
+    pub fn set_persistent_keepalive(&mut self, interval: u16) {
+        self.persistent_keepalive = match interval {
+            0 => None,
+            interval => Some(interval),
+        };
+    }
+
+    /// Get the endpoint of the server.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Get what the clients route through the server.
+    pub fn tunnel(&self) -> &Tunnel {
+        &self.tunnel
+    }
+
+    /// Get the DNS server addresses and search domains of the clients.
+    pub fn dns(&self) -> &[String] {
+        &self.dns
+    }
+
+    /// Get the keepalive interval of the clients, if enabled.
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+
+    /// Generate the configuration of the client of a peer of `server`, given the private key of
+    /// the client. The device of the configuration is named after the server device.
+    pub fn generate(
+        &self,
+        server: &Device,
+        peer: &Peer,
+        private_key: &Key,
+    ) -> Result<Config, Error> {
+        let server_key = server.public_key().ok_or(Error::MissingServerKey)?;
+        let peer_key = peer.public_key().ok_or(Error::MissingPeerKey)?;
+
+        if private_key.derive_public() != *peer_key {
+            return Err(Error::KeyMismatch);
+        }
+
+        let addresses = peer
+            .allowed_ips()
+            .iter()
+            .filter(|ip| ip.is_host())
+            .map(|ip| Address::new(*ip.addr(), ip.mask()))
+            .collect::<Vec<_>>();
+
+        if addresses.is_empty() {
+            return Err(Error::NoAddress);
+        }
+
+        let mut client = Peer::new(server_key, Some(self.endpoint));
+
+        for ip in self.tunnel.allowed_ips() {
+            client.add_allowed_ip(ip);
+        }
+
+        if let Some(key) = peer.preshared_key() {
+            client.set_preshared_key(key.clone());
+        }
+
+        if let Some(interval) = self.persistent_keepalive {
+            client.set_persistent_keepalive(interval);
+        }
+
+        let mut device = Device::new(server.name());
+        device.set_private_key(private_key.clone());
+        device.add_peer(client);
+
+        let mut config = Config::new(device);
+
+        for address in addresses {
+            config.add_address(address);
+        }
+
+        for dns in &self.dns {
+            config.add_dns(dns.as_str());
+        }
+
+        Ok(config)
+    }
+}
+
+/// Errors that can happen when generating a client configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The server device has no private key, so its public key is unknown.
+    MissingServerKey,
+    /// The peer has no public key.
+    MissingPeerKey,
+    /// The private key of the client does not match the public key of the peer.
+    KeyMismatch,
+    /// None of the allowed IPs of the peer is a single address to give to the client.
+    NoAddress,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingServerKey => write!(f, "server without a private key"),
+            Error::MissingPeerKey => write!(f, "peer without a public key"),
+            Error::KeyMismatch => {
+                write!(f, "private key does not match the public key of the peer")
+            }
+            Error::NoAddress => write!(f, "no single address among the allowed IPs of the peer"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
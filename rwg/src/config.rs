@@ -0,0 +1,667 @@
+//! Configuration files, in the formats of wg(8) and wg-quick(8).
+//!
+//! A file is made of an `[Interface]` section followed by `[Peer]` sections of `Key = Value`
+//! lines. Keys are case-insensitive and `#` starts a comment. wg-quick adds keys to the interface
+//! section describing how to set up the network interface of the device.
+//!
+//! Comment lines directly above a peer section header are kept as the metadata of the peer, lines
+//! such as `# Name = laptop` setting its known fields, and are written back there. Inside a peer
+//! section, only comments setting known fields are metadata. Other comments, such as commented
+//! out settings, are ignored.
+
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+use crate::device::Device;
+use crate::key::Key;
+use crate::link::Address;
+use crate::peer::Peer;
+use crate::route::MAIN_TABLE;
+use crate::uapi;
+
+/// Keys understood by wg(8), in the interface section or in peer sections.
+const KEYS: &[&str] = &[
+    "privatekey",
+    "listenport",
+    "fwmark",
+    "publickey",
+    "presharedkey",
+    "allowedips",
+    "endpoint",
+    "persistentkeepalive",
+];
+
+/// Keys of the interface section that are only understood by wg-quick.
+const QUICK_KEYS: &[&str] = &[
+    "address",
+    "dns",
+    "mtu",
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+/// Routing table in which wg-quick installs the routes to the allowed IPs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    /// Routes go to the main table, except default routes which use full-tunnel policy routing.
+    #[default]
+    Auto,
+    /// No routes are installed.
+    Off,
+    /// All the routes go to the given table.
+    Id(u32),
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Table::Auto => write!(f, "auto"),
+            Table::Off => write!(f, "off"),
+            Table::Id(MAIN_TABLE) => write!(f, "main"),
+            Table::Id(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl FromStr for Table {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Table, ()> {
+        match s {
+            "auto" => Ok(Table::Auto),
+            "off" => Ok(Table::Off),
+            "main" => Ok(Table::Id(MAIN_TABLE)),
+            "local" => Ok(Table::Id(255)),
+            "default" => Ok(Table::Id(253)),
+            id => id.parse().map(Table::Id).map_err(|_| ()),
+        }
+    }
+}
+
+/// Commands run by wg-quick around bringing a device up and down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+}
+
+/// A wg-quick configuration: a device along with the setup of its network interface.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    device: Device,
+    addresses: Vec<Address>,
+    dns: Vec<String>,
+    mtu: Option<u32>,
+    table: Table,
+    hooks: Vec<(Hook, String)>,
+    save_config: bool,
+}
+
+impl Config {
+    /// Create a configuration for a device, with nothing to set up on its network interface.
+    pub fn new(device: Device) -> Config {
+        Config {
+            device: device,
+            addresses: Vec::new(),
+            dns: Vec::new(),
+            mtu: None,
+            table: Table::Auto,
+            hooks: Vec::new(),
+            save_config: false,
+        }
+    }
+
+    /// Assign an address to the network interface.
+    pub fn add_address(&mut self, address: Address) {
+        self.addresses.push(address);
+    }
+
+    /// Add a DNS server address or search domain.
+    pub fn add_dns<S: Into<String>>(&mut self, dns: S) {
+        self.dns.push(dns.into());
+    }
+
+    /// Set the MTU of the network interface.
+    pub fn set_mtu(&mut self, mtu: u32) {
+        self.mtu = Some(mtu);
+    }
+
+    /// Set the routing table of the routes to the allowed IPs.
+    pub fn set_table(&mut self, table: Table) {
+        self.table = table;
+    }
+
+    /// Add a command to run at the specified stage.
+    pub fn add_hook<S: Into<String>>(&mut self, hook: Hook, command: S) {
+        self.hooks.push((hook, command.into()));
+    }
+
+    /// Choose whether the configuration is saved back to its file when the device goes down.
+    pub fn set_save_config(&mut self, save: bool) {
+        self.save_config = save;
+    }
+
+    /// Get the device.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Get a mutable reference to the device.
+    pub fn device_mut(&mut self) -> &mut Device {
+        &mut self.device
+    }
+
+    /// Get the device, dropping the setup of its network interface.
+    pub fn into_device(self) -> Device {
+        self.device
+    }
+
+    /// Get the addresses of the network interface.
+    pub fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+
+    /// Get the DNS server addresses and search domains.
+    pub fn dns(&self) -> &[String] {
+        &self.dns
+    }
+
+    /// Get the MTU of the network interface, if set.
+    pub fn mtu(&self) -> Option<u32> {
+        self.mtu
+    }
+
+    /// Get the routing table of the routes to the allowed IPs.
+    pub fn table(&self) -> Table {
+        self.table
+    }
+
+    /// Get the commands to run at the specified stage, in order.
+    pub fn hooks(&self, hook: Hook) -> Vec<&str> {
+        self.hooks
+            .iter()
+            .filter(|(h, _)| *h == hook)
+            .map(|(_, command)| command.as_str())
+            .collect()
+    }
+
+    /// Whether the configuration is saved back to its file when the device goes down.
+    pub fn save_config(&self) -> bool {
+        self.save_config
+    }
+}
+
+/// Read a wg-quick configuration for the device of the given name. Endpoints given as host names
+/// are resolved.
+pub fn read<R: BufRead>(name: &str, r: R) -> Result<Config, Error> {
+    parse(name, r, true)
+}
+
+/// Read a wg(8) configuration into a `Device` of the given name. Saving the device replaces its
+/// whole configuration, like `wg setconf`. wg-quick keys are rejected.
+pub fn read_device<R: BufRead>(name: &str, r: R) -> Result<Device, Error> {
+    parse(name, r, false).map(Config::into_device)
+}
+
+/// Write a wg-quick configuration.
+pub fn write<W: Write>(config: &Config, mut w: W) -> io::Result<()> {
+    let mut buf = String::from("[Interface]\n");
+
+    if !config.addresses.is_empty() {
+        let addresses = config
+            .addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect::<Vec<_>>();
+
+        buf.push_str(&format!("Address = {}\n", addresses.join(", ")));
+    }
+
+    if !config.dns.is_empty() {
+        buf.push_str(&format!("DNS = {}\n", config.dns.join(", ")));
+    }
+
+    if let Some(mtu) = config.mtu {
+        buf.push_str(&format!("MTU = {}\n", mtu));
+    }
+
+    if config.table != Table::Auto {
+        buf.push_str(&format!("Table = {}\n", config.table));
+    }
+
+    for (hook, command) in &config.hooks {
+        let key = match hook {
+            Hook::PreUp => "PreUp",
+            Hook::PostUp => "PostUp",
+            Hook::PreDown => "PreDown",
+            Hook::PostDown => "PostDown",
+        };
+
+        buf.push_str(&format!("{} = {}\n", key, command));
+    }
+
+    if config.save_config {
+        buf.push_str("SaveConfig = true\n");
+    }
+
+    write_interface(&mut buf, &config.device);
+    write_peers(&mut buf, &config.device);
+
+    w.write_all(buf.as_bytes())?;
+    w.flush()
+}
+
+/// Write the configuration of a device in the format of `wg showconf`.
+pub fn write_device<W: Write>(device: &Device, mut w: W) -> io::Result<()> {
+    let mut buf = String::from("[Interface]\n");
+
+    write_interface(&mut buf, device);
+    write_peers(&mut buf, device);
+
+    w.write_all(buf.as_bytes())?;
+    w.flush()
+}
+
+/// Copy a wg-quick configuration without the wg-quick keys, so that it can be used by wg(8), like
+/// `wg-quick strip`. Other lines, comments included, are kept untouched.
+pub fn strip<R: BufRead, W: Write>(r: R, mut w: W) -> Result<(), Error> {
+    let mut interface = false;
+
+    for line in r.lines() {
+        let line = line?;
+        let stripped = line.split('#').next().unwrap_or("").trim();
+
+        if stripped.starts_with('[') {
+            interface = stripped.eq_ignore_ascii_case("[Interface]");
+        } else if interface {
+            let key = stripped.split('=').next().unwrap_or("").trim();
+
+            if QUICK_KEYS.contains(&key.to_lowercase().as_str()) {
+                continue;
+            }
+        }
+
+        writeln!(w, "{}", line)?;
+    }
+
+    w.flush()?;
+
+    Ok(())
+}
+
+/// Write the keys of the interface section understood by wg(8).
+fn write_interface(buf: &mut String, device: &Device) {
+    if let Some(port) = device.listen_port() {
+        buf.push_str(&format!("ListenPort = {}\n", port));
+    }
+
+    match device.fwmark() {
+        Some(0) => buf.push_str("FwMark = off\n"),
+        Some(fwmark) => buf.push_str(&format!("FwMark = {:#x}\n", fwmark)),
+        None => {}
+    }
+
+    if let Some(key) = device.private_key() {
+        buf.push_str(&format!("PrivateKey = {}\n", key.to_base64()));
+    }
+}
+
+/// Write a section for every peer of a device.
+fn write_peers(buf: &mut String, device: &Device) {
+    for peer in device.peers() {
+        buf.push('\n');
+
+        if let Some(metadata) = peer.metadata() {
+            for line in metadata.lines().iter().chain(metadata.comments()) {
+                buf.push_str(&format!("# {}\n", line));
+            }
+        }
+
+        buf.push_str("[Peer]\n");
+
+        if let Some(key) = peer.public_key() {
+            buf.push_str(&format!("PublicKey = {}\n", key.to_base64()));
+        }
+
+        if let Some(key) = peer.preshared_key() {
+            buf.push_str(&format!("PresharedKey = {}\n", key.to_base64()));
+        }
+
+        if !peer.allowed_ips().is_empty() {
+            let allowed_ips = peer
+                .allowed_ips()
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>();
+
+            buf.push_str(&format!("AllowedIPs = {}\n", allowed_ips.join(", ")));
+        }
+
+        if let Some(&(addr, port)) = peer.endpoint() {
+            buf.push_str(&format!("Endpoint = {}\n", SocketAddr::new(addr, port)));
+        }
+
+        match peer.persistent_keepalive() {
+            Some(0) => buf.push_str("PersistentKeepalive = off\n"),
+            Some(interval) => buf.push_str(&format!("PersistentKeepalive = {}\n", interval)),
+            None => {}
+        }
+    }
+}
+
+/// The section a line belongs to.
+enum Section {
+    None,
+    Interface,
+    /// A peer section starting at the given line, with the public key once it is read.
+    Peer(usize, Option<Key>, Box<Peer>),
+}
+
+/// Parse a configuration, accepting the wg-quick keys if `quick` is set.
+fn parse<R: BufRead>(name: &str, r: R, quick: bool) -> Result<Config, Error> {
+    let mut config = Config::new(Device::new(name));
+    let mut section = Section::None;
+    // Whole-line comments directly above the current line, which belong to the peer of the next
+    // section header if there is one.
+    let mut comments = Vec::new();
+
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let number = i + 1;
+
+        if let Some(comment) = line.trim().strip_prefix('#') {
+            if is_setting(comment.trim()) {
+                read_metadata(&mut section, &mut comments);
+            } else {
+                comments.push(comment.trim().to_owned());
+            }
+
+            continue;
+        }
+
+        let line = line.split('#').next().unwrap_or("").trim();
+
+        if line.is_empty() {
+            read_metadata(&mut section, &mut comments);
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let mut next = match line[1..line.len() - 1].trim().to_lowercase().as_str() {
+                "interface" => Section::Interface,
+                "peer" => Section::Peer(number, None, Box::new(Peer::new(Key::zero(), None))),
+                _ => return Err(Error::UnknownSection(number, line.to_owned())),
+            };
+
+            if let Section::Peer(..) = next {
+                read_comments(&mut next, &mut comments);
+            } else {
+                read_metadata(&mut section, &mut comments);
+            }
+
+            finish_peer(&mut config, section)?;
+            section = next;
+
+            continue;
+        }
+
+        read_metadata(&mut section, &mut comments);
+
+        let mut parts = line.splitn(2, '=');
+
+        let (key, raw) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => return Err(Error::Malformed(number)),
+        };
+
+        let lower = key.to_lowercase();
+        let value = raw.split_whitespace().collect::<String>();
+        let invalid = || Error::InvalidValue(number, key.to_owned(), raw.to_owned());
+
+        match (&mut section, lower.as_str()) {
+            (Section::Interface, "privatekey") => {
+                let key = Key::from_base64(&value).map_err(|_| invalid())?;
+                config.device.set_private_key(key);
+            }
+
+            (Section::Interface, "listenport") => {
+                config
+                    .device
+                    .set_listen_port(value.parse().map_err(|_| invalid())?);
+            }
+
+            (Section::Interface, "fwmark") => {
+                let fwmark = parse_fwmark(&value).ok_or_else(invalid)?;
+                config.device.set_fwmark(fwmark);
+            }
+
+            (Section::Interface, "address") if quick => {
+                for address in value.split(',').filter(|s| !s.is_empty()) {
+                    config.add_address(address.parse().map_err(|_| invalid())?);
+                }
+            }
+
+            (Section::Interface, "dns") if quick => {
+                for dns in value.split(',').filter(|s| !s.is_empty()) {
+                    config.add_dns(dns);
+                }
+            }
+
+            (Section::Interface, "mtu") if quick => {
+                config.set_mtu(value.parse().map_err(|_| invalid())?);
+            }
+
+            (Section::Interface, "table") if quick => {
+                config.set_table(value.parse().map_err(|_| invalid())?);
+            }
+
+            (Section::Interface, "preup") if quick => config.add_hook(Hook::PreUp, raw),
+            (Section::Interface, "postup") if quick => config.add_hook(Hook::PostUp, raw),
+            (Section::Interface, "predown") if quick => config.add_hook(Hook::PreDown, raw),
+            (Section::Interface, "postdown") if quick => config.add_hook(Hook::PostDown, raw),
+
+            (Section::Interface, "saveconfig") if quick => {
+                config.set_save_config(parse_bool(&value).ok_or_else(invalid)?);
+            }
+
+            (Section::Peer(_, public_key, _), "publickey") => {
+                *public_key = Some(Key::from_base64(&value).map_err(|_| invalid())?);
+            }
+
+            (Section::Peer(_, _, peer), "presharedkey") => {
+                peer.set_preshared_key(Key::from_base64(&value).map_err(|_| invalid())?);
+            }
+
+            (Section::Peer(_, _, peer), "allowedips") => {
+                for ip in value.split(',').filter(|s| !s.is_empty()) {
+                    peer.add_allowed_ip(ip.parse().map_err(|_| invalid())?);
+                }
+            }
+
+            (Section::Peer(_, _, peer), "endpoint") => {
+                let addr = resolve_endpoint(&value).ok_or_else(invalid)?;
+                peer.set_endpoint((addr.ip(), addr.port()));
+            }
+
+            (Section::Peer(_, _, peer), "persistentkeepalive") => {
+                let interval = match value.as_str() {
+                    "off" => 0,
+                    value => value.parse().map_err(|_| invalid())?,
+                };
+
+                peer.set_persistent_keepalive(interval);
+            }
+
+            (_, other) if KEYS.contains(&other) => {
+                return Err(Error::UnexpectedKey(number, key.to_owned()));
+            }
+
+            (_, other) if quick && QUICK_KEYS.contains(&other) => {
+                return Err(Error::UnexpectedKey(number, key.to_owned()));
+            }
+
+            _ => return Err(Error::UnknownKey(number, key.to_owned())),
+        }
+    }
+
+    read_metadata(&mut section, &mut comments);
+    finish_peer(&mut config, section)?;
+
+    Ok(config)
+}
+
+/// Add the peer of a finished section to the device.
+fn finish_peer(config: &mut Config, section: Section) -> Result<(), Error> {
+    if let Section::Peer(number, public_key, mut peer) = section {
+        let public_key = public_key.ok_or(Error::MissingPublicKey(number))?;
+
+        peer.set_public_key(public_key);
+        config.device.add_peer(*peer);
+    }
+
+    Ok(())
+}
+
+/// Add the comment lines read directly above the header of a peer section to the metadata of the
+/// peer.
+fn read_comments(section: &mut Section, comments: &mut Vec<String>) {
+    if let Section::Peer(_, _, peer) = section {
+        for comment in comments.iter().filter(|comment| !comment.is_empty()) {
+            if !read_field(peer, comment) {
+                peer.metadata_mut().add_comment(comment.as_str());
+            }
+        }
+    }
+
+    comments.clear();
+}
+
+/// Set the known fields of the metadata of the peer of a section from the comment lines read so
+/// far. Other comments are dropped, as are all of them outside of peer sections.
+fn read_metadata(section: &mut Section, comments: &mut Vec<String>) {
+    if let Section::Peer(_, _, peer) = section {
+        for comment in comments.iter() {
+            read_field(peer, comment);
+        }
+    }
+
+    comments.clear();
+}
+
+/// Set a field of the metadata of a peer from a `Key = Value` comment line, returning whether the
+/// key is known.
+fn read_field(peer: &mut Peer, comment: &str) -> bool {
+    let mut parts = comment.splitn(2, '=');
+
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) => peer.metadata_mut().apply(key.trim(), value.trim()),
+        _ => false,
+    }
+}
+
+/// Whether a comment line is a commented out section header or setting, such as the lines of a
+/// disabled peer.
+fn is_setting(comment: &str) -> bool {
+    if comment.starts_with('[') {
+        return true;
+    }
+
+    let mut parts = comment.splitn(2, '=');
+
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(_)) => {
+            let key = key.trim().to_lowercase();
+            KEYS.contains(&key.as_str()) || QUICK_KEYS.contains(&key.as_str())
+        }
+
+        _ => false,
+    }
+}
+
+/// Parse a firewall mark, in decimal or hexadecimal, or `off`.
+fn parse_fwmark(value: &str) -> Option<u32> {
+    if value == "off" {
+        Some(0)
+    } else if value.starts_with("0x") || value.starts_with("0X") {
+        u32::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// Parse a boolean written as `true` or `false`.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse an endpoint, resolving host names.
+fn resolve_endpoint(value: &str) -> Option<SocketAddr> {
+    uapi::parse_endpoint(value).or_else(|| value.to_socket_addrs().ok()?.next())
+}
+
+/// Errors that can happen when reading a configuration file. Lines are numbered from 1.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error occured while reading the file.
+    Io(io::Error),
+    /// A line was neither a section header nor of the form `Key = Value`.
+    Malformed(usize),
+    /// A section other than `[Interface]` and `[Peer]`.
+    UnknownSection(usize, String),
+    /// A key that is not part of the format.
+    UnknownKey(usize, String),
+    /// A known key outside of the section it belongs to.
+    UnexpectedKey(usize, String),
+    /// A value that could not be parsed for the given key.
+    InvalidValue(usize, String, String),
+    /// The peer section starting at the given line has no public key.
+    MissingPublicKey(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Malformed(line) => write!(f, "line {}: malformed line", line),
+            Error::UnknownSection(line, section) => {
+                write!(f, "line {}: unknown section: {}", line, section)
+            }
+            Error::UnknownKey(line, key) => write!(f, "line {}: unknown key: {}", line, key),
+            Error::UnexpectedKey(line, key) => write!(f, "line {}: unexpected key: {}", line, key),
+            Error::InvalidValue(line, key, value) => {
+                write!(f, "line {}: invalid value for {}: {:?}", line, key, value)
+            }
+            Error::MissingPublicKey(line) => write!(f, "line {}: peer without a public key", line),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -1,14 +1,12 @@
 //! WireGuard device management.
 
-use std::ffi::{CStr, CString};
 use std::io;
-use std::mem;
-use std::ptr;
-
-use libwg_sys as sys;
 
+#[cfg(feature = "tokio")]
+use crate::backend::{AsyncBackend, Netlink, Userspace};
+use crate::backend::{Backend, System};
 use crate::key::Key;
-use crate::peer::{self, Peer};
+use crate::peer::Peer;
 
 /// A WireGuard device / interface.
 #[derive(Debug, Clone, PartialEq)]
@@ -16,30 +14,38 @@ pub struct Device {
     name: String,
     private_key: Option<Key>,
     listen_port: Option<u16>,
+    fwmark: Option<u32>,
     peers: Vec<Peer>,
+    replace_peers: bool,
 }
 
 impl Device {
-    /// Open all WireGuard devices on this machine.
-    pub fn all() -> Result<Vec<Device>, io::Error> {
-        let names = unsafe {
-            let mut names = Vec::new();
-            let mut pointer = sys::wg_list_device_names();
-
-            while *pointer != 0 as i8 {
-                let name = CStr::from_ptr(pointer);
-
-                pointer = pointer.add(name.to_bytes().len() + 1);
-                names.push(name);
-            }
+    /// Construct the description of a device without touching the system. The device can later be
+    /// created or updated with `save`.
+    pub fn new<S: Into<String>>(name: S) -> Device {
+        Device {
+            name: name.into(),
+            private_key: None,
+            listen_port: None,
+            fwmark: None,
+            peers: Vec::new(),
+            replace_peers: true,
+        }
+    }
 
-            names
-        };
+    /// Open all WireGuard devices on this machine, both kernel devices and userspace devices
+    /// exposing a UAPI socket.
+    pub fn all() -> Result<Vec<Device>, io::Error> {
+        Device::all_with(&System::new())
+    }
 
+    /// Open all the WireGuard devices managed by the specified backend.
+    pub fn all_with<B: Backend + ?Sized>(backend: &B) -> Result<Vec<Device>, io::Error> {
+        let names = backend.list()?;
         let mut devices = Vec::with_capacity(names.len());
 
         for name in names {
-            devices.push(Device::open(name.to_string_lossy())?);
+            devices.push(backend.get(&name)?);
         }
 
         Ok(devices)
@@ -47,137 +53,40 @@ impl Device {
 
     /// Create a new WireGuard device.
     pub fn create<S: Into<String>>(name: S, private_key: Option<Key>) -> Result<Device, io::Error> {
-        let name = CString::new(name.into()).expect("Invalid device name");
-
-        unsafe {
-            if sys::wg_add_device(name.as_ptr()) != 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-
-        Ok(Device {
-            name: name.into_string().unwrap(),
-            private_key: private_key,
-            listen_port: None,
-            peers: Vec::new(),
-        })
+        Device::create_with(&System::new(), name, private_key)
     }
 
-    /// Open an existing WireGuard device.
-    pub fn open<S: Into<String>>(name: S) -> Result<Device, io::Error> {
-        let name = CString::new(name.into()).expect("Invalid device name");
-
-        let handle = unsafe {
-            let mut h: *mut sys::wg_device = mem::zeroed();
+    /// Create a new WireGuard device using the specified backend.
+    pub fn create_with<B: Backend + ?Sized, S: Into<String>>(
+        backend: &B,
+        name: S,
+        private_key: Option<Key>,
+    ) -> Result<Device, io::Error> {
+        let mut device = Device::new(name);
 
-            if sys::wg_get_device(&mut h, name.as_ptr()) != 0 {
-                return Err(io::Error::last_os_error());
-            }
+        backend.create(&device.name)?;
+        device.private_key = private_key;
 
-            h
-        };
-
-        Ok(Device::from_handle(handle))
+        Ok(device)
     }
 
-    /// Create a `Device` object from the C library handle.
-    fn from_handle(h: *mut sys::wg_device) -> Device {
-        let name = unsafe { CStr::from_ptr((*h).name.as_ptr() as *const i8) };
-
-        let private_key = unsafe {
-            if (*h).flags & sys::wg_device_flags_WGDEVICE_HAS_PRIVATE_KEY != 0 {
-                Some(Key::from_bytes((*h).private_key))
-            } else {
-                None
-            }
-        };
-
-        let listen_port = unsafe {
-            let port = (*h).listen_port;
-
-            if port > 0 {
-                Some(port)
-            } else {
-                None
-            }
-        };
-
-        let peers = unsafe {
-            let mut peers = Vec::new();
-            let mut peer = (*h).first_peer;
-
-            while peer != ptr::null_mut() {
-                peers.push(Peer::from_handle(peer));
-                peer = (*peer).next_peer;
-            }
-
-            peers
-        };
+    /// Open an existing WireGuard device. Userspace devices exposing a UAPI socket take
+    /// precedence over kernel devices of the same name.
+    pub fn open<S: Into<String>>(name: S) -> Result<Device, io::Error> {
+        Device::open_with(&System::new(), name)
+    }
 
-        Device {
-            name: String::from(
-                name.to_str()
-                    .expect("Get an invalid interface name from wg"),
-            ),
-            private_key: private_key,
-            listen_port: listen_port,
-            peers: peers,
-        }
+    /// Open an existing WireGuard device using the specified backend.
+    pub fn open_with<B: Backend + ?Sized, S: Into<String>>(
+        backend: &B,
+        name: S,
+    ) -> Result<Device, io::Error> {
+        backend.get(&name.into())
     }
 
-    /// Get the C library handle that corresponds to this device.
-    fn handle(&self) -> Handle {
-        unsafe {
-            let mut h: sys::wg_device = mem::zeroed();
-            let name = CString::new(self.name.clone()).unwrap();
-
-            ptr::copy_nonoverlapping(
-                name.as_ptr(),
-                h.name.as_mut_ptr(),
-                self.name.as_bytes().len(),
-            );
-
-            if let Some(ref key) = self.private_key {
-                h.flags |= sys::wg_device_flags_WGDEVICE_HAS_PRIVATE_KEY;
-
-                h.private_key.copy_from_slice(key.as_bytes());
-                h.public_key.copy_from_slice(key.derive_public().as_bytes());
-            }
-
-            if let Some(listen_port) = self.listen_port {
-                h.flags |= sys::wg_device_flags_WGDEVICE_HAS_LISTEN_PORT;
-                h.listen_port = listen_port;
-            }
-
-            h.flags |= sys::wg_device_flags_WGDEVICE_REPLACE_PEERS;
-
-            let mut peers = self
-                .peers
-                .iter()
-                .map(|peer| peer.handle())
-                .collect::<Vec<_>>();
-
-            if !peers.is_empty() {
-                let peers_len = peers.len();
-                let peers_ptr = peers.as_mut_ptr();
-
-                for (i, peer) in peers.iter_mut().enumerate() {
-                    if i + 1 < peers_len {
-                        peer.handle.next_peer = peers_ptr.add(i + 1) as *mut sys::wg_peer;
-                    } else {
-                        peer.handle.next_peer = ptr::null_mut();
-                    }
-                }
-
-                h.first_peer = peers_ptr as *mut sys::wg_peer;
-                h.last_peer = peers_ptr.add(peers_len - 1) as *mut sys::wg_peer;
-            } else {
-                h.first_peer = ptr::null_mut();
-                h.last_peer = ptr::null_mut();
-            }
-
-            Handle { h: h, peers: peers }
-        }
+    /// Set the private key of this device.
+    pub fn set_private_key(&mut self, key: Key) {
+        self.private_key = Some(key);
     }
 
     /// Set the UDP listening port of this device.
@@ -185,6 +94,23 @@ impl Device {
         self.listen_port = Some(port);
     }
 
+    /// Set the firewall mark applied to outgoing packets. A mark of zero disables it, and
+    /// clears the mark of the device when saved.
+    pub fn set_fwmark(&mut self, fwmark: u32) {
+        self.fwmark = Some(fwmark);
+    }
+
+    /// Forget the firewall mark, as devices without one are read.
+    pub(crate) fn clear_fwmark(&mut self) {
+        self.fwmark = None;
+    }
+
+    /// Choose whether the peers of this device replace all the existing ones when it is saved, or
+    /// are merged with them. Defaults to `true`.
+    pub fn set_replace_peers(&mut self, replace: bool) {
+        self.replace_peers = replace;
+    }
+
     /// Attach a new peer to the device.
     pub fn add_peer(&mut self, peer: Peer) {
         self.peers.push(peer);
@@ -210,6 +136,17 @@ impl Device {
         self.listen_port
     }
 
+    /// Get the firewall mark of this device, if it has been set. Devices read from the kernel
+    /// have no mark rather than a mark of zero.
+    pub fn fwmark(&self) -> Option<u32> {
+        self.fwmark
+    }
+
+    /// Whether the peers of this device replace the existing ones when it is saved.
+    pub fn replace_peers(&self) -> bool {
+        self.replace_peers
+    }
+
     /// Get a read-only reference to the list of peers associated to this device.
     pub fn peers(&self) -> &[Peer] {
         self.peers.as_ref()
@@ -220,23 +157,115 @@ impl Device {
         &mut self.peers
     }
 
-    /// Save the changes made to the device and push them to the kernel. Consumes `self`.
+    /// Save the changes made to the device and push them to the kernel, or to the userspace
+    /// implementation if the device exposes a UAPI socket. Consumes `self`.
     pub fn save(self) -> io::Result<()> {
-        let mut handle = self.handle();
+        self.save_with(&System::new())
+    }
+
+    /// Save the changes made to the device using the specified backend. Consumes `self`.
+    pub fn save_with<B: Backend + ?Sized>(self, backend: &B) -> io::Result<()> {
+        backend.set(&self)
+    }
+
+    /// Delete the device from the system. Consumes `self`.
+    pub fn delete(self) -> io::Result<()> {
+        self.delete_with(&System::new())
+    }
 
-        unsafe {
-            if sys::wg_set_device(&mut handle.h) != 0 {
-                return Err(io::Error::last_os_error());
-            }
+    /// Delete the device using the specified backend. Consumes `self`.
+    pub fn delete_with<B: Backend + ?Sized>(self, backend: &B) -> io::Result<()> {
+        backend.delete(&self.name)
+    }
+
+    /// Open all WireGuard devices on this machine without blocking. Kernel devices are accessed
+    /// over netlink, whether or not the `libwg` feature is enabled.
+    #[cfg(feature = "tokio")]
+    pub async fn all_async() -> io::Result<Vec<Device>> {
+        Device::all_async_with(&async_system()).await
+    }
+
+    /// Open all the WireGuard devices managed by the specified backend without blocking.
+    #[cfg(feature = "tokio")]
+    pub async fn all_async_with<B: AsyncBackend + ?Sized>(backend: &B) -> io::Result<Vec<Device>> {
+        let names = backend.list().await?;
+        let mut devices = Vec::with_capacity(names.len());
+
+        for name in names {
+            devices.push(backend.get(&name).await?);
         }
 
-        Ok(())
+        Ok(devices)
+    }
+
+    /// Create a new WireGuard device without blocking.
+    #[cfg(feature = "tokio")]
+    pub async fn create_async<S: Into<String>>(
+        name: S,
+        private_key: Option<Key>,
+    ) -> io::Result<Device> {
+        Device::create_async_with(&async_system(), name, private_key).await
+    }
+
+    /// Create a new WireGuard device using the specified backend without blocking.
+    #[cfg(feature = "tokio")]
+    pub async fn create_async_with<B: AsyncBackend + ?Sized, S: Into<String>>(
+        backend: &B,
+        name: S,
+        private_key: Option<Key>,
+    ) -> io::Result<Device> {
+        let mut device = Device::new(name);
+
+        backend.create(&device.name).await?;
+        device.private_key = private_key;
+
+        Ok(device)
+    }
+
+    /// Open an existing WireGuard device without blocking.
+    #[cfg(feature = "tokio")]
+    pub async fn open_async<S: Into<String>>(name: S) -> io::Result<Device> {
+        Device::open_async_with(&async_system(), name).await
+    }
+
+    /// Open an existing WireGuard device using the specified backend without blocking.
+    #[cfg(feature = "tokio")]
+    pub async fn open_async_with<B: AsyncBackend + ?Sized, S: Into<String>>(
+        backend: &B,
+        name: S,
+    ) -> io::Result<Device> {
+        backend.get(&name.into()).await
+    }
+
+    /// Save the changes made to the device without blocking. Consumes `self`.
+    #[cfg(feature = "tokio")]
+    pub async fn save_async(self) -> io::Result<()> {
+        self.save_async_with(&async_system()).await
+    }
+
+    /// Save the changes made to the device using the specified backend without blocking.
+    /// Consumes `self`.
+    #[cfg(feature = "tokio")]
+    pub async fn save_async_with<B: AsyncBackend + ?Sized>(self, backend: &B) -> io::Result<()> {
+        backend.set(&self).await
+    }
+
+    /// Delete the device from the system without blocking. Consumes `self`.
+    #[cfg(feature = "tokio")]
+    pub async fn delete_async(self) -> io::Result<()> {
+        self.delete_async_with(&async_system()).await
+    }
+
+    /// Delete the device using the specified backend without blocking. Consumes `self`.
+    #[cfg(feature = "tokio")]
+    pub async fn delete_async_with<B: AsyncBackend + ?Sized>(self, backend: &B) -> io::Result<()> {
+        backend.delete(&self.name).await
     }
 }
 
-/// Handle to a device that can be used by the C library.
-#[repr(C)]
-struct Handle {
-    h: sys::wg_device,
-    peers: Vec<peer::Handle>,
+/// The devices of this machine, with kernel devices accessed over netlink since the C library
+/// only offers blocking calls.
+#[cfg(feature = "tokio")]
+fn async_system() -> System<Netlink> {
+    System::with(Netlink::new(), Userspace::new())
 }
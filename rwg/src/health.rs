@@ -0,0 +1,196 @@
+//! Connection health of peers, judged from the age of their latest handshake.
+//!
+//! WireGuard renews the session with a peer every `REKEY_AFTER_TIME` while traffic flows, and
+//! stops using a session after `REJECT_AFTER_TIME`. A peer without traffic does not handshake at
+//! all though, so an old handshake only means that a peer is down when persistent keepalives keep
+//! traffic flowing.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::device::Device;
+use crate::peer::Peer;
+
+/// Age of a session after which WireGuard initiates a new handshake when sending.
+pub const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// Time WireGuard waits for the response to a handshake before retrying.
+pub const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Age of a session after which WireGuard no longer uses it.
+pub const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+/// The health of the connection with a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Health {
+    /// No handshake ever completed.
+    NeverConnected,
+    /// The latest handshake is recent enough for its session to be in use.
+    Connected,
+    /// The session expired, which is expected of a peer without traffic.
+    Stale,
+    /// Handshakes stopped although they should have gone on.
+    LikelyDown,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Health::NeverConnected => "never connected",
+            Health::Connected => "connected",
+            Health::Stale => "stale",
+            Health::LikelyDown => "likely down",
+        })
+    }
+}
+
+/// Classifies peers with configurable thresholds.
+///
+/// A handshake older than the down threshold means `LikelyDown`. So does, for peers with
+/// persistent keepalives, one older than `REKEY_AFTER_TIME` plus the keepalive interval and
+/// `REKEY_TIMEOUT`, when the keepalives should have caused a new handshake. Otherwise, a handshake
+/// younger than the connected threshold means `Connected`, and an older one `Stale`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Classifier {
+    connected: Duration,
+    down: Duration,
+}
+
+impl Default for Classifier {
+    fn default() -> Classifier {
+        Classifier::new()
+    }
+}
+
+impl Classifier {
+    /// Create a classifier considering sessions connected up to `REJECT_AFTER_TIME`, and peers
+    /// down after three times that, when WireGuard erases their keys.
+    pub fn new() -> Classifier {
+        Classifier {
+            connected: REJECT_AFTER_TIME,
+            down: REJECT_AFTER_TIME * 3,
+        }
+    }
+
+    /// Set the age up to which a handshake means that the peer is connected.
+    pub fn set_connected(&mut self, connected: Duration) {
+        self.connected = connected;
+    }
+
+    /// Set the age after which a handshake means that the peer is likely down, whatever its
+    /// keepalive setting.
+    pub fn set_down(&mut self, down: Duration) {
+        self.down = down;
+    }
+
+    /// Get the age up to which a handshake means that the peer is connected.
+    pub fn connected(&self) -> Duration {
+        self.connected
+    }
+
+    /// Get the age after which a handshake means that the peer is likely down.
+    pub fn down(&self) -> Duration {
+        self.down
+    }
+
+    /// Classify a peer at the time `now`. Handshakes from the future count as fresh ones.
+    pub fn classify(&self, peer: &Peer, now: SystemTime) -> Health {
+        let last = match peer.last_handshake() {
+            Some(last) => last,
+            None => return Health::NeverConnected,
+        };
+
+        let age = now.duration_since(last).unwrap_or_default();
+
+        let down = match peer.persistent_keepalive().filter(|interval| *interval > 0) {
+            Some(interval) => self
+                .down
+                .min(REKEY_AFTER_TIME + Duration::from_secs(interval as u64) + REKEY_TIMEOUT),
+            None => self.down,
+        };
+
+        if age > down {
+            Health::LikelyDown
+        } else if age <= self.connected {
+            Health::Connected
+        } else {
+            Health::Stale
+        }
+    }
+
+    /// Classify every peer of a device at the time `now`.
+    pub fn summarize(&self, device: &Device, now: SystemTime) -> Summary {
+        let mut summary = Summary::default();
+
+        for peer in device.peers() {
+            match self.classify(peer, now) {
+                Health::NeverConnected => summary.never_connected += 1,
+                Health::Connected => summary.connected += 1,
+                Health::Stale => summary.stale += 1,
+                Health::LikelyDown => summary.likely_down += 1,
+            }
+        }
+
+        summary
+    }
+}
+
+/// Classify a peer with the default thresholds.
+pub fn classify(peer: &Peer, now: SystemTime) -> Health {
+    Classifier::new().classify(peer, now)
+}
+
+/// Classify every peer of a device with the default thresholds.
+pub fn summarize(device: &Device, now: SystemTime) -> Summary {
+    Classifier::new().summarize(device, now)
+}
+
+/// The number of peers of a device in each health state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    never_connected: usize,
+    connected: usize,
+    stale: usize,
+    likely_down: usize,
+}
+
+impl Summary {
+    /// Get the number of peers in the specified state.
+    pub fn count(&self, health: Health) -> usize {
+        match health {
+            Health::NeverConnected => self.never_connected,
+            Health::Connected => self.connected,
+            Health::Stale => self.stale,
+            Health::LikelyDown => self.likely_down,
+        }
+    }
+
+    /// Get the number of peers.
+    pub fn total(&self) -> usize {
+        self.never_connected + self.connected + self.stale + self.likely_down
+    }
+
+    /// Get the worst state among the peers, if there are any. Peers that never connected come
+    /// before the connected ones.
+    pub fn worst(&self) -> Option<Health> {
+        [
+            Health::LikelyDown,
+            Health::Stale,
+            Health::NeverConnected,
+            Health::Connected,
+        ]
+        .iter()
+        .cloned()
+        .find(|health| self.count(*health) > 0)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connected, {} stale, {} likely down, {} never connected",
+            self.connected, self.stale, self.likely_down, self.never_connected
+        )
+    }
+}
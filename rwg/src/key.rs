@@ -1,14 +1,16 @@
 //! WireGuard key management.
 
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
 
-use libwg_sys as sys;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
 
 /// The size in bytes of a WireGuard key.
 pub const KEY_SIZE: usize = 32;
 
 /// A cryptographic key, public or private.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Key {
     bytes: [u8; KEY_SIZE],
 }
@@ -16,15 +18,21 @@ pub struct Key {
 impl Key {
     /// Generate a new private key.
     pub fn generate_private() -> Key {
-        let mut bytes = [0u8; KEY_SIZE];
+        let mut bytes = random_bytes();
 
-        unsafe {
-            sys::wg_generate_private_key(bytes.as_mut_ptr());
-        }
+        bytes[0] &= 248;
+        bytes[31] = (bytes[31] & 127) | 64;
 
         Key { bytes: bytes }
     }
 
+    /// Generate a new preshared key.
+    pub fn generate_preshared() -> Key {
+        Key {
+            bytes: random_bytes(),
+        }
+    }
+
     /// Construct a new key that is only composed of zero bytes.
     pub fn zero() -> Key {
         Key {
@@ -65,15 +73,34 @@ impl Key {
         Ok(Key { bytes: bytes })
     }
 
-    /// Derive a public key from this key. Assumes `Self` is a private key.
-    pub fn derive_public(&self) -> Key {
+    /// Construct a key from its hexadecimal representation, as used by the userspace API.
+    pub fn from_hex(hex: &str) -> Result<Key, InvalidKey> {
         let mut bytes = [0u8; KEY_SIZE];
+        let hex = hex.as_bytes();
 
-        unsafe {
-            sys::wg_generate_public_key(bytes.as_mut_ptr(), self.bytes.as_ptr() as *mut u8);
+        if hex.len() != KEY_SIZE * 2 {
+            return Err(InvalidKey::InvalidLength);
         }
 
-        Key { bytes: bytes }
+        for (i, pair) in hex.chunks(2).enumerate() {
+            let high = (pair[0] as char)
+                .to_digit(16)
+                .ok_or(InvalidKey::InvalidHex)?;
+            let low = (pair[1] as char)
+                .to_digit(16)
+                .ok_or(InvalidKey::InvalidHex)?;
+
+            bytes[i] = (high << 4 | low) as u8;
+        }
+
+        Ok(Key { bytes: bytes })
+    }
+
+    /// Derive a public key from this key. Assumes `Self` is a private key.
+    pub fn derive_public(&self) -> Key {
+        Key {
+            bytes: x25519(self.bytes, X25519_BASEPOINT_BYTES),
+        }
     }
 
     /// Get the Base64 representation of the key.
@@ -81,6 +108,16 @@ impl Key {
         base64::encode(&self.bytes)
     }
 
+    /// Get the lowercase hexadecimal representation of the key.
+    pub fn to_hex(&self) -> String {
+        self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Check whether all the bytes of the key are zero.
+    pub fn is_zero(&self) -> bool {
+        self.bytes.iter().all(|b| *b == 0)
+    }
+
     /// Get a reference to the underlying key bytes.
     pub fn as_bytes(&self) -> &[u8] {
         &self.bytes
@@ -93,11 +130,23 @@ impl fmt::Display for Key {
     }
 }
 
+/// Read random bytes from the kernel's random number generator.
+fn random_bytes() -> [u8; KEY_SIZE] {
+    let mut bytes = [0u8; KEY_SIZE];
+
+    File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut bytes))
+        .unwrap_or_else(|err: io::Error| panic!("Failed to read random bytes: {}", err));
+
+    bytes
+}
+
 /// Errors that can happen when dealing with keys.
 #[derive(Debug)]
 pub enum InvalidKey {
     InvalidLength,
     InvalidBase64,
+    InvalidHex,
 }
 
 impl fmt::Display for InvalidKey {
@@ -105,6 +154,7 @@ impl fmt::Display for InvalidKey {
         match self {
             InvalidKey::InvalidLength => write!(f, "key length must be {}", KEY_SIZE),
             InvalidKey::InvalidBase64 => write!(f, "invalid base64 string"),
+            InvalidKey::InvalidHex => write!(f, "invalid hexadecimal string"),
         }
     }
 }
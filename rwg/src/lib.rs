@@ -1,13 +1,43 @@
 //! rwg - rusty wireguard
 
+#[cfg(feature = "tokio")]
+pub use self::backend::AsyncBackend;
+pub use self::backend::Backend;
 pub use self::device::Device;
 pub use self::key::Key;
+pub use self::link::{Address, InvalidAddress, Link, OperState};
+pub use self::netns::{Namespace, NETNS_DIR};
 pub use self::peer::{AllowedIp, Endpoint, Peer};
+pub use self::policy::FULL_TUNNEL_TABLE;
+pub use self::route::MAIN_TABLE;
+
+pub mod accounting;
+pub mod backend;
+pub mod check;
+pub mod client;
+pub mod config;
+pub mod health;
+pub mod metadata;
+pub mod metrics;
+#[cfg(feature = "qr")]
+pub mod qr;
+pub mod reaper;
+pub mod show;
+pub mod stats;
+pub mod topology;
+pub mod uapi;
+pub mod watch;
 
 mod device;
 mod key;
+mod link;
+#[cfg(feature = "libwg")]
 mod net;
+mod netlink;
+mod netns;
 mod peer;
+mod policy;
+mod route;
 
 #[cfg(test)]
 mod tests;
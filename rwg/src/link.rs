@@ -0,0 +1,356 @@
+//! Network interface management: link state, MTU and addresses.
+
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::device::Device;
+use crate::netlink::{self, ifinfomsg, Message, Socket};
+use crate::netlink::{IFLA_IFNAME, RTM_GETLINK, RTM_SETLINK};
+
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_GETADDR: u16 = 22;
+
+const IFLA_MTU: u16 = 4;
+const IFLA_OPERSTATE: u16 = 16;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+const IFF_UP: u32 = 0x1;
+const IFF_LOWER_UP: u32 = 0x10000;
+
+/// Size of `struct ifaddrmsg`, which precedes the attributes of address messages.
+const IFADDRMSG_SIZE: usize = 8;
+
+/// An IP address assigned to an interface, along with the prefix length of its network.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Address {
+    /// Create a new interface address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Address {
+        Address {
+            addr: addr,
+            prefix_len: prefix_len,
+        }
+    }
+
+    /// Get the IP address.
+    pub fn addr(&self) -> &IpAddr {
+        &self.addr
+    }
+
+    /// Get the prefix length of the network.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Address {
+    type Err = InvalidAddress;
+
+    /// Parse an address of the form `10.0.0.1/24`. Without a prefix length, the address is
+    /// considered alone in its network.
+    fn from_str(s: &str) -> Result<Address, InvalidAddress> {
+        let mut parts = s.trim().splitn(2, '/');
+
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| InvalidAddress)?;
+
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| InvalidAddress)?,
+            None => max,
+        };
+
+        if prefix_len > max {
+            return Err(InvalidAddress);
+        }
+
+        Ok(Address::new(addr, prefix_len))
+    }
+}
+
+/// Error returned when parsing an invalid address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidAddress;
+
+impl fmt::Display for InvalidAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid address, expected the form 10.0.0.1/24")
+    }
+}
+
+/// Operational state of an interface, as defined by RFC 2863.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperState {
+    Unknown,
+    NotPresent,
+    Down,
+    LowerLayerDown,
+    Testing,
+    Dormant,
+    Up,
+}
+
+impl OperState {
+    fn from_u8(state: u8) -> OperState {
+        match state {
+            1 => OperState::NotPresent,
+            2 => OperState::Down,
+            3 => OperState::LowerLayerDown,
+            4 => OperState::Testing,
+            5 => OperState::Dormant,
+            6 => OperState::Up,
+            _ => OperState::Unknown,
+        }
+    }
+}
+
+/// The state of the network interface of a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    index: u32,
+    flags: u32,
+    mtu: u32,
+    oper_state: OperState,
+}
+
+impl Link {
+    /// Get the index of the interface.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Whether the interface has been brought up administratively.
+    pub fn is_up(&self) -> bool {
+        self.flags & IFF_UP != 0
+    }
+
+    /// Whether the interface is able to carry packets.
+    pub fn is_lower_up(&self) -> bool {
+        self.flags & IFF_LOWER_UP != 0
+    }
+
+    /// Get the MTU of the interface.
+    pub fn mtu(&self) -> u32 {
+        self.mtu
+    }
+
+    /// Get the operational state of the interface.
+    pub fn oper_state(&self) -> OperState {
+        self.oper_state
+    }
+}
+
+impl Device {
+    /// Read the state of the network interface of this device.
+    pub fn link(&self) -> io::Result<Link> {
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        let mut msg = Message::new(RTM_GETLINK, 0);
+
+        msg.put(&ifinfomsg(0, 0, 0));
+        msg.attr_str(IFLA_IFNAME, self.name());
+
+        socket
+            .request(&msg)?
+            .iter()
+            .find_map(|payload| parse_link(payload))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid link message"))
+    }
+
+    /// Bring the network interface of this device up.
+    pub fn set_up(&self) -> io::Result<()> {
+        self.set_link(IFF_UP, IFF_UP, None)
+    }
+
+    /// Bring the network interface of this device down.
+    pub fn set_down(&self) -> io::Result<()> {
+        self.set_link(0, IFF_UP, None)
+    }
+
+    /// Set the MTU of the network interface of this device.
+    pub fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        self.set_link(0, 0, Some(mtu))
+    }
+
+    /// List the addresses assigned to the network interface of this device.
+    pub fn addresses(&self) -> io::Result<Vec<Address>> {
+        let index = self.index()?;
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        let mut msg = Message::new(RTM_GETADDR, netlink::NLM_F_DUMP);
+
+        msg.put(&ifaddrmsg(libc::AF_UNSPEC as u8, 0, 0));
+
+        Ok(socket
+            .request(&msg)?
+            .iter()
+            .filter_map(|payload| parse_address(payload))
+            .filter(|(i, _)| *i == index)
+            .map(|(_, address)| address)
+            .collect())
+    }
+
+    /// Assign an address to the network interface of this device.
+    pub fn add_address(&self, address: &Address) -> io::Result<()> {
+        let flags = netlink::NLM_F_CREATE | netlink::NLM_F_EXCL;
+        self.address_request(RTM_NEWADDR, flags, address)
+    }
+
+    /// Remove an address from the network interface of this device.
+    pub fn remove_address(&self, address: &Address) -> io::Result<()> {
+        self.address_request(RTM_DELADDR, 0, address)
+    }
+
+    /// Get the index of the network interface of this device.
+    pub(crate) fn index(&self) -> io::Result<u32> {
+        let name = CString::new(self.name())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid device name"))?;
+
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(io::Error::last_os_error()),
+            index => Ok(index),
+        }
+    }
+
+    /// Change the flags selected by `change` and optionally the MTU of the interface.
+    fn set_link(&self, flags: u32, change: u32, mtu: Option<u32>) -> io::Result<()> {
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        let mut msg = Message::new(RTM_SETLINK, 0);
+
+        msg.put(&ifinfomsg(0, flags, change));
+        msg.attr_str(IFLA_IFNAME, self.name());
+
+        if let Some(mtu) = mtu {
+            msg.attr_u32(IFLA_MTU, mtu);
+        }
+
+        socket.request(&msg)?;
+
+        Ok(())
+    }
+
+    /// Send an address request for the interface.
+    fn address_request(&self, ty: u16, flags: u16, address: &Address) -> io::Result<()> {
+        let index = self.index()?;
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        let mut msg = Message::new(ty, flags);
+
+        match address.addr() {
+            IpAddr::V4(ip4) => {
+                msg.put(&ifaddrmsg(libc::AF_INET as u8, address.prefix_len(), index));
+                msg.attr(IFA_LOCAL, &ip4.octets());
+                msg.attr(IFA_ADDRESS, &ip4.octets());
+            }
+
+            IpAddr::V6(ip6) => {
+                msg.put(&ifaddrmsg(
+                    libc::AF_INET6 as u8,
+                    address.prefix_len(),
+                    index,
+                ));
+                msg.attr(IFA_ADDRESS, &ip6.octets());
+            }
+        }
+
+        socket.request(&msg)?;
+
+        Ok(())
+    }
+}
+
+/// Build a `struct ifaddrmsg`.
+fn ifaddrmsg(family: u8, prefix_len: u8, index: u32) -> [u8; IFADDRMSG_SIZE] {
+    let mut header = [0u8; IFADDRMSG_SIZE];
+
+    header[0] = family;
+    header[1] = prefix_len;
+    header[4..8].copy_from_slice(&index.to_ne_bytes());
+
+    header
+}
+
+/// Parse the payload of a `RTM_NEWLINK` message.
+pub(crate) fn parse_link(payload: &[u8]) -> Option<Link> {
+    let header = payload.get(..netlink::IFINFOMSG_SIZE)?;
+    let index = netlink::attr_u32(&header[4..8])?;
+    let flags = netlink::attr_u32(&header[8..12])?;
+
+    let mut link = Link {
+        index: index,
+        flags: flags,
+        mtu: 0,
+        oper_state: OperState::Unknown,
+    };
+
+    for (ty, data) in netlink::attrs(&payload[netlink::IFINFOMSG_SIZE..]) {
+        match ty {
+            IFLA_MTU => link.mtu = netlink::attr_u32(data).unwrap_or(0),
+            IFLA_OPERSTATE => {
+                link.oper_state = OperState::from_u8(netlink::attr_u8(data).unwrap_or(0))
+            }
+            _ => {}
+        }
+    }
+
+    Some(link)
+}
+
+/// Parse the payload of a `RTM_NEWADDR` message into the interface index and the address.
+pub(crate) fn parse_address(payload: &[u8]) -> Option<(u32, Address)> {
+    let header = payload.get(..IFADDRMSG_SIZE)?;
+    let prefix_len = header[1];
+    let index = netlink::attr_u32(&header[4..8])?;
+
+    let mut local = None;
+    let mut address = None;
+
+    for (ty, data) in netlink::attrs(&payload[IFADDRMSG_SIZE..]) {
+        let addr = match data.len() {
+            4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(data);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+
+            _ => continue,
+        };
+
+        match ty {
+            IFA_LOCAL => local = Some(addr),
+            IFA_ADDRESS => address = Some(addr),
+            _ => {}
+        }
+    }
+
+    // For point-to-point IPv4 links, IFA_ADDRESS is the address of the other end.
+    let addr = local.or(address)?;
+
+    Some((index, Address::new(addr, prefix_len)))
+}
@@ -0,0 +1,443 @@
+//! Metadata about peers, such as their names and owners.
+//!
+//! The kernel only knows the keys, endpoints and allowed IPs of peers. A `Store` keeps what else
+//! is known about them in a sidecar file next to the configuration of the device, `wg0.meta` for
+//! `wg0.conf`, and attaches it to the peers of devices read from the kernel:
+//!
+//! ```text
+//! [Peer]
+//! PublicKey = <public key>
+//! Name = laptop
+//! Tags = office, admin
+//! Owner = alice
+//! CreatedAt = <seconds since the UNIX epoch>
+//! ExpiresAt = <seconds since the UNIX epoch>
+//! Comment = <free text, one line per comment>
+//! ```
+//!
+//! Configuration files carry the same lines as comments above their peer sections,
+//! `# Name = laptop`, so that metadata survives being read and written back by `config`.
+//! `Device::open_annotated` reads a device with the metadata from the store of its configuration
+//! in `CONFIG_DIR`.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{Backend, System};
+use crate::device::Device;
+use crate::key::Key;
+
+/// Extension of the sidecar files.
+pub const EXTENSION: &str = "meta";
+
+/// Directory of the wg-quick configuration files, next to which the stores of devices are kept.
+pub const CONFIG_DIR: &str = "/etc/wireguard";
+
+/// What is known about a peer besides its configuration.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    name: Option<String>,
+    tags: Vec<String>,
+    owner: Option<String>,
+    created_at: Option<SystemTime>,
+    expires_at: Option<SystemTime>,
+    comments: Vec<String>,
+}
+
+impl Metadata {
+    /// Create empty metadata.
+    pub fn new() -> Metadata {
+        Metadata::default()
+    }
+
+    /// Set the friendly name of the peer.
+    pub fn set_name<S: Into<String>>(&mut self, name: S) {
+        self.name = Some(name.into());
+    }
+
+    /// Add a tag, unless the peer already has it.
+    pub fn add_tag<S: Into<String>>(&mut self, tag: S) {
+        let tag = tag.into();
+
+        if !self.tags.contains(&tag) {
+            self.tags.push(tag);
+        }
+    }
+
+    /// Remove a tag.
+    pub fn remove_tag(&mut self, tag: &str) {
+        self.tags.retain(|t| t != tag);
+    }
+
+    /// Set who the peer belongs to.
+    pub fn set_owner<S: Into<String>>(&mut self, owner: S) {
+        self.owner = Some(owner.into());
+    }
+
+    /// Set when the peer was created. Stored with a precision of one second.
+    pub fn set_created_at(&mut self, time: SystemTime) {
+        self.created_at = Some(truncate(time));
+    }
+
+    /// Set when the peer expires, after which `reaper::Reaper` removes it. Stored with a
+    /// precision of one second.
+    pub fn set_expires_at(&mut self, time: SystemTime) {
+        self.expires_at = Some(truncate(time));
+    }
+
+    /// Remove the expiry time of the peer.
+    pub fn remove_expires_at(&mut self) {
+        self.expires_at = None;
+    }
+
+    /// Add a line of free text.
+    pub fn add_comment<S: Into<String>>(&mut self, comment: S) {
+        self.comments.push(comment.into());
+    }
+
+    /// Get the friendly name of the peer, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Get the tags of the peer.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Whether the peer has a tag.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// Get who the peer belongs to, if known.
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    /// Get when the peer was created, if known.
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.created_at
+    }
+
+    /// Get when the peer expires, if it does.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    /// Get the lines of free text.
+    pub fn comments(&self) -> &[String] {
+        &self.comments
+    }
+
+    /// Get a mutable reference to the lines of free text.
+    pub fn comments_mut(&mut self) -> &mut Vec<String> {
+        &mut self.comments
+    }
+
+    /// Whether nothing is known.
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// Apply a `Key = Value` line other than `Comment`, returning `false` if the key is not a
+    /// metadata key or the value is invalid.
+    pub(crate) fn apply(&mut self, key: &str, value: &str) -> bool {
+        match key.to_lowercase().as_str() {
+            "name" => self.set_name(value),
+            "owner" => self.set_owner(value),
+
+            "tags" => {
+                for tag in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                    self.add_tag(tag);
+                }
+            }
+
+            "createdat" => match value.parse() {
+                Ok(secs) => self.created_at = Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                Err(_) => return false,
+            },
+
+            "expiresat" => match value.parse() {
+                Ok(secs) => self.expires_at = Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                Err(_) => return false,
+            },
+
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Get the `Key = Value` lines of everything but the comments.
+    pub(crate) fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if let Some(name) = &self.name {
+            lines.push(format!("Name = {}", name));
+        }
+
+        if !self.tags.is_empty() {
+            lines.push(format!("Tags = {}", self.tags.join(", ")));
+        }
+
+        if let Some(owner) = &self.owner {
+            lines.push(format!("Owner = {}", owner));
+        }
+
+        if let Some(time) = self.created_at {
+            lines.push(format!("CreatedAt = {}", secs(time)));
+        }
+
+        if let Some(time) = self.expires_at {
+            lines.push(format!("ExpiresAt = {}", secs(time)));
+        }
+
+        lines
+    }
+}
+
+/// The metadata of peers, by public key, stored in a sidecar file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Store {
+    path: PathBuf,
+    peers: HashMap<Key, Metadata>,
+}
+
+impl Store {
+    /// Open the store at `path`. A missing file is an empty store, created when saved.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Store> {
+        let mut store = Store {
+            path: path.into(),
+            peers: HashMap::new(),
+        };
+
+        let file = match File::open(&store.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(err) => return Err(err),
+        };
+
+        let mut section: Option<(usize, Option<Key>, Metadata)> = None;
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let number = i + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.eq_ignore_ascii_case("[Peer]") {
+                store.finish(section.take())?;
+                section = Some((number, None, Metadata::new()));
+
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(invalid(format!("Line {}: malformed line", number))),
+            };
+
+            let (public_key, metadata) = match &mut section {
+                Some((_, public_key, metadata)) => (public_key, metadata),
+                None => return Err(invalid(format!("Line {}: key outside of a peer", number))),
+            };
+
+            if key.eq_ignore_ascii_case("PublicKey") {
+                let key = Key::from_base64(value)
+                    .map_err(|_| invalid(format!("Line {}: invalid public key", number)))?;
+
+                *public_key = Some(key);
+            } else if key.eq_ignore_ascii_case("Comment") {
+                metadata.add_comment(value);
+            } else if !metadata.apply(key, value) {
+                return Err(invalid(format!("Line {}: invalid line: {}", number, line)));
+            }
+        }
+
+        store.finish(section)?;
+
+        Ok(store)
+    }
+
+    /// Open the sidecar store of a configuration file, `wg0.meta` for `wg0.conf`.
+    pub fn open_for<P: AsRef<Path>>(config: P) -> io::Result<Store> {
+        Store::open(config.as_ref().with_extension(EXTENSION))
+    }
+
+    /// Open the store of the configuration of a device in `CONFIG_DIR`, `wg0.meta` for `wg0`.
+    pub fn open_for_device(name: &str) -> io::Result<Store> {
+        Store::open(device_path(CONFIG_DIR, name))
+    }
+
+    /// Get the path of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the store, replacing the previous file atomically.
+    pub fn save(&self) -> io::Result<()> {
+        let mut keys = self.peers.keys().collect::<Vec<_>>();
+        keys.sort_by_key(|key| key.to_base64());
+
+        let mut buf = String::new();
+
+        for key in keys {
+            let metadata = &self.peers[key];
+
+            if !buf.is_empty() {
+                buf.push('\n');
+            }
+
+            buf.push_str(&format!("[Peer]\nPublicKey = {}\n", key.to_base64()));
+
+            for line in metadata.lines() {
+                buf.push_str(&format!("{}\n", line));
+            }
+
+            for comment in &metadata.comments {
+                buf.push_str(&format!("Comment = {}\n", comment));
+            }
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+
+        file.write_all(buf.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Get the metadata of a peer.
+    pub fn get(&self, key: &Key) -> Option<&Metadata> {
+        self.peers.get(key)
+    }
+
+    /// Set the metadata of a peer. Empty metadata removes the peer from the store.
+    pub fn set(&mut self, key: Key, metadata: Metadata) {
+        if metadata.is_empty() {
+            self.peers.remove(&key);
+        } else {
+            self.peers.insert(key, metadata);
+        }
+    }
+
+    /// Remove a peer from the store, returning its metadata.
+    pub fn remove(&mut self, key: &Key) -> Option<Metadata> {
+        self.peers.remove(key)
+    }
+
+    /// Get the public keys of the peers in the store.
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.peers.keys()
+    }
+
+    /// Attach the stored metadata to the peers of a device, such as one read from the kernel.
+    pub fn attach(&self, device: &mut Device) {
+        for peer in device.peers_mut() {
+            let metadata = peer.public_key().and_then(|key| self.peers.get(key));
+
+            if let Some(metadata) = metadata {
+                peer.set_metadata(metadata.clone());
+            }
+        }
+    }
+
+    /// Store the metadata of the peers of a device, such as one read from a configuration file.
+    /// Peers without metadata are left untouched.
+    pub fn collect(&mut self, device: &Device) {
+        for peer in device.peers() {
+            if let (Some(key), Some(metadata)) = (peer.public_key(), peer.metadata()) {
+                self.set(key.clone(), metadata.clone());
+            }
+        }
+    }
+
+    /// Forget the peers that are not part of a device.
+    pub fn retain(&mut self, device: &Device) {
+        self.peers.retain(|key, _| {
+            device
+                .peers()
+                .iter()
+                .any(|peer| peer.public_key() == Some(key))
+        });
+    }
+
+    /// Add a peer section from its first line, the public key and the metadata read so far.
+    fn finish(&mut self, section: Option<(usize, Option<Key>, Metadata)>) -> io::Result<()> {
+        if let Some((number, public_key, metadata)) = section {
+            let key = public_key
+                .ok_or_else(|| invalid(format!("Line {}: peer without a public key", number)))?;
+
+            self.set(key, metadata);
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the number of whole seconds since the UNIX epoch.
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Drop the fraction of a second of a time.
+fn truncate(time: SystemTime) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs(time))
+}
+
+impl Device {
+    /// Open an existing WireGuard device, with the metadata of its peers from its store in
+    /// `CONFIG_DIR` attached. A store that cannot be read is ignored.
+    pub fn open_annotated<S: Into<String>>(name: S) -> io::Result<Device> {
+        Device::open_annotated_with(&System::new(), name)
+    }
+
+    /// Open an existing WireGuard device with metadata using the specified backend.
+    pub fn open_annotated_with<B: Backend + ?Sized, S: Into<String>>(
+        backend: &B,
+        name: S,
+    ) -> io::Result<Device> {
+        Device::open_annotated_in(backend, Path::new(CONFIG_DIR), name)
+    }
+
+    /// Open an existing WireGuard device with the metadata from its store in `dir`. The metadata
+    /// is only a convenience: a store that cannot be read is ignored, with a warning if it is
+    /// invalid, and the device is still opened.
+    pub(crate) fn open_annotated_in<B: Backend + ?Sized, S: Into<String>>(
+        backend: &B,
+        dir: &Path,
+        name: S,
+    ) -> io::Result<Device> {
+        let mut device = Device::open_with(backend, name)?;
+        let path = device_path(dir, device.name());
+
+        match Store::open(&path) {
+            Ok(store) => store.attach(&mut device),
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+            Err(err) => eprintln!("Warning: ignoring `{}': {}", path.display(), err),
+        }
+
+        Ok(device)
+    }
+}
+
+/// Get the path of the store of the configuration of a device in `dir`.
+fn device_path<P: AsRef<Path>>(dir: P, name: &str) -> PathBuf {
+    dir.as_ref().join(format!("{}.{}", name, EXTENSION))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
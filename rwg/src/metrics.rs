@@ -0,0 +1,204 @@
+//! Metrics of devices and their peers, in the OpenMetrics text format scraped by Prometheus.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::time::UNIX_EPOCH;
+
+use crate::device::Device;
+use crate::key::Key;
+use crate::peer::Peer;
+
+/// The content type of the rendered metrics.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Friendly names given to peers, added as a `name` label to their metrics.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Names {
+    names: HashMap<Key, String>,
+}
+
+impl Names {
+    /// Create an empty mapping.
+    pub fn new() -> Names {
+        Names::default()
+    }
+
+    /// Read a mapping file: one base64 public key per line, followed by the name of the peer.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn read<R: BufRead>(r: R) -> io::Result<Names> {
+        let mut names = Names::new();
+
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: expected a public key and a name", i + 1),
+                )
+            };
+
+            let (key, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let key = Key::from_base64(key).map_err(|_| invalid())?;
+
+            names.insert(key, name.trim());
+        }
+
+        Ok(names)
+    }
+
+    /// Give a name to a peer.
+    pub fn insert<S: Into<String>>(&mut self, key: Key, name: S) {
+        self.names.insert(key, name.into());
+    }
+
+    /// Get the name of a peer.
+    pub fn get(&self, key: &Key) -> Option<&str> {
+        self.names.get(key).map(String::as_str)
+    }
+}
+
+/// A metric family, with its samples.
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    unit: Option<&'static str>,
+    help: &'static str,
+    samples: String,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Family {
+        Family {
+            name: name,
+            kind: kind,
+            unit: None,
+            help: help,
+            samples: String::new(),
+        }
+    }
+
+    fn with_unit(mut self, unit: &'static str) -> Family {
+        self.unit = Some(unit);
+        self
+    }
+
+    /// Add a sample. Counters get the `_total` suffix.
+    fn sample(&mut self, labels: &str, value: u64) {
+        let suffix = if self.kind == "counter" { "_total" } else { "" };
+        let _ = writeln!(
+            self.samples,
+            "{}{}{{{}}} {}",
+            self.name, suffix, labels, value
+        );
+    }
+
+    fn render(&self, buf: &mut String) {
+        let _ = writeln!(buf, "# TYPE {} {}", self.name, self.kind);
+
+        if let Some(unit) = self.unit {
+            let _ = writeln!(buf, "# UNIT {} {}", self.name, unit);
+        }
+
+        let _ = writeln!(buf, "# HELP {} {}", self.name, self.help);
+        buf.push_str(&self.samples);
+    }
+}
+
+/// Render the metrics of devices:
+///
+/// - `wireguard_peers`: the number of peers of each device.
+/// - `wireguard_received_bytes_total` and `wireguard_sent_bytes_total`: the traffic of each peer.
+/// - `wireguard_latest_handshake_seconds`: the UNIX time of the latest handshake of each peer
+///   that had one.
+/// - `wireguard_allowed_ips`: the number of allowed IPs of each peer.
+///
+/// Peer metrics are labelled with the interface and the public key of the peer, along with its
+/// name when it has one in `names`.
+pub fn render(devices: &[Device], names: &Names) -> String {
+    let mut peers = Family::new("wireguard_peers", "gauge", "Number of peers of the device.");
+    let mut received = Family::new(
+        "wireguard_received_bytes",
+        "counter",
+        "Bytes received from the peer.",
+    )
+    .with_unit("bytes");
+    let mut sent = Family::new("wireguard_sent_bytes", "counter", "Bytes sent to the peer.")
+        .with_unit("bytes");
+    let mut handshake = Family::new(
+        "wireguard_latest_handshake_seconds",
+        "gauge",
+        "UNIX time of the latest handshake with the peer.",
+    )
+    .with_unit("seconds");
+    let mut allowed_ips = Family::new(
+        "wireguard_allowed_ips",
+        "gauge",
+        "Number of allowed IPs of the peer.",
+    );
+
+    for device in devices {
+        let labels = format!("interface=\"{}\"", escape(device.name()));
+        peers.sample(&labels, device.peers().len() as u64);
+
+        for peer in device.peers() {
+            let labels = peer_labels(device, peer, names);
+
+            received.sample(&labels, peer.rx_bytes());
+            sent.sample(&labels, peer.tx_bytes());
+            allowed_ips.sample(&labels, peer.allowed_ips().len() as u64);
+
+            if let Some(time) = peer.last_handshake() {
+                let secs = time
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or(0);
+
+                handshake.sample(&labels, secs);
+            }
+        }
+    }
+
+    let mut buf = String::new();
+
+    for family in &[peers, received, sent, handshake, allowed_ips] {
+        family.render(&mut buf);
+    }
+
+    buf.push_str("# EOF\n");
+    buf
+}
+
+/// Write the metrics of devices.
+pub fn write<W: Write>(devices: &[Device], names: &Names, mut w: W) -> io::Result<()> {
+    w.write_all(render(devices, names).as_bytes())
+}
+
+/// Build the labels identifying a peer.
+fn peer_labels(device: &Device, peer: &Peer, names: &Names) -> String {
+    let mut labels = format!("interface=\"{}\"", escape(device.name()));
+
+    if let Some(key) = peer.public_key() {
+        let _ = write!(labels, ",public_key=\"{}\"", key.to_base64());
+
+        if let Some(name) = names.get(key) {
+            let _ = write!(labels, ",name=\"{}\"", escape(name));
+        }
+    }
+
+    labels
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
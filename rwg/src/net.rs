@@ -16,7 +16,7 @@ pub fn endpoint_to_sockaddr(endpoint: &Endpoint, saddr: *mut sys::sockaddr) {
             let mut in4 = saddr as *mut sys::sockaddr_in;
 
             (*in4).sin_family = sys::AF_INET as u16;
-            (*in4).sin_port = *port;
+            (*in4).sin_port = port.to_be();
 
             write_ip4_to_in_addr(&ip4, &mut (*in4).sin_addr);
         },
@@ -25,7 +25,7 @@ pub fn endpoint_to_sockaddr(endpoint: &Endpoint, saddr: *mut sys::sockaddr) {
             let mut in6 = saddr as *mut sys::sockaddr_in6;
 
             (*in6).sin6_family = sys::AF_INET6 as u16;
-            (*in6).sin6_port = *port;
+            (*in6).sin6_port = port.to_be();
 
             write_ip6_to_in6_addr(&ip6, &mut (*in6).sin6_addr);
         },
@@ -42,7 +42,7 @@ pub fn sockaddr_to_endpoint(saddr: *const sys::sockaddr) -> Option<Endpoint> {
 
                 let addr = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
 
-                Some((IpAddr::V4(addr), (*in4).sin_port))
+                Some((IpAddr::V4(addr), u16::from_be((*in4).sin_port)))
             }
 
             sys::AF_INET6 => {
@@ -50,7 +50,7 @@ pub fn sockaddr_to_endpoint(saddr: *const sys::sockaddr) -> Option<Endpoint> {
 
                 Some((
                     IpAddr::V6(read_ip6_from_in6_addr(&(*in6).sin6_addr)),
-                    (*in6).sin6_port,
+                    u16::from_be((*in6).sin6_port),
                 ))
             }
 
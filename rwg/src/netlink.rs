@@ -0,0 +1,530 @@
+//! Minimal netlink client, used to talk to the kernel without the C library.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(feature = "tokio")]
+use tokio::io::unix::AsyncFd;
+#[cfg(feature = "tokio")]
+use tokio::io::Interest;
+
+pub const NLM_F_REQUEST: u16 = 0x1;
+pub const NLM_F_ACK: u16 = 0x4;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+pub const NLM_F_DUMP: u16 = 0x300;
+
+pub const NLMSG_ERROR: u16 = 0x2;
+pub const NLMSG_DONE: u16 = 0x3;
+
+pub const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = !(0x8000 | 0x4000);
+
+pub const RTM_NEWLINK: u16 = 16;
+pub const RTM_DELLINK: u16 = 17;
+pub const RTM_GETLINK: u16 = 18;
+pub const RTM_SETLINK: u16 = 19;
+
+pub const IFLA_IFNAME: u16 = 3;
+pub const IFLA_LINKINFO: u16 = 18;
+pub const IFLA_INFO_KIND: u16 = 1;
+
+/// Size of `struct ifinfomsg`, which precedes the attributes of link messages.
+pub const IFINFOMSG_SIZE: usize = 16;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+/// Size of the netlink message header.
+pub const HEADER_SIZE: usize = 16;
+/// Size of the generic netlink header that follows the netlink header.
+pub const GENL_HEADER_SIZE: usize = 4;
+
+/// Size of the buffer used to receive messages, large enough for any dump message.
+const RECV_BUFFER_SIZE: usize = 65536;
+
+/// Round a length up to the netlink alignment of 4 bytes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Build a `struct ifinfomsg` for the specified interface index, flags and change mask.
+pub fn ifinfomsg(index: u32, flags: u32, change: u32) -> [u8; IFINFOMSG_SIZE] {
+    let mut header = [0u8; IFINFOMSG_SIZE];
+
+    header[4..8].copy_from_slice(&index.to_ne_bytes());
+    header[8..12].copy_from_slice(&flags.to_ne_bytes());
+    header[12..16].copy_from_slice(&change.to_ne_bytes());
+
+    header
+}
+
+/// A netlink message being built.
+#[derive(Debug, Clone)]
+pub struct Message {
+    buf: Vec<u8>,
+    nests: Vec<usize>,
+}
+
+impl Message {
+    /// Start a message of the specified type. `NLM_F_REQUEST` is always added to the flags.
+    pub fn new(ty: u16, flags: u16) -> Message {
+        let mut buf = vec![0u8; HEADER_SIZE];
+
+        buf[4..6].copy_from_slice(&ty.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST).to_ne_bytes());
+
+        Message {
+            buf: buf,
+            nests: Vec::new(),
+        }
+    }
+
+    /// Start a generic netlink message for the specified family and command.
+    pub fn genl(family: u16, flags: u16, cmd: u8, version: u8) -> Message {
+        let mut msg = Message::new(family, flags);
+        msg.put(&[cmd, version, 0, 0]);
+        msg
+    }
+
+    /// Get the flags of the message.
+    pub fn flags(&self) -> u16 {
+        u16::from_ne_bytes([self.buf[6], self.buf[7]])
+    }
+
+    /// Append raw bytes, such as a protocol specific header, padded to the netlink alignment.
+    pub fn put(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    /// Append an attribute.
+    pub fn attr(&mut self, ty: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.put(data);
+    }
+
+    /// Append an 8-bit integer attribute.
+    pub fn attr_u8(&mut self, ty: u16, value: u8) {
+        self.attr(ty, &[value]);
+    }
+
+    /// Append a 16-bit integer attribute, in native byte order.
+    pub fn attr_u16(&mut self, ty: u16, value: u16) {
+        self.attr(ty, &value.to_ne_bytes());
+    }
+
+    /// Append a 32-bit integer attribute, in native byte order.
+    pub fn attr_u32(&mut self, ty: u16, value: u32) {
+        self.attr(ty, &value.to_ne_bytes());
+    }
+
+    /// Append a NUL-terminated string attribute.
+    pub fn attr_str(&mut self, ty: u16, value: &str) {
+        let mut data = Vec::with_capacity(value.len() + 1);
+
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+
+        self.attr(ty, &data);
+    }
+
+    /// Open a nested attribute. Every attribute appended until the matching `end_nested` call
+    /// belongs to it.
+    pub fn begin_nested(&mut self, ty: u16) {
+        self.nests.push(self.buf.len());
+        self.attr(ty | NLA_F_NESTED, &[]);
+    }
+
+    /// Close the innermost nested attribute.
+    pub fn end_nested(&mut self) {
+        let start = self.nests.pop().expect("No nested attribute to end");
+        let len = (self.buf.len() - start) as u16;
+
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    /// Get the current length of the message.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Drop everything appended after the message had the specified length, including nested
+    /// attributes opened since then.
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+
+        while self
+            .nests
+            .last()
+            .map(|start| *start >= len)
+            .unwrap_or(false)
+        {
+            self.nests.pop();
+        }
+    }
+
+    /// Get the bytes to send, with the length, sequence number and port ID filled in.
+    pub fn finish(&self, seq: u32, pid: u32) -> Vec<u8> {
+        assert!(self.nests.is_empty(), "Unterminated nested attribute");
+
+        let mut buf = self.buf.clone();
+        let len = buf.len() as u32;
+
+        buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        buf[12..16].copy_from_slice(&pid.to_ne_bytes());
+
+        buf
+    }
+}
+
+/// Collects the replies to a request, as they are received. Dumps end with `NLMSG_DONE`, and
+/// other requests with their acknowledgement.
+#[derive(Debug)]
+pub struct Replies {
+    seq: u32,
+    payloads: Vec<Vec<u8>>,
+}
+
+impl Replies {
+    /// Start collecting the replies to the request with the specified sequence number.
+    pub fn new(seq: u32) -> Replies {
+        Replies {
+            seq: seq,
+            payloads: Vec::new(),
+        }
+    }
+
+    /// Process a received datagram. Returns `true` once the request is complete.
+    pub fn feed(&mut self, mut datagram: &[u8]) -> io::Result<bool> {
+        while datagram.len() >= HEADER_SIZE {
+            let len = read_u32(&datagram[0..4]) as usize;
+            let ty = read_u16(&datagram[4..6]);
+            let seq = read_u32(&datagram[8..12]);
+
+            if len < HEADER_SIZE || len > datagram.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Truncated netlink message",
+                ));
+            }
+
+            let payload = &datagram[HEADER_SIZE..len];
+            datagram = &datagram[align(len).min(datagram.len())..];
+
+            if seq != self.seq {
+                continue;
+            }
+
+            match ty {
+                NLMSG_ERROR | NLMSG_DONE => {
+                    let errno = if payload.len() >= 4 {
+                        read_u32(&payload[0..4]) as i32
+                    } else {
+                        0
+                    };
+
+                    if errno < 0 {
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    }
+
+                    return Ok(true);
+                }
+
+                _ => self.payloads.push(payload.to_vec()),
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Get the payloads of the replies, after their netlink headers.
+    pub fn into_payloads(self) -> Vec<Vec<u8>> {
+        self.payloads
+    }
+}
+
+/// A netlink socket.
+#[derive(Debug)]
+pub struct Socket {
+    fd: RawFd,
+    pid: u32,
+    seq: u32,
+}
+
+impl Socket {
+    /// Open a netlink socket for the specified protocol, such as `libc::NETLINK_ROUTE`.
+    pub fn new(protocol: i32) -> io::Result<Socket> {
+        Socket::with_flags(protocol, 0)
+    }
+
+    /// Open a netlink socket, adding flags such as `libc::SOCK_NONBLOCK` to the socket type.
+    pub fn with_flags(protocol: i32, flags: i32) -> io::Result<Socket> {
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags,
+                protocol,
+            );
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut socket = Socket {
+                fd: fd,
+                pid: 0,
+                seq: 0,
+            };
+
+            let mut addr: libc::sockaddr_nl = mem::zeroed();
+            let mut addr_len = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+
+            if libc::bind(fd, &addr as *const _ as *const libc::sockaddr, addr_len) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if libc::getsockname(
+                fd,
+                &mut addr as *mut _ as *mut libc::sockaddr,
+                &mut addr_len,
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            socket.pid = addr.nl_pid;
+            socket.seq = addr.nl_pid ^ (std::process::id() << 8);
+
+            Ok(socket)
+        }
+    }
+
+    /// Prepare a request for sending: returns the bytes to send and the collector for its
+    /// replies. Requests that are not dumps ask for an acknowledgement.
+    pub fn prepare(&mut self, msg: &Message) -> (Vec<u8>, Replies) {
+        let mut msg = msg.clone();
+
+        if msg.flags() & NLM_F_DUMP != NLM_F_DUMP {
+            let flags = msg.flags() | NLM_F_ACK;
+            msg.buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+
+        (msg.finish(self.seq, self.pid), Replies::new(self.seq))
+    }
+
+    /// Send a request and wait for all of its replies.
+    pub fn request(&mut self, msg: &Message) -> io::Result<Vec<Vec<u8>>> {
+        let (bytes, mut replies) = self.prepare(msg);
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+
+        self.send(&bytes)?;
+
+        loop {
+            let len = self.recv(&mut buf)?;
+
+            if replies.feed(&buf[..len])? {
+                return Ok(replies.into_payloads());
+            }
+        }
+    }
+
+    /// Send a datagram to the kernel.
+    pub fn send(&self, bytes: &[u8]) -> io::Result<()> {
+        let sent = unsafe { libc::send(self.fd, bytes.as_ptr() as *const _, bytes.len(), 0) };
+
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Receive a datagram from the kernel.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(len as usize)
+    }
+
+    /// Resolve the ID of a generic netlink family from its name.
+    pub fn family_id(&mut self, name: &str) -> io::Result<u16> {
+        parse_family_id(name, &self.request(&family_request(name))?)
+    }
+}
+
+/// A non-blocking netlink socket driven by the tokio reactor.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct AsyncSocket {
+    inner: AsyncFd<Socket>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncSocket {
+    /// Open a netlink socket for the specified protocol and register it with the reactor of the
+    /// current runtime.
+    pub fn new(protocol: i32) -> io::Result<AsyncSocket> {
+        let socket = Socket::with_flags(protocol, libc::SOCK_NONBLOCK)?;
+
+        Ok(AsyncSocket {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Send a request and wait for all of its replies. Dropping the future abandons the request,
+    /// and replies to it that arrive later are ignored by the next requests.
+    pub async fn request(&mut self, msg: &Message) -> io::Result<Vec<Vec<u8>>> {
+        let (bytes, mut replies) = self.inner.get_mut().prepare(msg);
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+
+        self.inner
+            .async_io(Interest::WRITABLE, |socket| socket.send(&bytes))
+            .await?;
+
+        loop {
+            let len = self
+                .inner
+                .async_io(Interest::READABLE, |socket| socket.recv(&mut buf))
+                .await?;
+
+            if replies.feed(&buf[..len])? {
+                return Ok(replies.into_payloads());
+            }
+        }
+    }
+
+    /// Resolve the ID of a generic netlink family from its name.
+    pub async fn family_id(&mut self, name: &str) -> io::Result<u16> {
+        parse_family_id(name, &self.request(&family_request(name)).await?)
+    }
+}
+
+/// Build the request resolving a generic netlink family from its name.
+fn family_request(name: &str) -> Message {
+    let mut msg = Message::genl(GENL_ID_CTRL, 0, CTRL_CMD_GETFAMILY, 1);
+    msg.attr_str(CTRL_ATTR_FAMILY_NAME, name);
+    msg
+}
+
+/// Find the family ID in the replies to a family request.
+fn parse_family_id(name: &str, payloads: &[Vec<u8>]) -> io::Result<u16> {
+    for payload in payloads {
+        for (ty, data) in attrs(payload.get(GENL_HEADER_SIZE..).unwrap_or(&[])) {
+            if ty == CTRL_ATTR_FAMILY_ID {
+                if let Some(id) = attr_u16(data) {
+                    return Ok(id);
+                }
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Generic netlink family {} not found", name),
+    ))
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Iterator over the attributes contained in a buffer.
+#[derive(Debug, Clone)]
+pub struct Attrs<'a> {
+    buf: &'a [u8],
+}
+
+/// Iterate over the attributes contained in a buffer, yielding their type, without the nested
+/// and byte order flags, and their data.
+pub fn attrs(buf: &[u8]) -> Attrs<'_> {
+    Attrs { buf: buf }
+}
+
+impl<'a> Iterator for Attrs<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+
+        let len = read_u16(&self.buf[0..2]) as usize;
+        let ty = read_u16(&self.buf[2..4]) & NLA_TYPE_MASK;
+
+        if len < 4 || len > self.buf.len() {
+            self.buf = &[];
+            return None;
+        }
+
+        let data = &self.buf[4..len];
+        self.buf = &self.buf[align(len).min(self.buf.len())..];
+
+        Some((ty, data))
+    }
+}
+
+/// Read an 8-bit integer attribute.
+pub fn attr_u8(data: &[u8]) -> Option<u8> {
+    data.first().cloned()
+}
+
+/// Read a 16-bit integer attribute.
+pub fn attr_u16(data: &[u8]) -> Option<u16> {
+    data.get(0..2).map(read_u16)
+}
+
+/// Read a 32-bit integer attribute.
+pub fn attr_u32(data: &[u8]) -> Option<u32> {
+    data.get(0..4).map(read_u32)
+}
+
+/// Read a 64-bit integer attribute.
+pub fn attr_u64(data: &[u8]) -> Option<u64> {
+    data.get(0..8).map(read_u64)
+}
+
+/// Read a string attribute, stripping the terminating NUL byte.
+pub fn attr_str(data: &[u8]) -> Option<String> {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8(data[..end].to_vec()).ok()
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_ne_bytes([data[0], data[1]])
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_ne_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_ne_bytes(bytes)
+}
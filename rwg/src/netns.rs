@@ -0,0 +1,139 @@
+//! Network namespaces.
+//!
+//! A WireGuard device keeps its UDP socket in the namespace where it was created, even after
+//! being moved to another one. Creating a device in the init namespace and moving it into a
+//! container gives the container a tunnel whose encrypted traffic goes through the host.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use std::thread;
+
+use crate::backend::{Backend, System};
+use crate::device::Device;
+use crate::key::Key;
+use crate::netlink::{ifinfomsg, Message, Socket};
+use crate::netlink::{IFLA_IFNAME, RTM_SETLINK};
+
+const IFLA_NET_NS_FD: u16 = 28;
+
+/// Directory where `ip netns` keeps its named namespaces.
+pub const NETNS_DIR: &str = "/var/run/netns";
+
+/// A reference to a network namespace, held open as a file descriptor.
+#[derive(Debug)]
+pub struct Namespace {
+    file: File,
+}
+
+impl Namespace {
+    /// Open the namespace of the calling thread.
+    pub fn current() -> io::Result<Namespace> {
+        Namespace::from_path("/proc/thread-self/ns/net")
+    }
+
+    /// Open a namespace by path, such as `/proc/<pid>/ns/net` or a bind mount.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Namespace> {
+        Ok(Namespace {
+            file: File::open(path)?,
+        })
+    }
+
+    /// Open a namespace created by `ip netns add`.
+    pub fn named(name: &str) -> io::Result<Namespace> {
+        Namespace::from_path(Path::new(NETNS_DIR).join(name))
+    }
+
+    /// Take ownership of a file descriptor referring to a namespace.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor that is not owned by anything else.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Namespace {
+        Namespace {
+            file: File::from_raw_fd(fd),
+        }
+    }
+
+    /// Run `f` inside this namespace. It runs on a dedicated thread, so that the namespace of the
+    /// calling thread is left untouched. Sockets opened by `f` stay in this namespace.
+    pub fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send,
+        F: FnOnce() -> io::Result<T> + Send,
+    {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    self.enter()?;
+                    f()
+                })
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("namespace thread panicked")))
+        })
+    }
+
+    /// Move the calling thread into this namespace.
+    fn enter(&self) -> io::Result<()> {
+        match unsafe { libc::setns(self.file.as_raw_fd(), libc::CLONE_NEWNET) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl AsRawFd for Namespace {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for Namespace {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+impl Device {
+    /// Create a new kernel device in the current namespace and move it to `namespace`. Its UDP
+    /// socket stays in the current namespace.
+    pub fn create_in<S: Into<String>>(
+        name: S,
+        private_key: Option<Key>,
+        namespace: &Namespace,
+    ) -> io::Result<Device> {
+        Device::create_in_with(&System::new(), name, private_key, namespace)
+    }
+
+    /// Create a new device using the specified backend and move it to `namespace`.
+    pub fn create_in_with<B: Backend + ?Sized, S: Into<String>>(
+        backend: &B,
+        name: S,
+        private_key: Option<Key>,
+        namespace: &Namespace,
+    ) -> io::Result<Device> {
+        let device = Device::create_with(backend, name, private_key)?;
+
+        if let Err(err) = device.move_to(namespace) {
+            let _ = backend.delete(device.name());
+            return Err(err);
+        }
+
+        Ok(device)
+    }
+
+    /// Move the network interface of this device from the current namespace to `namespace`.
+    pub fn move_to(&self, namespace: &Namespace) -> io::Result<()> {
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        let mut msg = Message::new(RTM_SETLINK, 0);
+
+        msg.put(&ifinfomsg(0, 0, 0));
+        msg.attr_str(IFLA_IFNAME, self.name());
+        msg.attr_u32(IFLA_NET_NS_FD, namespace.as_raw_fd() as u32);
+
+        socket.request(&msg)?;
+
+        Ok(())
+    }
+}
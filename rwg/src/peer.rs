@@ -1,17 +1,17 @@
 //! WireGuard peer management.
 
-use std::mem;
-use std::net::IpAddr;
-use std::ptr;
-
-use libwg_sys as sys;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::SystemTime;
 
 use crate::key::Key;
-use crate::net;
+use crate::link::{Address, InvalidAddress};
+use crate::metadata::Metadata;
 
 /// A set of authorized IP addresses associated with a peer. Takes the form of a network address
 /// and a netmask.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AllowedIp {
     address: IpAddr,
     mask: u8,
@@ -26,64 +26,74 @@ impl AllowedIp {
         }
     }
 
-    /// Construct an `AllowedIp` object from a C library handle.
-    fn from_handle(h: *mut sys::wg_allowedip) -> AllowedIp {
-        let addr = unsafe {
-            match (*h).family as u32 {
-                sys::AF_INET => {
-                    let addr = net::read_ip4_from_in_addr(&(*h).__bindgen_anon_1.ip4);
-                    IpAddr::V4(addr)
-                }
+    /// Get the IP address.
+    pub fn addr(&self) -> &IpAddr {
+        &self.address
+    }
+
+    /// Get the network mask in CIDR form.
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
 
-                sys::AF_INET6 => {
-                    let addr = net::read_ip6_from_in6_addr(&(*h).__bindgen_anon_1.ip6);
-                    IpAddr::V6(addr)
-                }
+    /// Get the network this allowed IP designates, with all the host bits of the address cleared.
+    /// This is how the kernel stores allowed IPs.
+    pub fn network(&self) -> AllowedIp {
+        let address = match self.address {
+            IpAddr::V4(ip4) => {
+                let shift = 32u32.saturating_sub(self.mask as u32);
+                let bits = u32::from(ip4) & u32::MAX.checked_shl(shift).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(bits))
+            }
 
-                wtf => panic!("Got an invalid AF address family: {}", wtf),
+            IpAddr::V6(ip6) => {
+                let shift = 128u32.saturating_sub(self.mask as u32);
+                let bits = u128::from(ip6) & u128::MAX.checked_shl(shift).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(bits))
             }
         };
 
-        let mask = unsafe { (*h).cidr };
-
-        AllowedIp {
-            address: addr,
-            mask: mask,
-        }
+        AllowedIp::new(address, self.mask)
     }
 
-    /// Get the C library handle.
-    fn handle(&self) -> sys::wg_allowedip {
-        let mut allowed_ip = unsafe {
-            let mut allowed_ip: sys::wg_allowedip = mem::zeroed();
-
-            match self.address {
-                IpAddr::V4(ref ip4) => {
-                    allowed_ip.family = sys::AF_INET as u16;
-                    net::write_ip4_to_in_addr(ip4, &mut allowed_ip.__bindgen_anon_1.ip4);
-                }
-
-                IpAddr::V6(ref ip6) => {
-                    allowed_ip.family = sys::AF_INET6 as u16;
-                    net::write_ip6_to_in6_addr(ip6, &mut allowed_ip.__bindgen_anon_1.ip6);
-                }
-            };
+    /// Whether the network of this allowed IP contains an address.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        addr.is_ipv4() == self.address.is_ipv4()
+            && AllowedIp::new(*addr, self.mask).network() == self.network()
+    }
 
-            allowed_ip
-        };
+    /// Whether the networks of two allowed IPs have addresses in common.
+    pub fn overlaps(&self, other: &AllowedIp) -> bool {
+        if self.mask <= other.mask {
+            self.contains(&other.address)
+        } else {
+            other.contains(&self.address)
+        }
+    }
 
-        allowed_ip.cidr = self.mask;
-        allowed_ip
+    /// Whether this allowed IP designates a single address.
+    pub fn is_host(&self) -> bool {
+        match self.address {
+            IpAddr::V4(_) => self.mask >= 32,
+            IpAddr::V6(_) => self.mask >= 128,
+        }
     }
+}
 
-    /// Get the IP address.
-    pub fn addr(&self) -> &IpAddr {
-        &self.address
+impl fmt::Display for AllowedIp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.mask)
     }
+}
 
-    /// Get the network mask in CIDR form.
-    pub fn mask(&self) -> u8 {
-        self.mask
+impl FromStr for AllowedIp {
+    type Err = InvalidAddress;
+
+    /// Parse an allowed IP of the form `10.0.0.0/24`. Without a netmask, the allowed IP designates
+    /// a single address.
+    fn from_str(s: &str) -> Result<AllowedIp, InvalidAddress> {
+        let address = s.parse::<Address>()?;
+        Ok(AllowedIp::new(*address.addr(), address.prefix_len()))
     }
 }
 
@@ -95,8 +105,17 @@ pub type Endpoint = (IpAddr, u16);
 #[derive(Debug, Clone, PartialEq)]
 pub struct Peer {
     public_key: Option<Key>,
+    preshared_key: Option<Key>,
     endpoint: Option<Endpoint>,
+    persistent_keepalive: Option<u16>,
     allowed_ips: Vec<AllowedIp>,
+    last_handshake: Option<SystemTime>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    remove: bool,
+    update_only: bool,
+    replace_allowed_ips: bool,
+    metadata: Option<Metadata>,
 }
 
 impl Peer {
@@ -104,93 +123,84 @@ impl Peer {
     pub fn new(public_key: Key, endpoint: Option<Endpoint>) -> Peer {
         Peer {
             public_key: Some(public_key),
+            preshared_key: None,
             endpoint: endpoint,
+            persistent_keepalive: None,
             allowed_ips: Vec::new(),
+            last_handshake: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            remove: false,
+            update_only: false,
+            replace_allowed_ips: true,
+            metadata: None,
         }
     }
 
-    /// Construct a `Peer` object from a C library handle.
-    pub(super) fn from_handle(h: *mut sys::wg_peer) -> Peer {
-        let public_key = unsafe {
-            if (*h).flags & sys::wg_peer_flags_WGPEER_HAS_PUBLIC_KEY != 0 {
-                Some(Key::from_bytes((*h).public_key))
-            } else {
-                None
-            }
-        };
+    /// Set the IP address and port of this peer on the internet.
+    pub fn set_endpoint(&mut self, endpoint: Endpoint) {
+        self.endpoint.replace(endpoint);
+    }
 
-        let endpoint = unsafe { net::sockaddr_to_endpoint(&(*h).endpoint.addr) };
+    /// Set the preshared key used as an additional layer of symmetric encryption. A key of zero
+    /// bytes removes the preshared key of the peer when saved.
+    pub fn set_preshared_key(&mut self, key: Key) {
+        self.preshared_key.replace(key);
+    }
 
-        let allowed_ips = unsafe {
-            let mut ips = Vec::new();
-            let mut ip = (*h).first_allowedip;
+    /// Set the interval in seconds at which keepalive packets are sent to this peer. An interval
+    /// of zero disables persistent keepalives, and clears the interval of the peer when saved.
+    pub fn set_persistent_keepalive(&mut self, interval: u16) {
+        self.persistent_keepalive = Some(interval);
+    }
 
-            while ip != ptr::null_mut() {
-                ips.push(AllowedIp::from_handle(ip));
-                ip = (*ip).next_allowedip;
-            }
+    /// Forget the preshared key, as peers without one are read.
+    pub(crate) fn clear_preshared_key(&mut self) {
+        self.preshared_key = None;
+    }
 
-            ips
-        };
+    /// Forget the persistent keepalive interval, as peers without one are read.
+    pub(crate) fn clear_persistent_keepalive(&mut self) {
+        self.persistent_keepalive = None;
+    }
 
-        Peer {
-            public_key: public_key,
-            endpoint: endpoint,
-            allowed_ips: allowed_ips,
-        }
+    /// Mark this peer for removal from its device when the device is saved.
+    pub fn set_remove(&mut self, remove: bool) {
+        self.remove = remove;
     }
 
-    /// Get the C library handle for this peer.
-    pub(super) fn handle(&self) -> Handle {
-        unsafe {
-            let mut h: sys::wg_peer = mem::zeroed();
+    /// Only apply the changes to this peer if it already exists on the device, instead of
+    /// creating it.
+    pub fn set_update_only(&mut self, update_only: bool) {
+        self.update_only = update_only;
+    }
 
-            if let Some(ref key) = self.public_key {
-                h.flags |= sys::wg_peer_flags_WGPEER_HAS_PUBLIC_KEY;
-                h.public_key.copy_from_slice(key.as_bytes());
-            }
+    /// Choose whether the allowed IPs of this peer replace the existing ones when the device is
+    /// saved, or are appended to them. Defaults to `true`.
+    pub fn set_replace_allowed_ips(&mut self, replace: bool) {
+        self.replace_allowed_ips = replace;
+    }
 
-            if let Some(ref endpoint) = self.endpoint {
-                net::endpoint_to_sockaddr(endpoint, &mut h.endpoint.addr);
-            } else {
-                h.endpoint = mem::zeroed();
-            }
+    /// Attach metadata to this peer. It is kept in configuration files, but never sent to the
+    /// device.
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = Some(metadata);
+    }
 
-            h.flags |= sys::wg_peer_flags_WGPEER_REPLACE_ALLOWEDIPS;
-
-            let mut allowed_ips = self
-                .allowed_ips
-                .iter()
-                .map(|ip| ip.handle())
-                .collect::<Vec<_>>();
-
-            let allowed_ips_len = allowed_ips.len();
-            let allowed_ips_ptr = allowed_ips.as_mut_ptr();
-
-            if !self.allowed_ips.is_empty() {
-                for (i, ip) in allowed_ips.iter_mut().enumerate() {
-                    if i + 1 < allowed_ips_len {
-                        ip.next_allowedip = allowed_ips_ptr.add(i + 1);
-                    }
-                }
-
-                h.first_allowedip = allowed_ips_ptr;
-                h.last_allowedip = allowed_ips_ptr.add(allowed_ips_len - 1);
-            } else {
-                h.first_allowedip = ptr::null_mut();
-                h.last_allowedip = ptr::null_mut();
-            }
+    /// Set the public key identifying this peer.
+    pub(crate) fn set_public_key(&mut self, key: Key) {
+        self.public_key = Some(key);
+    }
 
-            Handle {
-                handle: h,
-                allowed_ips: allowed_ips,
-            }
-        }
+    /// Set the time of the latest handshake with this peer.
+    pub(crate) fn set_last_handshake(&mut self, time: Option<SystemTime>) {
+        self.last_handshake = time;
     }
 
-    /// Set the IP address and port of this peer on the internet.
-    pub fn set_endpoint(&mut self, endpoint: Endpoint) {
-        self.endpoint.replace(endpoint);
+    /// Set the number of bytes received from and transmitted to this peer.
+    pub(crate) fn set_transfer(&mut self, rx_bytes: u64, tx_bytes: u64) {
+        self.rx_bytes = rx_bytes;
+        self.tx_bytes = tx_bytes;
     }
 
     /// Add a new allowed IP to this peer.
@@ -203,11 +213,52 @@ impl Peer {
         self.public_key.as_ref()
     }
 
+    /// Get the preshared key of this peer, if any.
+    pub fn preshared_key(&self) -> Option<&Key> {
+        self.preshared_key.as_ref()
+    }
+
     /// Get the internet endpoint of this peer.
     pub fn endpoint(&self) -> Option<&Endpoint> {
         self.endpoint.as_ref()
     }
 
+    /// Get the persistent keepalive interval in seconds, if it has been set. Peers read from the
+    /// kernel have no interval rather than an interval of zero.
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+
+    /// Get the time of the latest handshake with this peer, if one ever happened.
+    pub fn last_handshake(&self) -> Option<SystemTime> {
+        self.last_handshake
+    }
+
+    /// Get the number of bytes received from this peer.
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx_bytes
+    }
+
+    /// Get the number of bytes transmitted to this peer.
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes
+    }
+
+    /// Whether this peer is marked for removal.
+    pub fn remove(&self) -> bool {
+        self.remove
+    }
+
+    /// Whether changes to this peer only apply if it already exists.
+    pub fn update_only(&self) -> bool {
+        self.update_only
+    }
+
+    /// Whether the allowed IPs of this peer replace the existing ones.
+    pub fn replace_allowed_ips(&self) -> bool {
+        self.replace_allowed_ips
+    }
+
     /// Get the list of allowed IPs.
     pub fn allowed_ips(&self) -> &[AllowedIp] {
         self.allowed_ips.as_ref()
@@ -217,10 +268,15 @@ impl Peer {
     pub fn allowed_ips_mut(&mut self) -> &mut Vec<AllowedIp> {
         &mut self.allowed_ips
     }
-}
 
-/// Handle to a peer for the C library.
-pub(super) struct Handle {
-    pub handle: sys::wg_peer,
-    pub allowed_ips: Vec<sys::wg_allowedip>,
+    /// Get the metadata attached to this peer, if any.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Get a mutable reference to the metadata of this peer, attaching empty metadata if there
+    /// was none.
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        self.metadata.get_or_insert_with(Metadata::new)
+    }
 }
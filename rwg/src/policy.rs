@@ -0,0 +1,272 @@
+//! Policy routing sending all the traffic through a device, the way wg-quick does with
+//! `Table = auto`.
+//!
+//! Default routes are installed in a dedicated table, named after the firewall mark of the
+//! device. Packets without the mark are looked up in that table, while the encrypted packets
+//! sent by the device carry the mark and keep using the main table. A rule suppressing the
+//! default route of the main table lets more specific routes take precedence.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+
+use crate::backend::{Backend, System};
+use crate::device::Device;
+use crate::netlink::{self, Message, Socket};
+use crate::peer::AllowedIp;
+use crate::route::{self, MAIN_TABLE, RTMSG_SIZE};
+
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+const RTM_GETRULE: u16 = 34;
+
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_IFGROUP: u16 = 13;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FRA_FWMASK: u16 = 16;
+const FRA_PAD: u16 = 18;
+const FRA_PROTOCOL: u16 = 21;
+
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 0x2;
+
+/// Table and firewall mark used for full-tunnel routing when the device has no firewall mark.
+pub const FULL_TUNNEL_TABLE: u32 = 51820;
+
+/// A routing policy rule, as used for full-tunnel routing.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Rule {
+    pub family: u8,
+    pub table: u32,
+    pub not_fwmark: Option<u32>,
+    pub suppress_prefix_len: Option<u32>,
+    /// Whether the rule has selectors other than the firewall mark.
+    pub selective: bool,
+}
+
+impl Rule {
+    /// Rule sending the packets without the mark of the device to its table.
+    fn not_fwmark(family: u8, table: u32) -> Rule {
+        Rule {
+            family: family,
+            table: table,
+            not_fwmark: Some(table),
+            suppress_prefix_len: None,
+            selective: false,
+        }
+    }
+
+    /// Rule looking up the main table, but ignoring its default route.
+    fn suppress_default(family: u8) -> Rule {
+        Rule {
+            family: family,
+            table: MAIN_TABLE,
+            not_fwmark: None,
+            suppress_prefix_len: Some(0),
+            selective: false,
+        }
+    }
+}
+
+impl Device {
+    /// Send all the traffic of the families for which a peer of this device is allowed a default
+    /// route through the device. The firewall mark of the device is used as the routing table,
+    /// and a free table is picked and set as the mark if the device has none. Returns the table.
+    /// Calling this again on a configured device changes nothing.
+    pub fn enable_full_tunnel(&mut self) -> io::Result<u32> {
+        self.enable_full_tunnel_with(&System::new())
+    }
+
+    /// Send all the traffic through this device, setting the firewall mark with the specified
+    /// backend if needed.
+    pub fn enable_full_tunnel_with<B: Backend + ?Sized>(&mut self, backend: &B) -> io::Result<u32> {
+        let table = match self.fwmark().filter(|fwmark| *fwmark > 0) {
+            Some(fwmark) => fwmark,
+
+            None => {
+                let table = free_table()?;
+                let mut changes = Device::new(self.name());
+
+                changes.set_fwmark(table);
+                changes.set_replace_peers(false);
+                backend.set(&changes)?;
+
+                self.set_fwmark(table);
+                table
+            }
+        };
+
+        let rules = dump_rules()?;
+        let installed = self.installed_routes(table)?;
+
+        for default in full_tunnel_routes(self) {
+            let family = family(&default);
+
+            if !installed.contains(&default.network()) {
+                self.add_route(&default, table)?;
+            }
+
+            for rule in &[
+                Rule::not_fwmark(family, table),
+                Rule::suppress_default(family),
+            ] {
+                if !rules.contains(rule) {
+                    rule_request(RTM_NEWRULE, netlink::NLM_F_CREATE, rule)?;
+                }
+            }
+
+            if family == libc::AF_INET as u8 {
+                fs::write("/proc/sys/net/ipv4/conf/all/src_valid_mark", "1")?;
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Undo `enable_full_tunnel`: remove the policy rules and the routes of the table of this
+    /// device. Rules and routes that are already gone are ignored.
+    pub fn disable_full_tunnel(&self) -> io::Result<()> {
+        let table = match self.fwmark().filter(|fwmark| *fwmark > 0) {
+            Some(fwmark) => fwmark,
+            None => return Ok(()),
+        };
+
+        for rule in dump_rules()? {
+            let ours = rule == Rule::not_fwmark(rule.family, table)
+                || rule == Rule::suppress_default(rule.family);
+
+            if ours {
+                rule_request(RTM_DELRULE, 0, &rule)?;
+            }
+        }
+
+        self.flush_routes(table)
+    }
+}
+
+/// Get the default routes implied by the allowed IPs of the peers of a device.
+pub(crate) fn full_tunnel_routes(device: &Device) -> Vec<AllowedIp> {
+    let mut routes = device
+        .routes()
+        .into_iter()
+        .filter(|ip| ip.mask() == 0)
+        .collect::<Vec<_>>();
+
+    routes.sort();
+    routes
+}
+
+/// Get the address family of an allowed IP.
+fn family(ip: &AllowedIp) -> u8 {
+    match ip.addr() {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+/// Find the first table from `FULL_TUNNEL_TABLE` that holds no route.
+fn free_table() -> io::Result<u32> {
+    let used = route::dump_routes()?
+        .into_iter()
+        .map(|route| route.table)
+        .collect::<BTreeSet<_>>();
+
+    Ok((FULL_TUNNEL_TABLE..)
+        .find(|table| !used.contains(table))
+        .unwrap_or(FULL_TUNNEL_TABLE))
+}
+
+/// List the policy rules of both families.
+fn dump_rules() -> io::Result<Vec<Rule>> {
+    let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+    let mut msg = Message::new(RTM_GETRULE, netlink::NLM_F_DUMP);
+
+    msg.put(&[0u8; RTMSG_SIZE]);
+
+    Ok(socket
+        .request(&msg)?
+        .iter()
+        .filter_map(|payload| parse_rule(payload))
+        .collect())
+}
+
+/// Send a rule request.
+fn rule_request(ty: u16, flags: u16, rule: &Rule) -> io::Result<()> {
+    let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+    let mut msg = Message::new(ty, flags);
+    let mut header = [0u8; RTMSG_SIZE];
+
+    header[0] = rule.family;
+    header[4] = if rule.table < 256 {
+        rule.table as u8
+    } else {
+        0
+    };
+    header[7] = FR_ACT_TO_TBL;
+
+    if rule.not_fwmark.is_some() {
+        header[8..12].copy_from_slice(&FIB_RULE_INVERT.to_ne_bytes());
+    }
+
+    msg.put(&header);
+    msg.attr_u32(FRA_TABLE, rule.table);
+
+    if let Some(fwmark) = rule.not_fwmark {
+        msg.attr_u32(FRA_FWMARK, fwmark);
+    }
+
+    if let Some(prefix_len) = rule.suppress_prefix_len {
+        msg.attr_u32(FRA_SUPPRESS_PREFIXLEN, prefix_len);
+    }
+
+    socket.request(&msg)?;
+
+    Ok(())
+}
+
+/// Parse the payload of a `RTM_NEWRULE` message. Only the rules sending packets to a table are
+/// considered, and only the fields used for full-tunnel routing are kept.
+pub(crate) fn parse_rule(payload: &[u8]) -> Option<Rule> {
+    let header = payload.get(..RTMSG_SIZE)?;
+
+    if header[7] != FR_ACT_TO_TBL {
+        return None;
+    }
+
+    let flags = netlink::attr_u32(&header[8..12])?;
+
+    let mut rule = Rule {
+        family: header[0],
+        table: header[4] as u32,
+        not_fwmark: None,
+        suppress_prefix_len: None,
+        selective: header[1] != 0 || header[2] != 0 || header[3] != 0,
+    };
+
+    let mut fwmark = None;
+
+    for (ty, data) in netlink::attrs(&payload[RTMSG_SIZE..]) {
+        // The kernel reports -1 for the masks and suppressors that are not in use.
+        let value = netlink::attr_u32(data).filter(|value| *value != u32::MAX);
+
+        match ty {
+            FRA_TABLE => rule.table = netlink::attr_u32(data)?,
+            FRA_FWMARK => fwmark = value,
+            FRA_SUPPRESS_PREFIXLEN => rule.suppress_prefix_len = value,
+            FRA_FWMASK | FRA_SUPPRESS_IFGROUP => rule.selective |= value.is_some(),
+            FRA_PRIORITY | FRA_PAD | FRA_PROTOCOL => {}
+            _ => rule.selective = true,
+        }
+    }
+
+    match (fwmark, flags & FIB_RULE_INVERT != 0) {
+        (Some(fwmark), true) => rule.not_fwmark = Some(fwmark),
+        (None, false) => {}
+        _ => rule.selective = true,
+    }
+
+    Some(rule)
+}
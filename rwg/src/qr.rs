@@ -0,0 +1,234 @@
+//! QR codes of configurations, as imported by the mobile WireGuard apps.
+//!
+//! A `Code` is rendered for terminals with UTF-8 half blocks, or as an SVG or PNG image. All the
+//! renderings surround the code with the quiet zone of four modules required by scanners.
+
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use qrcodegen::{QrCode, QrCodeEcc, QrSegment, Version};
+
+use crate::config::{self, Config};
+
+/// Width of the light border around the codes, in modules.
+pub const QUIET_ZONE: usize = 4;
+
+/// How much of a code can be damaged and still be read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EcLevel {
+    /// About 7% of the code.
+    Low,
+    /// About 15% of the code.
+    #[default]
+    Medium,
+    /// About 25% of the code.
+    Quartile,
+    /// About 30% of the code.
+    High,
+}
+
+impl fmt::Display for EcLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EcLevel::Low => write!(f, "L"),
+            EcLevel::Medium => write!(f, "M"),
+            EcLevel::Quartile => write!(f, "Q"),
+            EcLevel::High => write!(f, "H"),
+        }
+    }
+}
+
+impl FromStr for EcLevel {
+    type Err = ();
+
+    /// Parse a level from its letter, `L`, `M`, `Q` or `H`, in any case.
+    fn from_str(s: &str) -> Result<EcLevel, ()> {
+        match s.to_uppercase().as_str() {
+            "L" => Ok(EcLevel::Low),
+            "M" => Ok(EcLevel::Medium),
+            "Q" => Ok(EcLevel::Quartile),
+            "H" => Ok(EcLevel::High),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A QR code: a square of dark and light modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl Code {
+    /// Encode text at the given error correction level, in a single code of any size.
+    pub fn encode(text: &str, level: EcLevel) -> Result<Code, TooLarge> {
+        let ecl = match level {
+            EcLevel::Low => QrCodeEcc::Low,
+            EcLevel::Medium => QrCodeEcc::Medium,
+            EcLevel::Quartile => QrCodeEcc::Quartile,
+            EcLevel::High => QrCodeEcc::High,
+        };
+
+        let segments = QrSegment::make_segments(text);
+        let code = QrCode::encode_segments_advanced(
+            &segments,
+            ecl,
+            Version::MIN,
+            Version::MAX,
+            None,
+            false,
+        )
+        .map_err(|_| TooLarge {
+            len: text.len(),
+            level: level,
+        })?;
+
+        let size = code.size() as usize;
+        let modules = (0..size * size)
+            .map(|i| code.get_module((i % size) as i32, (i / size) as i32))
+            .collect();
+
+        Ok(Code {
+            size: size,
+            modules: modules,
+        })
+    }
+
+    /// Encode a wg-quick configuration, such as one made by `client::Generator`.
+    pub fn from_config(config: &Config, level: EcLevel) -> Result<Code, TooLarge> {
+        let mut buf = Vec::new();
+        config::write(config, &mut buf).expect("writing to memory failed");
+
+        Code::encode(&String::from_utf8_lossy(&buf), level)
+    }
+
+    /// Get the number of modules on a side of the code, without the quiet zone.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module at the given column and row is dark. The quiet zone is not included,
+    /// and modules outside of the code are light.
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    /// Render the code with UTF-8 half blocks, two rows of modules per line. Light modules are
+    /// drawn, so that the code reads correctly on terminals with a dark background.
+    pub fn to_terminal(&self) -> String {
+        let side = self.size + 2 * QUIET_ZONE;
+        let mut buf = String::new();
+
+        for y in (0..side).step_by(2) {
+            for x in 0..side {
+                let top = !self.is_dark_padded(x, y);
+                let bottom = y + 1 < side && !self.is_dark_padded(x, y + 1);
+
+                buf.push(match (top, bottom) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+
+            buf.push('\n');
+        }
+
+        buf
+    }
+
+    /// Render the code as an SVG image, one unit per module.
+    pub fn to_svg(&self) -> String {
+        let side = self.size + 2 * QUIET_ZONE;
+        let mut path = String::new();
+
+        for y in 0..self.size {
+            for x in (0..self.size).filter(|&x| self.is_dark(x, y)) {
+                if !path.is_empty() {
+                    path.push(' ');
+                }
+
+                path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+            }
+        }
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" viewBox=\"0 0 {0} {0}\" \
+             shape-rendering=\"crispEdges\">\n\
+             <rect width=\"100%\" height=\"100%\" fill=\"#ffffff\"/>\n\
+             <path d=\"{1}\" fill=\"#000000\"/>\n\
+             </svg>\n",
+            side, path
+        )
+    }
+
+    /// Render the code as a black and white PNG image, `scale` pixels wide per module.
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let scale = scale.max(1);
+        let width = (self.size + 2 * QUIET_ZONE) * scale;
+        let stride = width.div_ceil(8);
+
+        // One bit per pixel, set for white.
+        let mut pixels = vec![0u8; stride * width];
+
+        for y in 0..width {
+            for x in 0..width {
+                if !self.is_dark_padded(x / scale, y / scale) {
+                    pixels[y * stride + x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width as u32, width as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .expect("encoding to memory failed");
+
+        png
+    }
+
+    /// Whether a module is dark, in coordinates including the quiet zone.
+    fn is_dark_padded(&self, x: usize, y: usize) -> bool {
+        x >= QUIET_ZONE && y >= QUIET_ZONE && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE)
+    }
+}
+
+/// The text to encode does not fit in a single QR code at the requested error correction level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLarge {
+    len: usize,
+    level: EcLevel,
+}
+
+impl TooLarge {
+    /// Get the length of the text, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.len
+    }
+
+    /// Get the requested error correction level.
+    pub fn level(&self) -> EcLevel {
+        self.level
+    }
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes do not fit in a QR code with error correction level {}",
+            self.len, self.level
+        )
+    }
+}
+
+impl error::Error for TooLarge {}
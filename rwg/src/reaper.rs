@@ -0,0 +1,245 @@
+//! Automatic removal of stale and expired peers.
+//!
+//! A `Reaper` removes the peers of a device whose latest handshake is too old, or whose expiry
+//! time has passed. Expiry times are set on the reaper, or persisted as the `ExpiresAt` metadata of
+//! the peers, which `metadata::Store` attaches to the devices read from the kernel. Peers are
+//! removed one by one, without replacing the other peers, so that changes made concurrently are
+//! kept.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use crate::backend::{Backend, System};
+use crate::device::Device;
+use crate::key::Key;
+use crate::metadata::Metadata;
+use crate::peer::Peer;
+use crate::show;
+
+/// Why a peer is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// Its expiry time, held here, passed.
+    Expired(SystemTime),
+    /// Its latest handshake, held here, is older than the threshold.
+    Stale(SystemTime),
+}
+
+/// A peer removed, or to be removed in a dry run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Removal {
+    peer: Peer,
+    reason: Reason,
+}
+
+impl Removal {
+    /// Get the peer, as it was before its removal.
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// Get why the peer is removed.
+    pub fn reason(&self) -> Reason {
+        self.reason
+    }
+}
+
+/// What a reaper removed from a device, at some point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    device: String,
+    time: SystemTime,
+    dry_run: bool,
+    removals: Vec<Removal>,
+}
+
+impl Report {
+    /// Get the name of the device.
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// Whether nothing was actually removed.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Get the removed peers.
+    pub fn removals(&self) -> &[Removal] {
+        &self.removals
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run {
+            "would remove"
+        } else {
+            "removed"
+        };
+
+        for removal in &self.removals {
+            let key = removal
+                .peer
+                .public_key()
+                .map(|key| key.to_base64())
+                .unwrap_or_default();
+
+            let (what, time) = match removal.reason {
+                Reason::Expired(time) => ("expired", time),
+                Reason::Stale(time) => ("latest handshake", time),
+            };
+
+            let ago = self.time.duration_since(time).unwrap_or_default();
+
+            writeln!(
+                f,
+                "{}: {} peer {}: {} {} ago",
+                self.device,
+                verb,
+                key,
+                what,
+                show::format_duration(ago)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes stale and expired peers.
+///
+/// Peers that never completed a handshake are not stale, since they may just have been added:
+/// give them an expiry time to have them removed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reaper {
+    stale_after: Option<Duration>,
+    expiries: HashMap<Key, SystemTime>,
+    dry_run: bool,
+}
+
+impl Reaper {
+    /// Create a reaper that removes nothing until configured.
+    pub fn new() -> Reaper {
+        Reaper::default()
+    }
+
+    /// Remove the peers whose latest handshake is older than `stale_after`.
+    pub fn set_stale_after(&mut self, stale_after: Duration) {
+        self.stale_after = Some(stale_after);
+    }
+
+    /// Set the time after which a peer is removed, instead of the one in its metadata. The time
+    /// is only kept in memory: use `Metadata::set_expires_at` to persist it.
+    pub fn set_expiry(&mut self, key: Key, time: SystemTime) {
+        self.expiries.insert(key, time);
+    }
+
+    /// Remove the expiry time of a peer.
+    pub fn remove_expiry(&mut self, key: &Key) {
+        self.expiries.remove(key);
+    }
+
+    /// Choose whether to only report what would be removed. Defaults to `false`.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Get the age after which a handshake makes a peer stale, if any.
+    pub fn stale_after(&self) -> Option<Duration> {
+        self.stale_after
+    }
+
+    /// Get the expiry time of a peer set on this reaper, if any.
+    pub fn expiry(&self, key: &Key) -> Option<SystemTime> {
+        self.expiries.get(key).cloned()
+    }
+
+    /// Whether only what would be removed is reported.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Find the peers of a device to remove at the time `now`.
+    pub fn plan(&self, device: &Device, now: SystemTime) -> Vec<Removal> {
+        device
+            .peers()
+            .iter()
+            .filter_map(|peer| {
+                let reason = self.reason(peer, now)?;
+
+                Some(Removal {
+                    peer: peer.clone(),
+                    reason: reason,
+                })
+            })
+            .collect()
+    }
+
+    /// Remove the stale and expired peers of a device at the time `now`, unless running dry. The
+    /// expiry times of the removed peers are forgotten.
+    pub fn reap(&mut self, device: &Device, now: SystemTime) -> io::Result<Report> {
+        self.reap_with(&System::new(), device, now)
+    }
+
+    /// Remove the stale and expired peers of a device using the specified backend.
+    pub fn reap_with<B: Backend + ?Sized>(
+        &mut self,
+        backend: &B,
+        device: &Device,
+        now: SystemTime,
+    ) -> io::Result<Report> {
+        let removals = self.plan(device, now);
+
+        if !self.dry_run && !removals.is_empty() {
+            let mut changes = Device::new(device.name());
+            changes.set_replace_peers(false);
+
+            for removal in &removals {
+                if let Some(key) = removal.peer.public_key() {
+                    let mut peer = Peer::new(key.clone(), None);
+                    peer.set_remove(true);
+
+                    changes.add_peer(peer);
+                }
+            }
+
+            changes.save_with(backend)?;
+
+            for removal in &removals {
+                if let Some(key) = removal.peer.public_key() {
+                    self.expiries.remove(key);
+                }
+            }
+        }
+
+        Ok(Report {
+            device: device.name().to_owned(),
+            time: now,
+            dry_run: self.dry_run,
+            removals: removals,
+        })
+    }
+
+    /// Tell why a peer must be removed at the time `now`, if it must.
+    fn reason(&self, peer: &Peer, now: SystemTime) -> Option<Reason> {
+        let expiry = peer
+            .public_key()
+            .and_then(|key| self.expiry(key))
+            .or_else(|| peer.metadata().and_then(Metadata::expires_at));
+
+        if let Some(time) = expiry.filter(|time| *time <= now) {
+            return Some(Reason::Expired(time));
+        }
+
+        let (stale_after, last) = (self.stale_after?, peer.last_handshake()?);
+
+        if now.duration_since(last).unwrap_or_default() > stale_after {
+            Some(Reason::Stale(last))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Routes to the allowed IPs of the peers of a device.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::device::Device;
+use crate::netlink::{self, Message, Socket};
+use crate::peer::AllowedIp;
+
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_TABLE: u16 = 15;
+
+const RT_TABLE_COMPAT: u8 = 252;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+
+/// Protocol of the routes installed by rwg, unused by the kernel and routing daemons. Routes of
+/// other protocols, like the ones added with `ip route` or by the kernel for interface addresses,
+/// are left alone.
+pub(crate) const RTPROT_RWG: u8 = 87;
+
+/// Size of `struct rtmsg`, which precedes the attributes of route messages.
+pub(crate) const RTMSG_SIZE: usize = 12;
+
+/// The main routing table, the one consulted when no policy rule applies.
+pub const MAIN_TABLE: u32 = 254;
+
+/// A route through the interface of a device, as read from the kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Route {
+    pub index: u32,
+    pub table: u32,
+    pub protocol: u8,
+    pub kind: u8,
+    pub destination: AllowedIp,
+}
+
+impl Device {
+    /// Get the routes implied by the allowed IPs of the peers of this device, without duplicates.
+    pub fn routes(&self) -> Vec<AllowedIp> {
+        let mut routes = Vec::new();
+
+        for ip in self.peers().iter().flat_map(|peer| peer.allowed_ips()) {
+            let network = ip.network();
+
+            if !routes.contains(&network) {
+                routes.push(network);
+            }
+        }
+
+        routes
+    }
+
+    /// List the routes installed by rwg through the interface of this device, in `table`.
+    pub fn installed_routes(&self, table: u32) -> io::Result<Vec<AllowedIp>> {
+        Ok(installed(&dump_routes()?, self.index()?, table))
+    }
+
+    /// Route `destination` through the interface of this device, in `table`. Fails with
+    /// `AlreadyExists` if that table already has a route to the same destination, which is left
+    /// alone.
+    pub fn add_route(&self, destination: &AllowedIp, table: u32) -> io::Result<()> {
+        let flags = netlink::NLM_F_CREATE | netlink::NLM_F_EXCL;
+
+        match self.route_request(RTM_NEWROUTE, flags, destination, table) {
+            Err(ref err) if err.raw_os_error() == Some(libc::EEXIST) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "A route to {} already exists in table {}",
+                    destination.network(),
+                    table
+                ),
+            )),
+
+            result => result,
+        }
+    }
+
+    /// Remove the route to `destination` through the interface of this device, in `table`.
+    pub fn remove_route(&self, destination: &AllowedIp, table: u32) -> io::Result<()> {
+        self.route_request(RTM_DELROUTE, 0, destination, table)
+    }
+
+    /// Make the routes through the interface of this device in `table` match the allowed IPs of its
+    /// peers: missing routes are added and routes to networks no peer is allowed anymore are
+    /// removed. The device must hold its full configuration, as returned by `Device::open`.
+    pub fn sync_routes(&self, table: u32) -> io::Result<()> {
+        self.reconcile_routes(&self.routes(), table)
+    }
+
+    /// Make the routes through the interface of this device in `table` match `routes`.
+    pub fn reconcile_routes(&self, routes: &[AllowedIp], table: u32) -> io::Result<()> {
+        let (add, remove) = reconcile(routes, &self.installed_routes(table)?);
+
+        for destination in &remove {
+            self.remove_route(destination, table)?;
+        }
+
+        for destination in &add {
+            self.add_route(destination, table)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove all the routes installed by rwg through the interface of this device, in `table`.
+    pub fn flush_routes(&self, table: u32) -> io::Result<()> {
+        for destination in self.installed_routes(table)? {
+            self.remove_route(&destination, table)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a route request for the interface.
+    fn route_request(
+        &self,
+        ty: u16,
+        flags: u16,
+        destination: &AllowedIp,
+        table: u32,
+    ) -> io::Result<()> {
+        let index = self.index()?;
+        let destination = destination.network();
+        let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+        let mut msg = Message::new(ty, flags);
+
+        let (family, octets) = match destination.addr() {
+            IpAddr::V4(ip4) => (libc::AF_INET, ip4.octets().to_vec()),
+            IpAddr::V6(ip6) => (libc::AF_INET6, ip6.octets().to_vec()),
+        };
+
+        msg.put(&rtmsg(
+            family as u8,
+            destination.mask(),
+            table,
+            RTPROT_RWG,
+            RTN_UNICAST,
+        ));
+        msg.attr(RTA_DST, &octets);
+        msg.attr_u32(RTA_OIF, index);
+        msg.attr_u32(RTA_TABLE, table);
+
+        socket.request(&msg)?;
+
+        Ok(())
+    }
+}
+
+/// List the routes of all the tables.
+pub(crate) fn dump_routes() -> io::Result<Vec<Route>> {
+    let mut socket = Socket::new(libc::NETLINK_ROUTE)?;
+    let mut msg = Message::new(RTM_GETROUTE, netlink::NLM_F_DUMP);
+
+    msg.put(&rtmsg(libc::AF_UNSPEC as u8, 0, 0, 0, 0));
+
+    Ok(socket
+        .request(&msg)?
+        .iter()
+        .filter_map(|payload| parse_route(payload))
+        .collect())
+}
+
+/// Get the destinations of the routes installed by rwg among `routes`, through the interface of
+/// index `index`, in `table`.
+pub(crate) fn installed(routes: &[Route], index: u32, table: u32) -> Vec<AllowedIp> {
+    routes
+        .iter()
+        .filter(|route| route.index == index && route.table == table)
+        .filter(|route| route.protocol == RTPROT_RWG && route.kind == RTN_UNICAST)
+        .map(|route| route.destination.clone())
+        .collect()
+}
+
+/// Compute the routes to add and to remove to go from the `installed` routes to the `desired` ones.
+pub(crate) fn reconcile(
+    desired: &[AllowedIp],
+    installed: &[AllowedIp],
+) -> (Vec<AllowedIp>, Vec<AllowedIp>) {
+    let desired = desired
+        .iter()
+        .map(|ip| ip.network())
+        .collect::<BTreeSet<_>>();
+    let installed = installed
+        .iter()
+        .map(|ip| ip.network())
+        .collect::<BTreeSet<_>>();
+
+    (
+        desired.difference(&installed).cloned().collect(),
+        installed.difference(&desired).cloned().collect(),
+    )
+}
+
+/// Build a `struct rtmsg`. Tables that do not fit in the header are carried by `RTA_TABLE`.
+fn rtmsg(family: u8, dst_len: u8, table: u32, protocol: u8, kind: u8) -> [u8; RTMSG_SIZE] {
+    let mut header = [0u8; RTMSG_SIZE];
+
+    header[0] = family;
+    header[1] = dst_len;
+    header[4] = if table < 256 {
+        table as u8
+    } else {
+        RT_TABLE_COMPAT
+    };
+    header[5] = protocol;
+
+    header[6] = if kind == RTN_UNICAST {
+        RT_SCOPE_LINK
+    } else {
+        RT_SCOPE_UNIVERSE
+    };
+
+    header[7] = kind;
+
+    header
+}
+
+/// Parse the payload of a `RTM_NEWROUTE` message.
+pub(crate) fn parse_route(payload: &[u8]) -> Option<Route> {
+    let header = payload.get(..RTMSG_SIZE)?;
+
+    let unspecified = match header[0] as i32 {
+        libc::AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        libc::AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => return None,
+    };
+
+    let mut route = Route {
+        index: 0,
+        table: header[4] as u32,
+        protocol: header[5],
+        kind: header[7],
+        destination: AllowedIp::new(unspecified, header[1]),
+    };
+
+    for (ty, data) in netlink::attrs(&payload[RTMSG_SIZE..]) {
+        match ty {
+            RTA_DST => {
+                let addr = match data.len() {
+                    4 => {
+                        let mut octets = [0u8; 4];
+                        octets.copy_from_slice(data);
+                        IpAddr::V4(Ipv4Addr::from(octets))
+                    }
+
+                    16 => {
+                        let mut octets = [0u8; 16];
+                        octets.copy_from_slice(data);
+                        IpAddr::V6(Ipv6Addr::from(octets))
+                    }
+
+                    _ => return None,
+                };
+
+                route.destination = AllowedIp::new(addr, header[1]);
+            }
+
+            RTA_OIF => route.index = netlink::attr_u32(data)?,
+            RTA_TABLE => route.table = netlink::attr_u32(data)?,
+            _ => {}
+        }
+    }
+
+    Some(route)
+}
@@ -0,0 +1,305 @@
+//! Human-readable rendering of devices and their peers, like `wg show`.
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use crate::device::Device;
+use crate::peer::Peer;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+
+/// Renders devices the way `wg show` does. Private and preshared keys are hidden unless asked
+/// otherwise, and peers are sorted by most recent handshake.
+#[derive(Debug, Clone)]
+pub struct Printer {
+    colors: bool,
+    sort: bool,
+    show_keys: bool,
+}
+
+impl Default for Printer {
+    fn default() -> Printer {
+        Printer::new()
+    }
+}
+
+impl Printer {
+    /// Create a printer without colors, hiding keys and sorting peers.
+    pub fn new() -> Printer {
+        Printer {
+            colors: false,
+            sort: true,
+            show_keys: false,
+        }
+    }
+
+    /// Choose whether to use terminal colors.
+    pub fn set_colors(&mut self, colors: bool) {
+        self.colors = colors;
+    }
+
+    /// Choose whether peers are sorted by most recent handshake, or kept in device order.
+    pub fn set_sort(&mut self, sort: bool) {
+        self.sort = sort;
+    }
+
+    /// Choose whether private and preshared keys are printed instead of `(hidden)`.
+    pub fn set_show_keys(&mut self, show_keys: bool) {
+        self.show_keys = show_keys;
+    }
+
+    /// Render a device, with handshakes relative to `now`.
+    pub fn render(&self, device: &Device, now: SystemTime) -> String {
+        let mut buf = String::new();
+
+        self.push_label(&mut buf, GREEN, "interface", device.name());
+
+        if let Some(key) = device.public_key() {
+            self.push_field(&mut buf, "public key", &key.to_base64());
+        }
+
+        if let Some(key) = device.private_key() {
+            let key = self.secret(&key.to_base64());
+            self.push_field(&mut buf, "private key", &key);
+        }
+
+        if let Some(port) = device.listen_port() {
+            self.push_field(&mut buf, "listening port", &port.to_string());
+        }
+
+        if let Some(fwmark) = device.fwmark().filter(|fwmark| *fwmark > 0) {
+            self.push_field(&mut buf, "fwmark", &format!("{:#x}", fwmark));
+        }
+
+        for peer in self.peers(device) {
+            let key = peer
+                .public_key()
+                .map(|key| key.to_base64())
+                .unwrap_or_else(|| String::from("(none)"));
+
+            buf.push('\n');
+            self.push_label(&mut buf, YELLOW, "peer", &key);
+            self.push_peer(&mut buf, peer, now);
+        }
+
+        buf
+    }
+
+    /// Write a device, with handshakes relative to `now`.
+    pub fn write<W: Write>(&self, device: &Device, now: SystemTime, mut w: W) -> io::Result<()> {
+        w.write_all(self.render(device, now).as_bytes())
+    }
+
+    /// Get the peers of a device in display order.
+    fn peers<'a>(&self, device: &'a Device) -> Vec<&'a Peer> {
+        let mut peers = device.peers().iter().collect::<Vec<_>>();
+
+        if self.sort {
+            // Stable, so that peers without handshakes stay in device order, after the others.
+            peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_handshake()));
+        }
+
+        peers
+    }
+
+    /// Render the fields of a peer.
+    fn push_peer(&self, buf: &mut String, peer: &Peer, now: SystemTime) {
+        if let Some(name) = peer.metadata().and_then(|metadata| metadata.name()) {
+            self.push_field(buf, "name", name);
+        }
+
+        if let Some(key) = peer.preshared_key() {
+            let key = self.secret(&key.to_base64());
+            self.push_field(buf, "preshared key", &key);
+        }
+
+        if let Some(&(addr, port)) = peer.endpoint() {
+            self.push_field(buf, "endpoint", &SocketAddr::new(addr, port).to_string());
+        }
+
+        let allowed_ips = if peer.allowed_ips().is_empty() {
+            String::from("(none)")
+        } else {
+            peer.allowed_ips()
+                .iter()
+                .map(|ip| {
+                    format!(
+                        "{}{}/{}{}",
+                        ip.addr(),
+                        self.color(CYAN),
+                        self.color(RESET),
+                        ip.mask()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        self.push_field(buf, "allowed ips", &allowed_ips);
+
+        if let Some(time) = peer.last_handshake() {
+            let ago = match now.duration_since(time) {
+                Ok(elapsed) if elapsed.as_secs() == 0 => String::from("Now"),
+                Ok(elapsed) => format!("{} ago", self.duration(elapsed)),
+
+                Err(_) => format!(
+                    "{}System clock wound backward; connection problems may ensue.{}",
+                    self.color(RED),
+                    self.color(RESET)
+                ),
+            };
+
+            self.push_field(buf, "latest handshake", &ago);
+        }
+
+        if peer.rx_bytes() > 0 || peer.tx_bytes() > 0 {
+            let transfer = format!(
+                "{} received, {} sent",
+                self.bytes(peer.rx_bytes()),
+                self.bytes(peer.tx_bytes())
+            );
+
+            self.push_field(buf, "transfer", &transfer);
+        }
+
+        if let Some(interval) = peer.persistent_keepalive().filter(|interval| *interval > 0) {
+            let every = format!(
+                "every {}",
+                self.duration(Duration::from_secs(interval as u64))
+            );
+            self.push_field(buf, "persistent keepalive", &every);
+        }
+    }
+
+    /// Render a section header such as `interface: wg0`.
+    fn push_label(&self, buf: &mut String, color: &str, label: &str, value: &str) {
+        buf.push_str(&format!(
+            "{}{}{}{}{}: {}{}{}\n",
+            self.color(RESET),
+            self.color(color),
+            self.color(BOLD),
+            label,
+            self.color(RESET),
+            self.color(color),
+            value,
+            self.color(RESET)
+        ));
+    }
+
+    /// Render an indented field such as `  listening port: 51820`.
+    fn push_field(&self, buf: &mut String, label: &str, value: &str) {
+        buf.push_str(&format!(
+            "  {}{}{}: {}\n",
+            self.color(BOLD),
+            label,
+            self.color(RESET),
+            value
+        ));
+    }
+
+    /// Hide a secret key unless keys are shown.
+    fn secret(&self, key: &str) -> String {
+        if self.show_keys {
+            key.to_owned()
+        } else {
+            String::from("(hidden)")
+        }
+    }
+
+    /// Render a duration with colored numbers.
+    fn duration(&self, duration: Duration) -> String {
+        if duration.as_secs() == 0 {
+            return format!("{}0{} seconds", self.color(CYAN), self.color(RESET));
+        }
+
+        units(duration)
+            .iter()
+            .map(|(n, unit)| format!("{}{}{} {}", self.color(CYAN), n, self.color(RESET), unit))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Render an amount of bytes with a colored unit.
+    fn bytes(&self, bytes: u64) -> String {
+        let (amount, unit) = scale(bytes);
+        format!(
+            "{} {}{}{}",
+            amount,
+            self.color(CYAN),
+            unit,
+            self.color(RESET)
+        )
+    }
+
+    /// Get an escape sequence, or nothing without colors.
+    fn color<'a>(&self, code: &'a str) -> &'a str {
+        if self.colors {
+            code
+        } else {
+            ""
+        }
+    }
+}
+
+/// Format a duration like `wg show`: `1 minute, 3 seconds`.
+pub fn format_duration(duration: Duration) -> String {
+    Printer::new().duration(duration)
+}
+
+/// Format an amount of bytes like `wg show`: `1.21 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    Printer::new().bytes(bytes)
+}
+
+/// Split a duration into years, days, hours, minutes and seconds, leaving out the zero ones.
+fn units(duration: Duration) -> Vec<(u64, String)> {
+    let secs = duration.as_secs();
+
+    let parts = [
+        (secs / (365 * 24 * 3600), "year"),
+        (secs / (24 * 3600) % 365, "day"),
+        (secs / 3600 % 24, "hour"),
+        (secs / 60 % 60, "minute"),
+        (secs % 60, "second"),
+    ];
+
+    parts
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|&(n, unit)| {
+            (
+                n,
+                if n == 1 {
+                    unit.to_owned()
+                } else {
+                    format!("{}s", unit)
+                },
+            )
+        })
+        .collect()
+}
+
+/// Scale an amount of bytes to a binary unit.
+fn scale(bytes: u64) -> (String, &'static str) {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return (bytes.to_string(), "B");
+    }
+
+    let mut amount = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while amount >= 1024.0 && unit < UNITS.len() - 1 {
+        amount /= 1024.0;
+        unit += 1;
+    }
+
+    (format!("{:.2}", amount), UNITS[unit])
+}
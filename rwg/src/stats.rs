@@ -0,0 +1,217 @@
+//! History of the transfer counters of peers, for rates and totals.
+//!
+//! The counters of a peer start from zero again when it is removed and added back, or when its
+//! device is recreated. A `Sampler` notices that, and keeps totals that include the traffic
+//! counted before each reset.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use crate::device::Device;
+use crate::key::Key;
+
+/// Number of samples kept per peer, unless configured otherwise.
+pub const DEFAULT_CAPACITY: usize = 120;
+
+/// The traffic of a peer at some point in time, including the traffic counted before resets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    time: SystemTime,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl Sample {
+    /// Get the time at which the sample was taken.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Get the total amount of bytes received from the peer.
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx_bytes
+    }
+
+    /// Get the total amount of bytes sent to the peer.
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes
+    }
+}
+
+/// Throughput, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    rx: f64,
+    tx: f64,
+}
+
+impl Rate {
+    /// Get the throughput of the traffic received from the peer.
+    pub fn rx(&self) -> f64 {
+        self.rx
+    }
+
+    /// Get the throughput of the traffic sent to the peer.
+    pub fn tx(&self) -> f64 {
+        self.tx
+    }
+}
+
+/// The samples of a peer, oldest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct History {
+    samples: VecDeque<Sample>,
+    counters: (u64, u64),
+    offset: (u64, u64),
+    resets: u32,
+}
+
+impl History {
+    /// Get the samples, oldest first.
+    pub fn samples(&self) -> &VecDeque<Sample> {
+        &self.samples
+    }
+
+    /// Get the latest sample.
+    pub fn latest(&self) -> Option<&Sample> {
+        self.samples.back()
+    }
+
+    /// Get the total amounts of bytes received and sent, surviving counter resets.
+    pub fn totals(&self) -> (u64, u64) {
+        self.latest()
+            .map(|sample| (sample.rx_bytes, sample.tx_bytes))
+            .unwrap_or_default()
+    }
+
+    /// Get the number of counter resets noticed.
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    /// Get the average throughput over the latest `window`, from the oldest sample within it to
+    /// the latest one. Needs two samples taken at different times.
+    pub fn rate(&self, window: Duration) -> Option<Rate> {
+        let latest = self.latest()?;
+        let start = latest.time.checked_sub(window).unwrap_or(latest.time);
+        let oldest = self.samples.iter().find(|sample| sample.time >= start)?;
+
+        let elapsed = latest.time.duration_since(oldest.time).ok()?.as_secs_f64();
+
+        if elapsed == 0.0 {
+            return None;
+        }
+
+        Some(Rate {
+            rx: (latest.rx_bytes - oldest.rx_bytes) as f64 / elapsed,
+            tx: (latest.tx_bytes - oldest.tx_bytes) as f64 / elapsed,
+        })
+    }
+
+    /// Record the raw counters of the peer. Returns whether they were reset.
+    fn record(&mut self, capacity: usize, time: SystemTime, rx_bytes: u64, tx_bytes: u64) -> bool {
+        if self.latest().is_some_and(|latest| time < latest.time) {
+            return false;
+        }
+
+        let (rx, tx) = self.counters;
+        let reset = !self.samples.is_empty() && (rx_bytes < rx || tx_bytes < tx);
+
+        if reset {
+            self.offset = (self.offset.0 + rx, self.offset.1 + tx);
+            self.resets += 1;
+        }
+
+        self.counters = (rx_bytes, tx_bytes);
+        self.samples.push_back(Sample {
+            time: time,
+            rx_bytes: self.offset.0 + rx_bytes,
+            tx_bytes: self.offset.1 + tx_bytes,
+        });
+
+        while self.samples.len() > capacity {
+            self.samples.pop_front();
+        }
+
+        reset
+    }
+}
+
+/// Keeps a bounded history of the counters of every peer seen on a device.
+///
+/// The history of a peer that disappears is kept, so that its totals go on if it comes back,
+/// until it is forgotten.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sampler {
+    capacity: usize,
+    peers: HashMap<Key, History>,
+}
+
+impl Default for Sampler {
+    fn default() -> Sampler {
+        Sampler::new()
+    }
+}
+
+impl Sampler {
+    /// Create a sampler keeping `DEFAULT_CAPACITY` samples per peer.
+    pub fn new() -> Sampler {
+        Sampler {
+            capacity: DEFAULT_CAPACITY,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Set the number of samples kept per peer. At least one is always kept.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+
+        for history in self.peers.values_mut() {
+            while history.samples.len() > self.capacity {
+                history.samples.pop_front();
+            }
+        }
+    }
+
+    /// Get the number of samples kept per peer.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Record the counters of the peers of a device, retrieved at the time `now`. Returns the
+    /// public keys of the peers whose counters were reset since the previous sample. Samples older
+    /// than the latest one of a peer are ignored.
+    pub fn record(&mut self, device: &Device, now: SystemTime) -> Vec<Key> {
+        let mut resets = Vec::new();
+
+        for peer in device.peers() {
+            let key = match peer.public_key() {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let history = self.peers.entry(key.clone()).or_default();
+
+            if history.record(self.capacity, now, peer.rx_bytes(), peer.tx_bytes()) {
+                resets.push(key.clone());
+            }
+        }
+
+        resets
+    }
+
+    /// Get the history of a peer.
+    pub fn history(&self, key: &Key) -> Option<&History> {
+        self.peers.get(key)
+    }
+
+    /// Iterate over the histories of every peer.
+    pub fn histories(&self) -> impl Iterator<Item = (&Key, &History)> {
+        self.peers.iter()
+    }
+
+    /// Forget the history of a peer.
+    pub fn forget(&mut self, key: &Key) -> Option<History> {
+        self.peers.remove(key)
+    }
+}
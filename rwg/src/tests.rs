@@ -1,10 +1,81 @@
 //! Library tests.
 
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
 use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::{AllowedIp, Device, Key, Peer};
+use crate::backend::{netlink, Mock, System, Userspace};
+use crate::netlink::{ifinfomsg, Message, RTM_NEWLINK};
+use crate::watch::{Event, Watcher};
+use crate::{
+    config, health, link, metrics, policy, route, show, stats, uapi, Address, AllowedIp, Backend,
+    Device, Key, Namespace, OperState, Peer,
+};
+
+/// Create an empty temporary directory dedicated to the specified test.
+fn temp_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rwg-{}-{}", process::id(), test));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// Serve `connections` UAPI connections on `<dir>/<name>.sock`, answering every `get=1` with
+/// `response` and every `set=1` with `errno=0`. The raw requests are sent back on the returned
+/// channel. Connections closed without a request, such as the ones listing devices, are ignored.
+fn fake_uapi_server(
+    dir: &Path,
+    name: &str,
+    response: String,
+    connections: usize,
+) -> mpsc::Receiver<String> {
+    let listener = UnixListener::bind(dir.join(format!("{}.sock", name))).unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming().take(connections) {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+
+            loop {
+                let mut line = String::new();
+
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\n" {
+                    break;
+                }
+
+                request.push_str(&line);
+            }
+
+            if request.is_empty() {
+                continue;
+            }
+
+            if request.starts_with("get=1") {
+                stream.write_all(response.as_bytes()).unwrap();
+            } else {
+                stream.write_all(b"errno=0\n\n").unwrap();
+            }
+
+            tx.send(request).unwrap();
+        }
+    });
+
+    rx
+}
 
 #[test]
+#[ignore = "needs CAP_NET_ADMIN and the wireguard kernel module"]
 fn create_and_retrieve() {
     let dev_name = "testwg0";
 
@@ -28,6 +99,2034 @@ fn create_and_retrieve() {
     dev.clone().save().unwrap();
 
     // Verify that the device has been correctly configured.
-    assert_eq!(1, Device::all().unwrap().len());
+    assert!(Device::all().unwrap().iter().any(|d| d.name() == dev_name));
     assert_eq!(dev, Device::open(dev_name).unwrap());
+
+    dev.delete().unwrap();
+}
+
+#[test]
+fn uapi_set_encoding() {
+    let mut dev = Device::new("wg0");
+    dev.set_private_key(Key::from_bytes([1u8; 32]));
+    dev.set_listen_port(51820);
+
+    let mut peer = Peer::new(
+        Key::from_bytes([2u8; 32]),
+        Some((IpAddr::V6("fe80::1".parse().unwrap()), 1234)),
+    );
+
+    peer.set_persistent_keepalive(25);
+    peer.set_replace_allowed_ips(false);
+    peer.add_allowed_ip(AllowedIp::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 24));
+    dev.add_peer(peer);
+
+    let mut peer = Peer::new(Key::from_bytes([3u8; 32]), None);
+    peer.set_remove(true);
+    dev.add_peer(peer);
+
+    let mut buf = Vec::new();
+    uapi::write_set(&dev, &mut buf).unwrap();
+
+    let expected = format!(
+        "set=1\nprivate_key={}\nlisten_port=51820\nreplace_peers=true\n\
+         public_key={}\nendpoint=[fe80::1]:1234\npersistent_keepalive_interval=25\n\
+         allowed_ip=10.0.0.0/24\npublic_key={}\nremove=true\n\n",
+        "01".repeat(32),
+        "02".repeat(32),
+        "03".repeat(32),
+    );
+
+    assert_eq!(expected, String::from_utf8(buf).unwrap());
+}
+
+#[test]
+fn uapi_set_clearing() {
+    let mut dev = Device::new("wg0");
+    dev.set_fwmark(0);
+    dev.set_replace_peers(false);
+
+    let mut peer = Peer::new(Key::from_bytes([2u8; 32]), None);
+    peer.set_persistent_keepalive(0);
+    peer.set_replace_allowed_ips(false);
+    dev.add_peer(peer);
+
+    let mut buf = Vec::new();
+    uapi::write_set(&dev, &mut buf).unwrap();
+
+    let expected = format!(
+        "set=1\nfwmark=0\npublic_key={}\npersistent_keepalive_interval=0\n\n",
+        "02".repeat(32),
+    );
+
+    assert_eq!(expected, String::from_utf8(buf).unwrap());
+
+    // Leaving the values unset changes nothing.
+    let mut dev = Device::new("wg0");
+    dev.set_replace_peers(false);
+    dev.add_peer(Peer::new(Key::from_bytes([2u8; 32]), None));
+    dev.peers_mut()[0].set_replace_allowed_ips(false);
+
+    let mut buf = Vec::new();
+    uapi::write_set(&dev, &mut buf).unwrap();
+
+    assert_eq!(
+        format!("set=1\npublic_key={}\n\n", "02".repeat(32)),
+        String::from_utf8(buf).unwrap()
+    );
+
+    // A cleared mark and interval are read back as unset.
+    let mock = Mock::new();
+    let mut dev = Device::create_with(&mock, "wg0", None).unwrap();
+    let mut peer = Peer::new(Key::from_bytes([2u8; 32]), None);
+
+    dev.set_fwmark(0x1234);
+    peer.set_persistent_keepalive(25);
+    dev.add_peer(peer);
+    dev.save_with(&mock).unwrap();
+
+    let mut changes = Device::new("wg0");
+    let mut peer = Peer::new(Key::from_bytes([2u8; 32]), None);
+
+    changes.set_fwmark(0);
+    changes.set_replace_peers(false);
+    peer.set_persistent_keepalive(0);
+    peer.set_replace_allowed_ips(false);
+    changes.add_peer(peer);
+    changes.save_with(&mock).unwrap();
+
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+
+    assert_eq!(None, dev.fwmark());
+    assert_eq!(None, dev.peers()[0].persistent_keepalive());
+}
+
+#[test]
+fn uapi_get_decoding() {
+    let response = format!(
+        "private_key={}\nlisten_port=51820\nfwmark=51820\npublic_key={}\npreshared_key={}\n\
+         protocol_version=1\nendpoint=1.1.1.1:42069\nlast_handshake_time_sec=1600000000\n\
+         last_handshake_time_nsec=5\ntx_bytes=1024\nrx_bytes=2048\n\
+         persistent_keepalive_interval=0\nallowed_ip=10.0.0.1/32\nallowed_ip=fd00::/64\n\
+         public_key={}\nerrno=0\n\n",
+        "01".repeat(32),
+        "02".repeat(32),
+        "00".repeat(32),
+        "03".repeat(32),
+    );
+
+    let dev = uapi::read_get("wg0", response.as_bytes()).unwrap();
+
+    assert_eq!("wg0", dev.name());
+    assert_eq!(Some(&Key::from_bytes([1u8; 32])), dev.private_key());
+    assert_eq!(Some(51820), dev.listen_port());
+    assert_eq!(Some(51820), dev.fwmark());
+    assert_eq!(2, dev.peers().len());
+
+    let peer = &dev.peers()[0];
+
+    assert_eq!(Some(&Key::from_bytes([2u8; 32])), peer.public_key());
+    assert_eq!(None, peer.preshared_key());
+    assert_eq!(None, peer.persistent_keepalive());
+    assert_eq!(
+        Some(&(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 42069)),
+        peer.endpoint()
+    );
+    assert_eq!(
+        Some(UNIX_EPOCH + Duration::new(1_600_000_000, 5)),
+        peer.last_handshake()
+    );
+    assert_eq!((2048, 1024), (peer.rx_bytes(), peer.tx_bytes()));
+    assert_eq!(2, peer.allowed_ips().len());
+    assert_eq!(None, dev.peers()[1].last_handshake());
+}
+
+#[test]
+fn uapi_strict_responses() {
+    let unknown = "listen_port=1\nfoo=bar\nerrno=0\n\n";
+    let orphan = "endpoint=1.1.1.1:1\nerrno=0\n\n";
+    let failed = "errno=19\n\n";
+    let truncated = "listen_port=1\n";
+
+    match uapi::read_get("wg0", unknown.as_bytes()) {
+        Err(uapi::Error::UnknownKey(ref key)) if key == "foo" => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    match uapi::read_get("wg0", orphan.as_bytes()) {
+        Err(uapi::Error::UnexpectedKey(ref key)) if key == "endpoint" => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    match uapi::read_set(failed.as_bytes()) {
+        Err(uapi::Error::Errno(19)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    assert!(uapi::read_get("wg0", truncated.as_bytes()).is_err());
+    assert!(uapi::read_set("errno=0\n\n".as_bytes()).is_ok());
+}
+
+#[test]
+fn userspace_socket_backend() {
+    let dir = temp_dir("userspace_socket_backend");
+    let response = format!(
+        "private_key={}\nlisten_port=1337\npublic_key={}\nallowed_ip=10.0.0.2/32\nerrno=0\n\n",
+        "01".repeat(32),
+        "02".repeat(32),
+    );
+
+    let requests = fake_uapi_server(&dir, "wgu0", response, 3);
+    let userspace = Userspace::with_dir(&dir);
+
+    fs::write(dir.join("notasocket.sock"), b"").unwrap();
+
+    assert_eq!(vec![String::from("wgu0")], userspace.list().unwrap());
+    assert!(userspace.exists("wgu0"));
+    assert!(!userspace.exists("notasocket"));
+
+    let mut dev = Device::open_with(&userspace, "wgu0").unwrap();
+
+    assert_eq!("get=1\n", requests.recv().unwrap());
+    assert_eq!(Some(1337), dev.listen_port());
+    assert_eq!(1, dev.peers().len());
+
+    dev.set_listen_port(1338);
+    dev.save_with(&userspace).unwrap();
+
+    let request = requests.recv().unwrap();
+
+    assert!(request.starts_with("set=1\n"));
+    assert!(request.contains("listen_port=1338\n"));
+    assert!(request.contains("allowed_ip=10.0.0.2/32\n"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mock_create_and_retrieve() {
+    let mock = Mock::new();
+
+    let mut dev = Device::create_with(&mock, "testwg0", Some(Key::from_bytes([1u8; 32]))).unwrap();
+    dev.set_listen_port(1337);
+
+    let mut peer = Peer::new(
+        Key::from_bytes([2u8; 32]),
+        Some((IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 42069)),
+    );
+
+    peer.add_allowed_ip(AllowedIp::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 32));
+    dev.add_peer(peer);
+
+    dev.clone().save_with(&mock).unwrap();
+
+    assert!(Device::create_with(&mock, "testwg0", None).is_err());
+    assert_eq!(1, Device::all_with(&mock).unwrap().len());
+    assert_eq!(dev, Device::open_with(&mock, "testwg0").unwrap());
+
+    Device::open_with(&mock, "testwg0")
+        .unwrap()
+        .delete_with(&mock)
+        .unwrap();
+
+    assert!(Device::open_with(&mock, "testwg0").is_err());
+}
+
+#[test]
+fn mock_kernel_semantics() {
+    let mock = Mock::new();
+    let net = |a, b, c, d, mask| AllowedIp::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), mask);
+
+    let mut dev = Device::create_with(&mock, "wg0", None).unwrap();
+
+    let mut first = Peer::new(Key::from_bytes([1u8; 32]), None);
+    first.add_allowed_ip(net(10, 0, 0, 1, 32));
+    first.add_allowed_ip(net(192, 168, 1, 42, 24));
+
+    let mut second = Peer::new(Key::from_bytes([2u8; 32]), None);
+    second.add_allowed_ip(net(10, 0, 0, 2, 32));
+
+    dev.add_peer(first);
+    dev.add_peer(second);
+    dev.save_with(&mock).unwrap();
+
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+
+    assert_eq!(
+        &[net(10, 0, 0, 1, 32), net(192, 168, 1, 0, 24)],
+        dev.peers()[0].allowed_ips()
+    );
+
+    // Incremental changes: move an allowed IP, append another, skip an unknown peer.
+    let mut changes = Device::new("wg0");
+    changes.set_replace_peers(false);
+
+    let mut second = Peer::new(Key::from_bytes([2u8; 32]), None);
+    second.set_replace_allowed_ips(false);
+    second.add_allowed_ip(net(10, 0, 0, 1, 32));
+
+    let mut unknown = Peer::new(Key::from_bytes([3u8; 32]), None);
+    unknown.set_update_only(true);
+
+    changes.add_peer(second);
+    changes.add_peer(unknown);
+    changes.save_with(&mock).unwrap();
+
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+
+    assert_eq!(2, dev.peers().len());
+    assert_eq!(&[net(192, 168, 1, 0, 24)], dev.peers()[0].allowed_ips());
+    assert_eq!(
+        &[net(10, 0, 0, 2, 32), net(10, 0, 0, 1, 32)],
+        dev.peers()[1].allowed_ips()
+    );
+
+    // Set a preshared key, then clear it with a key of zeros.
+    for key in &[Key::from_bytes([9u8; 32]), Key::zero()] {
+        let mut changes = Device::new("wg0");
+        let mut first = Peer::new(Key::from_bytes([1u8; 32]), None);
+
+        changes.set_replace_peers(false);
+        first.set_replace_allowed_ips(false);
+        first.set_preshared_key(key.clone());
+        changes.add_peer(first);
+        changes.save_with(&mock).unwrap();
+
+        let dev = Device::open_with(&mock, "wg0").unwrap();
+        let expected = Some(key).filter(|key| !key.is_zero());
+
+        assert_eq!(expected, dev.peers()[0].preshared_key());
+    }
+
+    // Remove a peer.
+    let mut changes = Device::new("wg0");
+    changes.set_replace_peers(false);
+
+    let mut first = Peer::new(Key::from_bytes([1u8; 32]), None);
+    first.set_remove(true);
+
+    changes.add_peer(first);
+    changes.save_with(&mock).unwrap();
+
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+
+    assert_eq!(1, dev.peers().len());
+    assert_eq!(
+        Some(&Key::from_bytes([2u8; 32])),
+        dev.peers()[0].public_key()
+    );
+
+    assert!(Device::new("nope").save_with(&mock).is_err());
+}
+
+#[cfg(feature = "libwg")]
+#[test]
+fn libwg_update_only() {
+    use crate::backend::libwg::existing_peers_only;
+
+    let mock = Mock::new();
+    let mut dev = Device::create_with(&mock, "wg0", None).unwrap();
+
+    dev.add_peer(Peer::new(Key::from_bytes([1u8; 32]), None));
+    dev.save_with(&mock).unwrap();
+
+    let mut changes = Device::new("wg0");
+    changes.set_replace_peers(false);
+
+    for byte in 1..=3 {
+        let mut peer = Peer::new(Key::from_bytes([byte; 32]), None);
+        peer.set_update_only(byte != 3);
+        changes.add_peer(peer);
+    }
+
+    let keys = |device: &Device| {
+        device
+            .peers()
+            .iter()
+            .map(|peer| peer.public_key().unwrap().as_bytes()[0])
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        vec![1, 3],
+        keys(&existing_peers_only(&mock, &changes).unwrap())
+    );
+
+    // Replaced peers are gone before the update-only peers are looked up.
+    changes.set_replace_peers(true);
+
+    assert_eq!(
+        vec![3],
+        keys(&existing_peers_only(&mock, &changes).unwrap())
+    );
+}
+
+#[test]
+fn mock_simulated_activity() {
+    let mock = Mock::new();
+    let key = Key::from_bytes([1u8; 32]);
+    let endpoint = (IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 51820);
+    let now = SystemTime::now();
+
+    let mut dev = Device::create_with(&mock, "wg0", None).unwrap();
+    dev.add_peer(Peer::new(key.clone(), None));
+    dev.save_with(&mock).unwrap();
+
+    mock.handshake("wg0", &key, now).unwrap();
+    mock.transfer("wg0", &key, 100, 200).unwrap();
+    mock.transfer("wg0", &key, 1, 2).unwrap();
+    mock.roam("wg0", &key, endpoint).unwrap();
+
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+    let peer = &dev.peers()[0];
+
+    assert_eq!(Some(now), peer.last_handshake());
+    assert_eq!((101, 202), (peer.rx_bytes(), peer.tx_bytes()));
+    assert_eq!(Some(&endpoint), peer.endpoint());
+    assert!(mock.handshake("wg0", &Key::zero(), now).is_err());
+}
+
+#[test]
+fn system_discovers_userspace_devices() {
+    let dir = temp_dir("system_discovers_userspace_devices");
+    let response = String::from("listen_port=1337\nerrno=0\n\n");
+    let _requests = fake_uapi_server(&dir, "wgu0", response, 2);
+
+    let mock = Mock::new();
+    let system = System::with(&mock, Userspace::with_dir(&dir));
+
+    Device::create_with(&system, "wg0", None).unwrap();
+
+    assert_eq!(vec!["wg0", "wgu0"], system.list().unwrap());
+    assert_eq!(vec!["wg0"], mock.list().unwrap());
+    assert_eq!(
+        Some(1337),
+        Device::open_with(&system, "wgu0").unwrap().listen_port()
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stale_userspace_sockets() {
+    let dir = temp_dir("stale_userspace_sockets");
+
+    // The socket of a crashed implementation, which nothing listens on anymore.
+    drop(UnixListener::bind(dir.join("wg0.sock")).unwrap());
+
+    let mock = Mock::new();
+    let userspace = Userspace::with_dir(&dir);
+    let system = System::with(&mock, userspace.clone());
+
+    Device::create_with(&mock, "wg0", None).unwrap();
+
+    assert!(userspace.exists("wg0"));
+    assert!(userspace.list().unwrap().is_empty());
+    assert_eq!(vec!["wg0"], system.list().unwrap());
+    assert_eq!(1, Device::all_with(&system).unwrap().len());
+
+    let mut dev = Device::open_with(&system, "wg0").unwrap();
+    dev.set_listen_port(51820);
+    dev.save_with(&system).unwrap();
+
+    assert_eq!(
+        Some(51820),
+        Device::open_with(&mock, "wg0").unwrap().listen_port()
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn x25519_public_keys() {
+    // Test vectors from RFC 7748, section 6.1.
+    let alice = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+    let bob = "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb";
+
+    assert_eq!(
+        "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a",
+        Key::from_hex(alice).unwrap().derive_public().to_hex()
+    );
+
+    assert_eq!(
+        "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f",
+        Key::from_hex(bob).unwrap().derive_public().to_hex()
+    );
+
+    // Iterated test vectors from RFC 7748, section 5.2: the key and point of each round are the
+    // result and key of the previous one.
+    let mut key = [0u8; 32];
+    let mut point = [0u8; 32];
+
+    key[0] = 9;
+    point[0] = 9;
+
+    for i in 1..=1000 {
+        let result = x25519_dalek::x25519(key, point);
+
+        point = key;
+        key = result;
+
+        if i == 1 {
+            assert_eq!(
+                "422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079",
+                Key::from_bytes(key).to_hex()
+            );
+        }
+    }
+
+    assert_eq!(
+        "684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51",
+        Key::from_bytes(key).to_hex()
+    );
+
+    let private = Key::generate_private();
+
+    assert_eq!(0, private.as_bytes()[0] & 7);
+    assert_eq!(64, private.as_bytes()[31] & 192);
+}
+
+#[test]
+fn netlink_message_splitting() {
+    let mut dev = Device::new("wg0");
+    dev.set_private_key(Key::from_bytes([1u8; 32]));
+    dev.set_listen_port(51820);
+
+    for i in 0..3u8 {
+        let mut peer = Peer::new(
+            Key::from_bytes([i + 2; 32]),
+            Some((IpAddr::V4(Ipv4Addr::new(1, 1, 1, i)), 51820 + i as u16)),
+        );
+
+        peer.set_persistent_keepalive(25);
+
+        for j in 0..(i as u16 * 400) {
+            let addr = Ipv4Addr::new(10, i, (j >> 8) as u8, j as u8);
+            peer.add_allowed_ip(AllowedIp::new(IpAddr::V4(addr), 32));
+        }
+
+        dev.add_peer(peer);
+    }
+
+    let messages = netlink::set_messages(0x20, &dev).unwrap();
+
+    assert!(messages.len() > 2);
+    assert!(messages
+        .iter()
+        .all(|msg| msg.len() <= netlink::SET_MESSAGE_SIZE));
+
+    let payloads = messages
+        .iter()
+        .map(|msg| msg.finish(0, 0)[16..].to_vec())
+        .collect::<Vec<_>>();
+
+    assert_eq!(dev, netlink::parse_device("wg0", &payloads).unwrap());
+
+    // A peer continued in the following messages keeps `WGPEER_F_UPDATE_ONLY` (4) but not
+    // `WGPEER_F_REPLACE_ALLOWEDIPS` (2).
+    dev.peers_mut()[2].set_update_only(true);
+    dev.peers_mut()[2].set_replace_allowed_ips(true);
+
+    let flags = netlink::set_messages(0x20, &dev)
+        .unwrap()
+        .iter()
+        .flat_map(|msg| {
+            let payload = msg.finish(0, 0)[16 + crate::netlink::GENL_HEADER_SIZE..].to_vec();
+            let mut flags = Vec::new();
+
+            for (ty, data) in crate::netlink::attrs(&payload) {
+                if ty != 8 {
+                    continue;
+                }
+
+                for (_, peer) in crate::netlink::attrs(data) {
+                    let attrs = crate::netlink::attrs(peer).collect::<Vec<_>>();
+
+                    if attrs.contains(&(1, &[4u8; 32][..])) {
+                        flags.push(
+                            attrs
+                                .iter()
+                                .find(|(ty, _)| *ty == 3)
+                                .map(|(_, data)| crate::netlink::attr_u32(data).unwrap()),
+                        );
+                    }
+                }
+            }
+
+            flags
+        })
+        .collect::<Vec<_>>();
+
+    assert!(flags.len() > 1);
+    assert_eq!(Some(6), flags[0]);
+    assert!(flags[1..].iter().all(|flags| *flags == Some(4)));
+}
+
+#[test]
+fn link_messages() {
+    assert_eq!(
+        Ok(Address::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 24)),
+        "10.0.0.1/24".parse()
+    );
+    assert_eq!(
+        Ok(128),
+        "fd00::1".parse::<Address>().map(|a| a.prefix_len())
+    );
+    assert!("10.0.0.1/33".parse::<Address>().is_err());
+    assert_eq!(
+        "fd00::1/64",
+        "fd00::1/64".parse::<Address>().unwrap().to_string()
+    );
+
+    let mut msg = Message::new(RTM_NEWLINK, 0);
+    msg.put(&ifinfomsg(7, 0x1, 0));
+    msg.attr_u32(4, 1420);
+    msg.attr_u8(16, 6);
+
+    let link = link::parse_link(&msg.finish(0, 0)[16..]).unwrap();
+
+    assert_eq!(7, link.index());
+    assert!(link.is_up());
+    assert!(!link.is_lower_up());
+    assert_eq!(1420, link.mtu());
+    assert_eq!(OperState::Up, link.oper_state());
+
+    let mut msg = Message::new(20, 0);
+    msg.put(&[libc::AF_INET as u8, 24, 0, 0]);
+    msg.put(&7u32.to_ne_bytes());
+    msg.attr(1, &[10, 0, 0, 2]);
+    msg.attr(2, &[10, 0, 0, 1]);
+
+    assert_eq!(
+        Some((7, "10.0.0.1/24".parse().unwrap())),
+        link::parse_address(&msg.finish(0, 0)[16..])
+    );
+}
+
+#[test]
+fn route_reconciliation() {
+    let ip = |s: &str, mask| AllowedIp::new(s.parse().unwrap(), mask);
+
+    let mut dev = Device::new("wg0");
+    let mut a = Peer::new(Key::from_bytes([1u8; 32]), None);
+    let mut b = Peer::new(Key::from_bytes([2u8; 32]), None);
+
+    a.add_allowed_ip(ip("10.0.0.1", 24));
+    a.add_allowed_ip(ip("fd00::", 64));
+    b.add_allowed_ip(ip("10.0.0.0", 24));
+    b.add_allowed_ip(ip("10.1.0.0", 16));
+    dev.add_peer(a);
+    dev.add_peer(b);
+
+    assert_eq!(
+        vec![ip("10.0.0.0", 24), ip("fd00::", 64), ip("10.1.0.0", 16)],
+        dev.routes()
+    );
+
+    let installed = vec![ip("10.0.0.0", 24), ip("10.2.0.0", 16)];
+    let (add, remove) = route::reconcile(&dev.routes(), &installed);
+
+    assert_eq!(vec![ip("10.1.0.0", 16), ip("fd00::", 64)], add);
+    assert_eq!(vec![ip("10.2.0.0", 16)], remove);
+
+    let mut msg = Message::new(24, 0);
+    msg.put(&[libc::AF_INET6 as u8, 64, 0, 0, 252, 3, 253, 1, 0, 0, 0, 0]);
+    msg.attr(1, &"fd00::".parse::<std::net::Ipv6Addr>().unwrap().octets());
+    msg.attr_u32(4, 7);
+    msg.attr_u32(15, 1000);
+
+    let parsed = route::parse_route(&msg.finish(0, 0)[16..]).unwrap();
+
+    assert_eq!(7, parsed.index);
+    assert_eq!(1000, parsed.table);
+    assert_eq!(ip("fd00::", 64), parsed.destination);
+
+    // Routes added by hand with `ip route`, of the boot protocol, are not ours to remove.
+    let mut by_hand = parsed.clone();
+    by_hand.destination = ip("fd01::", 64);
+
+    let mut ours = parsed.clone();
+    ours.protocol = route::RTPROT_RWG;
+
+    let installed = route::installed(&[by_hand, ours], 7, 1000);
+
+    assert_eq!(vec![ip("fd00::", 64)], installed);
+
+    let (add, remove) = route::reconcile(&[], &installed);
+
+    assert!(add.is_empty());
+    assert_eq!(vec![ip("fd00::", 64)], remove);
+}
+
+#[test]
+fn full_tunnel_rules() {
+    let ip = |s: &str, mask| AllowedIp::new(s.parse().unwrap(), mask);
+
+    let mut dev = Device::new("wg0");
+    let mut peer = Peer::new(Key::from_bytes([1u8; 32]), None);
+
+    peer.add_allowed_ip(ip("::", 0));
+    peer.add_allowed_ip(ip("10.0.0.0", 8));
+    peer.add_allowed_ip(ip("0.0.0.0", 0));
+    dev.add_peer(peer);
+
+    assert_eq!(
+        vec![ip("0.0.0.0", 0), ip("::", 0)],
+        policy::full_tunnel_routes(&dev)
+    );
+
+    // A rule as dumped by the kernel for `ip rule add not fwmark 51820 table 51820`.
+    let mut msg = Message::new(32, 0);
+    msg.put(&[libc::AF_INET as u8, 0, 0, 0, 0, 0, 0, 1]);
+    msg.put(&2u32.to_ne_bytes());
+    msg.attr_u32(15, 51820);
+    msg.attr_u32(6, 32765);
+    msg.attr_u32(10, 51820);
+    msg.attr_u32(16, u32::MAX);
+    msg.attr_u32(14, u32::MAX);
+    msg.attr_u8(21, 0);
+
+    let rule = policy::parse_rule(&msg.finish(0, 0)[16..]).unwrap();
+
+    assert_eq!(Some(51820), rule.not_fwmark);
+    assert_eq!(51820, rule.table);
+    assert_eq!(None, rule.suppress_prefix_len);
+    assert!(!rule.selective);
+
+    // The same rule restricted to a source network is not ours.
+    msg.attr(2, &[192, 168, 0, 0]);
+
+    assert!(
+        policy::parse_rule(&msg.finish(0, 0)[16..])
+            .unwrap()
+            .selective
+    );
+}
+
+#[test]
+fn config_files() {
+    let private = Key::from_bytes([1u8; 32]);
+    let public = Key::from_bytes([2u8; 32]);
+
+    let text = format!(
+        "# Managed by hand\n\
+         [Interface]\n\
+         Address = 10.0.0.1/24, fd00::1/64\n\
+         ListenPort = 51820\n\
+         PrivateKey = {}\n\
+         FwMark = 0x1234\n\
+         Table = off\n\
+         PostUp = iptables -A FORWARD -i %i -j ACCEPT # forward\n\
+         SaveConfig = true\n\
+         \n\
+         [peer]\n\
+         publickey={}\n\
+         AllowedIPs = 10.0.0.2, 10.1.0.0/16\n\
+         AllowedIPs = fd00::2/128\n\
+         Endpoint = [fe80::1%eth0]:51820\n\
+         PersistentKeepalive = off\n",
+        private.to_base64(),
+        public.to_base64()
+    );
+
+    let config = config::read("wg0", text.as_bytes()).unwrap();
+    let device = config.device();
+
+    assert_eq!(2, config.addresses().len());
+    assert_eq!(config::Table::Off, config.table());
+    assert_eq!(
+        vec!["iptables -A FORWARD -i %i -j ACCEPT"],
+        config.hooks(config::Hook::PostUp)
+    );
+    assert!(config.save_config());
+    assert_eq!(Some(51820), device.listen_port());
+    assert_eq!(Some(0x1234), device.fwmark());
+    assert_eq!(Some(&private), device.private_key());
+
+    let peer = &device.peers()[0];
+
+    assert_eq!(Some(&public), peer.public_key());
+    assert_eq!(3, peer.allowed_ips().len());
+    assert_eq!(32, peer.allowed_ips()[0].mask());
+    assert_eq!(Some(0), peer.persistent_keepalive());
+    assert_eq!(Some(51820), peer.endpoint().map(|e| e.1));
+
+    let mut written = Vec::new();
+    config::write(&config, &mut written).unwrap();
+
+    assert_eq!(config, config::read("wg0", &written[..]).unwrap());
+
+    let mut stripped = Vec::new();
+    config::strip(text.as_bytes(), &mut stripped).unwrap();
+
+    let device = config::read_device("wg0", &stripped[..]).unwrap();
+
+    assert_eq!(config.device(), &device);
+    assert!(String::from_utf8(stripped)
+        .unwrap()
+        .starts_with("# Managed by hand\n"));
+
+    match config::read_device("wg0", text.as_bytes()) {
+        Err(config::Error::UnknownKey(3, key)) => assert_eq!("Address", key),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    match config::read(
+        "wg0",
+        "[Interface]\n[Peer]\nAllowedIPs = 10.0.0.0/8\n".as_bytes(),
+    ) {
+        Err(config::Error::MissingPublicKey(2)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn show_rendering() {
+    assert_eq!(
+        "1 minute, 3 seconds",
+        show::format_duration(Duration::from_secs(63))
+    );
+    assert_eq!(
+        "2 years, 1 day, 1 hour",
+        show::format_duration(Duration::from_secs(2 * 365 * 86400 + 86400 + 3600))
+    );
+    assert_eq!("1.21 MiB", show::format_bytes(1_268_777));
+    assert_eq!("530.00 KiB", show::format_bytes(530 * 1024));
+    assert_eq!("1023 B", show::format_bytes(1023));
+
+    let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mut dev = Device::new("wg0");
+    dev.set_private_key(Key::from_bytes([1u8; 32]));
+    dev.set_listen_port(51820);
+
+    for i in 2..5u8 {
+        let mut peer = Peer::new(Key::from_bytes([i; 32]), None);
+        peer.add_allowed_ip(AllowedIp::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), 32));
+
+        if i > 2 {
+            peer.set_last_handshake(Some(now - Duration::from_secs(i as u64 * 60 + 3)));
+            peer.set_transfer(1_268_777, 530 * 1024);
+        }
+
+        dev.add_peer(peer);
+    }
+
+    dev.peers_mut()[1].set_preshared_key(Key::from_bytes([9u8; 32]));
+    dev.peers_mut()[1].set_persistent_keepalive(25);
+    dev.peers_mut()[2].metadata_mut().set_name("laptop");
+
+    let output = show::Printer::new().render(&dev, now);
+    let expected = format!(
+        "interface: wg0\n  \
+         public key: {}\n  \
+         private key: (hidden)\n  \
+         listening port: 51820\n\n\
+         peer: {}\n  \
+         preshared key: (hidden)\n  \
+         allowed ips: 10.0.0.3/32\n  \
+         latest handshake: 3 minutes, 3 seconds ago\n  \
+         transfer: 1.21 MiB received, 530.00 KiB sent\n  \
+         persistent keepalive: every 25 seconds\n\n\
+         peer: {}\n  \
+         name: laptop\n  \
+         allowed ips: 10.0.0.4/32\n  \
+         latest handshake: 4 minutes, 3 seconds ago\n  \
+         transfer: 1.21 MiB received, 530.00 KiB sent\n\n\
+         peer: {}\n  \
+         allowed ips: 10.0.0.2/32\n",
+        Key::from_bytes([1u8; 32]).derive_public(),
+        Key::from_bytes([3u8; 32]),
+        Key::from_bytes([4u8; 32]),
+        Key::from_bytes([2u8; 32])
+    );
+
+    assert_eq!(expected, output);
+
+    let mut printer = show::Printer::new();
+    printer.set_colors(true);
+
+    assert!(printer
+        .render(&dev, now)
+        .starts_with("\x1b[0m\x1b[32m\x1b[1minterface\x1b[0m: \x1b[32mwg0\x1b[0m\n"));
+}
+
+#[test]
+fn namespace_threads() {
+    assert!(Namespace::named("rwg-does-not-exist").is_err());
+
+    let inode = || fs::metadata("/proc/thread-self/ns/net").map(|m| m.ino());
+    let outer = inode().unwrap();
+
+    match Namespace::current().unwrap().run(inode) {
+        Ok(inner) => assert_eq!(outer, inner),
+
+        // Entering a namespace needs CAP_SYS_ADMIN.
+        Err(err) => assert_eq!(Some(libc::EPERM), err.raw_os_error()),
+    }
+
+    assert_eq!(outer, inode().unwrap());
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_backends() {
+    use crate::backend::{AsyncBackend, Netlink};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let dir = temp_dir("async_backends");
+    let response = format!(
+        "private_key={}\nlisten_port=1337\npublic_key={}\nallowed_ip=10.0.0.2/32\nerrno=0\n\n",
+        "01".repeat(32),
+        "02".repeat(32),
+    );
+
+    let requests = fake_uapi_server(&dir, "wgu0", response, 3);
+    let userspace = Userspace::with_dir(&dir);
+    let mock = Mock::new();
+
+    runtime.block_on(async {
+        assert_eq!(
+            vec![String::from("wgu0")],
+            AsyncBackend::list(&userspace).await.unwrap()
+        );
+
+        let mut dev = Device::open_async_with(&userspace, "wgu0").await.unwrap();
+
+        assert_eq!("get=1\n", requests.recv().unwrap());
+        assert_eq!(Some(1337), dev.listen_port());
+        assert_eq!(1, dev.peers().len());
+
+        dev.set_listen_port(1338);
+        dev.save_async_with(&userspace).await.unwrap();
+
+        let request = requests.recv().unwrap();
+
+        assert!(request.starts_with("set=1\n"));
+        assert!(request.contains("listen_port=1338\n"));
+
+        let dev = Device::create_async_with(&mock, "wg0", Some(Key::generate_private()))
+            .await
+            .unwrap();
+        let key = dev.private_key().cloned();
+
+        dev.save_async_with(&mock).await.unwrap();
+
+        let all = Device::all_async_with(&mock).await.unwrap();
+
+        assert_eq!(1, all.len());
+        assert_eq!(key.as_ref(), all[0].private_key());
+
+        Device::open_async_with(&mock, "wg0")
+            .await
+            .unwrap()
+            .delete_async_with(&mock)
+            .await
+            .unwrap();
+
+        assert!(Device::open_async_with(&mock, "wg0").await.is_err());
+
+        // Listing links needs no privileges, and must agree with the blocking socket.
+        let names = AsyncBackend::list(&Netlink::new()).await.unwrap();
+        assert_eq!(Backend::list(&Netlink::new()).unwrap(), names);
+    });
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn watcher_events() {
+    let mock = Mock::new();
+    let (a, b) = (Key::from_bytes([1u8; 32]), Key::from_bytes([2u8; 32]));
+    let endpoint = (IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 51820);
+    let now = SystemTime::now();
+
+    let mut dev = Device::create_with(&mock, "wg0", None).unwrap();
+    dev.add_peer(Peer::new(a.clone(), None));
+    dev.save_with(&mock).unwrap();
+
+    let mut watcher = Watcher::with_backend(&mock, "wg0");
+    watcher.set_interval(Duration::from_millis(1));
+
+    assert!(watcher.poll().unwrap().is_empty());
+
+    let mut changes = Device::new("wg0");
+    changes.set_replace_peers(false);
+    changes.add_peer(Peer::new(b.clone(), None));
+    changes.save_with(&mock).unwrap();
+
+    mock.handshake("wg0", &a, now).unwrap();
+    mock.roam("wg0", &a, endpoint).unwrap();
+    mock.transfer("wg0", &a, 100, 200).unwrap();
+
+    let events = watcher.poll().unwrap();
+
+    assert_eq!(3, events.len());
+    assert!(events.contains(&Event::PeerAdded(Peer::new(b.clone(), None))));
+    assert!(events.contains(&Event::EndpointRoamed {
+        peer: a.clone(),
+        from: None,
+        to: endpoint,
+    }));
+    assert!(events.contains(&Event::HandshakeCompleted {
+        peer: a.clone(),
+        time: now,
+    }));
+
+    // Reported once, until the next handshake.
+    watcher.set_stale_after(Duration::from_secs(60));
+    let later = now + Duration::from_secs(61);
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+    let stale = Event::HandshakeStale {
+        peer: a.clone(),
+        last: now,
+    };
+
+    assert_eq!(vec![stale.clone()], watcher.update(&dev, later));
+    assert!(watcher.update(&dev, later).is_empty());
+
+    // Adding the peer back resets its counters.
+    let mut dev = Device::new("wg0");
+    dev.add_peer(Peer::new(a.clone(), None));
+    dev.save_with(&mock).unwrap();
+
+    let events = watcher
+        .by_ref()
+        .take(2)
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+
+    assert!(events.contains(&Event::PeerRemoved(Peer::new(b.clone(), None))));
+    assert!(events.contains(&Event::CountersReset {
+        peer: a.clone(),
+        rx_bytes: 100,
+        tx_bytes: 200,
+    }));
+
+    mock.handshake("wg0", &a, later).unwrap();
+
+    let events = watcher.poll().unwrap();
+    assert_eq!(Some(&a), events[0].public_key());
+    assert!(!events.contains(&stale));
+
+    Device::new("wg0").delete_with(&mock).unwrap();
+    assert!(watcher.next().unwrap().is_err());
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn watcher_stream() {
+    use futures_core::Stream;
+    use std::pin::Pin;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mock: &'static Mock = Box::leak(Box::new(Mock::new()));
+    let key = Key::from_bytes([1u8; 32]);
+
+    Device::create_with(mock, "wg0", None).unwrap();
+
+    let mut watcher = Watcher::with_backend(mock, "wg0");
+    watcher.set_interval(Duration::from_millis(1));
+
+    let mut stream = watcher.into_stream();
+
+    runtime.block_on(async {
+        let mut dev = Device::new("wg0");
+        dev.add_peer(Peer::new(key.clone(), None));
+
+        // The first retrieval happens on the first poll of the stream.
+        let next = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx));
+        let first = tokio::time::timeout(Duration::from_millis(20), next).await;
+        assert!(first.is_err());
+
+        dev.save_with(mock).unwrap();
+
+        let next = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx));
+        let event = next.await.unwrap().unwrap();
+
+        assert_eq!(Event::PeerAdded(Peer::new(key.clone(), None)), event);
+    });
+}
+
+#[test]
+fn health_classification() {
+    use crate::health::{Classifier, Health, REJECT_AFTER_TIME};
+
+    let now = SystemTime::now();
+    let ago = |secs| Some(now - Duration::from_secs(secs));
+
+    let mut idle = Peer::new(Key::from_bytes([1u8; 32]), None);
+    let mut kept = Peer::new(Key::from_bytes([2u8; 32]), None);
+    kept.set_persistent_keepalive(25);
+
+    assert_eq!(Health::NeverConnected, health::classify(&idle, now));
+
+    idle.set_last_handshake(Some(now + Duration::from_secs(5)));
+    assert_eq!(Health::Connected, health::classify(&idle, now));
+
+    for (age, idle_health, kept_health) in &[
+        (100, Health::Connected, Health::Connected),
+        // REKEY_AFTER_TIME, plus the keepalive interval and REKEY_TIMEOUT.
+        (150, Health::Connected, Health::Connected),
+        (151, Health::Connected, Health::LikelyDown),
+        (180, Health::Connected, Health::LikelyDown),
+        (200, Health::Stale, Health::LikelyDown),
+        (540, Health::Stale, Health::LikelyDown),
+        (541, Health::LikelyDown, Health::LikelyDown),
+    ] {
+        idle.set_last_handshake(ago(*age));
+        kept.set_last_handshake(ago(*age));
+
+        assert_eq!(*idle_health, health::classify(&idle, now), "{}s", age);
+        assert_eq!(*kept_health, health::classify(&kept, now), "{}s", age);
+    }
+
+    let mut classifier = Classifier::new();
+    classifier.set_connected(Duration::from_secs(60));
+    classifier.set_down(REJECT_AFTER_TIME);
+
+    idle.set_last_handshake(ago(100));
+    assert_eq!(Health::Stale, classifier.classify(&idle, now));
+
+    let mut dev = Device::new("wg0");
+    dev.add_peer(idle);
+    dev.add_peer(kept);
+    dev.add_peer(Peer::new(Key::from_bytes([3u8; 32]), None));
+
+    let summary = classifier.summarize(&dev, now);
+
+    assert_eq!(3, summary.total());
+    assert_eq!(1, summary.count(Health::LikelyDown));
+    assert_eq!(Some(Health::LikelyDown), summary.worst());
+    assert_eq!(
+        "0 connected, 1 stale, 1 likely down, 1 never connected",
+        summary.to_string()
+    );
+    assert_eq!(None, health::summarize(&Device::new("wg1"), now).worst());
+}
+
+#[test]
+fn metrics_rendering() {
+    let (a, b) = (Key::from_bytes([1u8; 32]), Key::from_bytes([2u8; 32]));
+
+    let file = format!(
+        "# Peers\n\n{} laptop \"work\"\n{}  phone\n",
+        a.to_base64(),
+        b.to_base64()
+    );
+    let names = metrics::Names::read(file.as_bytes()).unwrap();
+
+    assert_eq!(Some("laptop \"work\""), names.get(&a));
+    assert_eq!(Some("phone"), names.get(&b));
+    assert!(metrics::Names::read(&b"notakey name\n"[..]).is_err());
+    assert!(metrics::Names::read(a.to_base64().as_bytes()).is_err());
+
+    let mut first = Peer::new(a.clone(), None);
+    first.set_transfer(100, 200);
+    first.set_last_handshake(Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+    first.add_allowed_ip("10.0.0.2/32".parse().unwrap());
+
+    let mut dev = Device::new("wg0");
+    dev.add_peer(first);
+    dev.add_peer(Peer::new(Key::from_bytes([3u8; 32]), None));
+
+    let text = metrics::render(&[dev, Device::new("wg1")], &names);
+    let labels = format!(
+        "interface=\"wg0\",public_key=\"{}\",name=\"laptop \\\"work\\\"\"",
+        a.to_base64()
+    );
+
+    assert!(text.contains("# TYPE wireguard_received_bytes counter\n"));
+    assert!(text.contains("# UNIT wireguard_received_bytes bytes\n"));
+    assert!(text.contains("wireguard_peers{interface=\"wg0\"} 2\n"));
+    assert!(text.contains("wireguard_peers{interface=\"wg1\"} 0\n"));
+    assert!(text.contains(&format!(
+        "wireguard_received_bytes_total{{{}}} 100\n",
+        labels
+    )));
+    assert!(text.contains(&format!("wireguard_sent_bytes_total{{{}}} 200\n", labels)));
+    assert!(text.contains(&format!(
+        "wireguard_latest_handshake_seconds{{{}}} 1600000000\n",
+        labels
+    )));
+    assert!(text.contains(&format!("wireguard_allowed_ips{{{}}} 1\n", labels)));
+    assert_eq!(
+        1,
+        text.matches("wireguard_latest_handshake_seconds{").count()
+    );
+    assert!(text.ends_with("# EOF\n"));
+}
+
+#[test]
+fn stats_sampling() {
+    let key = Key::from_bytes([1u8; 32]);
+    let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let at = |secs| start + Duration::from_secs(secs);
+
+    let device = |rx, tx| {
+        let mut peer = Peer::new(key.clone(), None);
+        peer.set_transfer(rx, tx);
+
+        let mut dev = Device::new("wg0");
+        dev.add_peer(peer);
+        dev
+    };
+
+    let mut sampler = stats::Sampler::new();
+    sampler.set_capacity(4);
+
+    assert!(sampler.record(&device(0, 0), at(0)).is_empty());
+    assert!(sampler.record(&device(1000, 100), at(10)).is_empty());
+    assert!(sampler.record(&device(3000, 300), at(20)).is_empty());
+
+    let history = sampler.history(&key).unwrap();
+    let rate = history.rate(Duration::from_secs(10)).unwrap();
+
+    assert_eq!((200.0, 20.0), (rate.rx(), rate.tx()));
+    assert_eq!(150.0, history.rate(Duration::from_secs(60)).unwrap().rx());
+
+    // The peer was added back: its counters start over, but not its totals.
+    assert_eq!(vec![key.clone()], sampler.record(&device(500, 50), at(30)));
+    assert!(sampler.record(&device(400, 400), at(25)).is_empty());
+    assert!(sampler.record(&device(1500, 150), at(40)).is_empty());
+
+    let history = sampler.history(&key).unwrap();
+
+    assert_eq!(4, history.samples().len());
+    assert_eq!(1, history.resets());
+    assert_eq!((4500, 450), history.totals());
+    assert_eq!(100.0, history.rate(Duration::from_secs(10)).unwrap().rx());
+    assert!(history.rate(Duration::from_secs(0)).is_none());
+
+    assert!(sampler.forget(&key).is_some());
+    assert!(sampler.history(&key).is_none());
+}
+
+#[test]
+fn accounting_quotas() {
+    use crate::accounting::{Accounting, Action, Period, Quota};
+
+    let dir = temp_dir("accounting_quotas");
+    let path = dir.join("usage.db");
+    let mock = Mock::new();
+    let (a, b) = (Key::from_bytes([1u8; 32]), Key::from_bytes([2u8; 32]));
+
+    // 2020-09-13 12:26:40 UTC, then the next day and month.
+    let day = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let next_day = day + Duration::from_secs(86400);
+    let next_month = day + Duration::from_secs(31 * 86400);
+
+    assert_eq!("2020-09-13", Period::Daily.id(day));
+    assert_eq!("2020-09", Period::Monthly.id(day));
+    assert_eq!("2020-10", Period::Monthly.id(next_month));
+    assert_eq!(
+        "2024-02-29",
+        Period::Daily.id(UNIX_EPOCH + Duration::from_secs(1_709_208_000))
+    );
+
+    let mut dev = Device::create_with(&mock, "wg0", None).unwrap();
+
+    for (key, ip) in &[(&a, "10.0.0.1/32"), (&b, "10.0.0.2/32")] {
+        let mut peer = Peer::new((*key).clone(), None);
+        peer.add_allowed_ip(ip.parse().unwrap());
+        dev.add_peer(peer);
+    }
+
+    dev.clone().save_with(&mock).unwrap();
+    mock.transfer("wg0", &a, 600, 400).unwrap();
+
+    let mut db = Accounting::open(&path).unwrap();
+    db.record(&Device::open_with(&mock, "wg0").unwrap(), day);
+    db.set_quota(
+        a.clone(),
+        Quota::new(Period::Monthly, 1500, Action::ClearAllowedIps),
+    );
+    db.set_quota(b.clone(), Quota::new(Period::Daily, 100, Action::Remove));
+    db.save().unwrap();
+
+    // The device was recreated: its counters start over.
+    Device::new("wg0").delete_with(&mock).unwrap();
+    Device::create_with(&mock, "wg0", None).unwrap();
+    dev.save_with(&mock).unwrap();
+    mock.transfer("wg0", &a, 300, 200).unwrap();
+    mock.transfer("wg0", &b, 100, 1).unwrap();
+
+    let mut db = Accounting::open(&path).unwrap();
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+    db.record(&dev, next_day);
+
+    assert_eq!(1500, db.usage(&a, Period::Monthly, next_day).total());
+    assert_eq!(500, db.usage(&a, Period::Daily, next_day).total());
+    assert_eq!(1000, db.usage(&a, Period::Daily, day).total());
+    assert_eq!(0, db.usage(&a, Period::Monthly, next_month).total());
+    assert_eq!(vec![b.clone()], db.exceeded(next_day));
+
+    mock.transfer("wg0", &a, 1, 0).unwrap();
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+    db.record(&dev, next_day);
+
+    assert_eq!(
+        vec![a.clone(), b.clone()],
+        db.enforce_with(&mock, &dev, next_day).unwrap()
+    );
+
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+
+    assert_eq!(1, dev.peers().len());
+    assert!(dev.peers()[0].allowed_ips().is_empty());
+    assert!(db.enforce_with(&mock, &dev, next_day).unwrap().is_empty());
+
+    assert_eq!(3, db.history(&a).len());
+
+    db.prune(next_day);
+    assert_eq!(
+        vec!["2020-09", "2020-09-14"],
+        db.history(&a).iter().map(|(id, _)| *id).collect::<Vec<_>>()
+    );
+
+    db.prune(next_month);
+    assert!(db.history(&a).is_empty());
+
+    fs::write(&path, "usage nope 2020-09 1 2\n").unwrap();
+    assert!(Accounting::open(&path).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reaper_removals() {
+    use crate::metadata::Metadata;
+    use crate::reaper::{Reaper, Reason, Removal};
+
+    let mock = Mock::new();
+    let keys = (1..=4)
+        .map(|i| Key::from_bytes([i; 32]))
+        .collect::<Vec<_>>();
+    let now = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let hours = |n: u64| Duration::from_secs(n * 3600);
+
+    let mut dev = Device::create_with(&mock, "wg0", None).unwrap();
+
+    for key in &keys {
+        dev.add_peer(Peer::new(key.clone(), None));
+    }
+
+    dev.save_with(&mock).unwrap();
+
+    // Stale, fresh, never connected and expired, never connected.
+    mock.handshake("wg0", &keys[0], now - hours(25)).unwrap();
+    mock.handshake("wg0", &keys[1], now - hours(1)).unwrap();
+
+    let mut reaper = Reaper::new();
+    reaper.set_stale_after(hours(24));
+    reaper.set_expiry(keys[2].clone(), now - hours(2));
+    reaper.set_expiry(keys[3].clone(), now + hours(2));
+    reaper.set_dry_run(true);
+
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+    let report = reaper.reap_with(&mock, &dev, now).unwrap();
+
+    assert!(report.dry_run());
+    assert_eq!(2, report.removals().len());
+    assert_eq!(
+        Reason::Stale(now - hours(25)),
+        report.removals()[0].reason()
+    );
+    assert_eq!(
+        Reason::Expired(now - hours(2)),
+        report.removals()[1].reason()
+    );
+    assert_eq!(
+        format!(
+            "wg0: would remove peer {}: latest handshake 1 day, 1 hour ago\n\
+             wg0: would remove peer {}: expired 2 hours ago\n",
+            keys[0].to_base64(),
+            keys[2].to_base64()
+        ),
+        report.to_string()
+    );
+    assert_eq!(4, Device::open_with(&mock, "wg0").unwrap().peers().len());
+
+    reaper.set_dry_run(false);
+
+    let report = reaper.reap_with(&mock, &dev, now).unwrap();
+    let dev = Device::open_with(&mock, "wg0").unwrap();
+
+    assert_eq!(2, report.removals().len());
+    assert_eq!(
+        vec![Some(&keys[1]), Some(&keys[3])],
+        dev.peers().iter().map(Peer::public_key).collect::<Vec<_>>()
+    );
+    assert_eq!(None, reaper.expiry(&keys[2]));
+
+    let report = reaper.reap_with(&mock, &dev, now + hours(3)).unwrap();
+
+    assert_eq!(
+        Reason::Expired(now + hours(2)),
+        report.removals()[0].reason()
+    );
+    assert_eq!(1, Device::open_with(&mock, "wg0").unwrap().peers().len());
+
+    // Expiry times persisted in the metadata of the peers, unless overridden.
+    let mut dev = Device::open_with(&mock, "wg0").unwrap();
+    let mut metadata = Metadata::new();
+
+    metadata.set_expires_at(now - hours(1));
+    dev.peers_mut()[0].set_metadata(metadata);
+
+    let mut reaper = Reaper::new();
+    reaper.set_dry_run(true);
+
+    assert_eq!(
+        Some(Reason::Expired(now - hours(1))),
+        reaper.plan(&dev, now).first().map(Removal::reason)
+    );
+
+    reaper.set_expiry(keys[1].clone(), now + hours(1));
+    assert!(reaper.plan(&dev, now).is_empty());
+}
+
+#[test]
+fn peer_metadata() {
+    use crate::metadata::{Metadata, Store};
+
+    let dir = temp_dir("peer_metadata");
+    let mock = Mock::new();
+    let (laptop, phone) = (Key::from_bytes([1; 32]), Key::from_bytes([2; 32]));
+    let created = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+    let text = format!(
+        "[Interface]\n\
+         ListenPort = 51820\n\
+         \n\
+         # Name = laptop\n\
+         # Tags = office, admin\n\
+         # Owner = alice\n\
+         # CreatedAt = 1600000000\n\
+         # Replaced in 2021, see ticket #12\n\
+         [Peer]\n\
+         PublicKey = {}\n\
+         AllowedIPs = 10.0.0.2/32 # inline comments are dropped\n\
+         \n\
+         [Peer]\n\
+         PublicKey = {}\n\
+         AllowedIPs = 10.0.0.3/32\n",
+        laptop.to_base64(),
+        phone.to_base64()
+    );
+
+    let dev = config::read_device("wg0", text.as_bytes()).unwrap();
+    let metadata = dev.peers()[0].metadata().unwrap();
+
+    assert_eq!(Some("laptop"), metadata.name());
+    assert_eq!(&["office", "admin"], metadata.tags());
+    assert!(metadata.has_tag("admin"));
+    assert_eq!(Some("alice"), metadata.owner());
+    assert_eq!(Some(created), metadata.created_at());
+    assert_eq!(&["Replaced in 2021, see ticket #12"], metadata.comments());
+    assert_eq!(None, dev.peers()[1].metadata());
+
+    let mut buf = Vec::new();
+    config::write_device(&dev, &mut buf).unwrap();
+
+    let written = String::from_utf8(buf).unwrap();
+    assert!(written.contains(&format!(
+        "\n\
+         # Name = laptop\n\
+         # Tags = office, admin\n\
+         # Owner = alice\n\
+         # CreatedAt = 1600000000\n\
+         # Replaced in 2021, see ticket #12\n\
+         [Peer]\n\
+         PublicKey = {}\n",
+        laptop.to_base64()
+    )));
+    assert_eq!(dev, config::read_device("wg0", written.as_bytes()).unwrap());
+
+    // Comments directly above a section header belong to the peer it opens. Inside a section,
+    // only known fields are metadata, and commented out settings never are.
+    let text = format!(
+        "[Interface]\n\
+         ListenPort = 51820\n\
+         \n\
+         # Name = laptop\n\
+         [Peer]\n\
+         PublicKey = {}\n\
+         # Endpoint = 192.0.2.1:51820\n\
+         AllowedIPs = 10.0.0.2/32\n\
+         \n\
+         # Retired\n\
+         #[Peer]\n\
+         #PublicKey = {}\n\
+         #AllowedIPs = 10.0.0.4/32\n\
+         # Lost on 2021-03-04\n\
+         [Peer]\n\
+         PublicKey = {}\n\
+         # Expires soon\n\
+         AllowedIPs = 10.0.0.3/32\n\
+         # Name = phone\n",
+        laptop.to_base64(),
+        Key::from_bytes([3; 32]).to_base64(),
+        phone.to_base64()
+    );
+
+    let commented = config::read_device("wg0", text.as_bytes()).unwrap();
+    let names = |dev: &Device| {
+        dev.peers()
+            .iter()
+            .map(|peer| peer.metadata().and_then(|m| m.name()).map(str::to_owned))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        vec![Some(String::from("laptop")), Some(String::from("phone"))],
+        names(&commented)
+    );
+    assert!(commented.peers()[0]
+        .metadata()
+        .unwrap()
+        .comments()
+        .is_empty());
+    assert_eq!(
+        &["Lost on 2021-03-04"],
+        commented.peers()[1].metadata().unwrap().comments()
+    );
+    assert_eq!(2, commented.peers().len());
+    assert_eq!(None, commented.peers()[0].endpoint());
+
+    let mut buf = Vec::new();
+    config::write_device(&commented, &mut buf).unwrap();
+
+    assert_eq!(
+        commented,
+        config::read_device("wg0", buf.as_slice()).unwrap()
+    );
+
+    // The kernel forgets the metadata, the sidecar store brings it back.
+    let mut store = Store::open_for(dir.join("wg0.conf")).unwrap();
+    store.collect(&dev);
+
+    let mut metadata = Metadata::new();
+    metadata.set_name("phone");
+    metadata.set_expires_at(created + Duration::from_millis(1500));
+    metadata.add_comment("Lost on 2021-03-04");
+    store.set(phone.clone(), metadata);
+    store.save().unwrap();
+
+    assert_eq!(dir.join("wg0.meta"), store.path());
+
+    let store = Store::open_for(dir.join("wg0.conf")).unwrap();
+    let mut created_dev = Device::create_with(&mock, "wg0", None).unwrap();
+
+    for peer in dev.peers() {
+        created_dev.add_peer(peer.clone());
+    }
+
+    created_dev.save_with(&mock).unwrap();
+
+    let mut opened = Device::open_with(&mock, "wg0").unwrap();
+    assert_eq!(None, opened.peers()[0].metadata());
+
+    store.attach(&mut opened);
+
+    assert_eq!(dev.peers()[0].metadata(), opened.peers()[0].metadata());
+    assert_eq!(
+        Some("phone"),
+        opened.peers()[1].metadata().and_then(|m| m.name())
+    );
+    assert_eq!(
+        &["Lost on 2021-03-04"],
+        opened.peers()[1].metadata().unwrap().comments()
+    );
+    assert_eq!(
+        Some(created + Duration::from_secs(1)),
+        opened.peers()[1].metadata().unwrap().expires_at()
+    );
+
+    assert_eq!(
+        opened,
+        Device::open_annotated_in(&mock, &dir, "wg0").unwrap()
+    );
+
+    fs::write(dir.join("bad.meta"), "Name = orphan\n").unwrap();
+    assert_eq!(
+        io::ErrorKind::InvalidData,
+        Store::open(dir.join("bad.meta")).unwrap_err().kind()
+    );
+
+    // A corrupt store does not prevent opening the device.
+    fs::rename(dir.join("bad.meta"), dir.join("wg0.meta")).unwrap();
+    assert_eq!(
+        Device::open_with(&mock, "wg0").unwrap(),
+        Device::open_annotated_in(&mock, &dir, "wg0").unwrap()
+    );
+}
+
+#[test]
+fn client_configs() {
+    use crate::client::{Error, Generator, Tunnel};
+
+    let server_key = Key::from_bytes([1; 32]);
+    let client_key = Key::from_bytes([2; 32]);
+    let psk = Key::from_bytes([3; 32]);
+    let endpoint = (IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), 51820);
+
+    let mut peer = Peer::new(client_key.derive_public(), None);
+    peer.set_preshared_key(psk.clone());
+    peer.add_allowed_ip("10.0.0.2/32".parse().unwrap());
+    peer.add_allowed_ip("fd00::2/128".parse().unwrap());
+    peer.add_allowed_ip("192.168.5.0/24".parse().unwrap());
+
+    let mut server = Device::new("wg0");
+    server.set_private_key(server_key.clone());
+    server.set_listen_port(51820);
+    server.add_peer(peer.clone());
+
+    let mut generator = Generator::new(endpoint);
+    generator.add_dns("10.0.0.1");
+    generator.set_persistent_keepalive(25);
+
+    let config = generator.generate(&server, &peer, &client_key).unwrap();
+
+    let mut buf = Vec::new();
+    config::write(&config, &mut buf).unwrap();
+
+    assert_eq!(
+        format!(
+            "[Interface]\n\
+             Address = 10.0.0.2/32, fd00::2/128\n\
+             DNS = 10.0.0.1\n\
+             PrivateKey = {}\n\
+             \n\
+             [Peer]\n\
+             PublicKey = {}\n\
+             PresharedKey = {}\n\
+             AllowedIPs = 0.0.0.0/0, ::/0\n\
+             Endpoint = 203.0.113.1:51820\n\
+             PersistentKeepalive = 25\n",
+            client_key.to_base64(),
+            server_key.derive_public().to_base64(),
+            psk.to_base64()
+        ),
+        String::from_utf8(buf).unwrap()
+    );
+
+    generator.set_tunnel(Tunnel::Split(vec!["10.0.0.0/24".parse().unwrap()]));
+
+    let config = generator.generate(&server, &peer, &client_key).unwrap();
+    assert_eq!(
+        &["10.0.0.0/24".parse::<AllowedIp>().unwrap()],
+        config.device().peers()[0].allowed_ips()
+    );
+
+    assert_eq!(
+        Err(Error::KeyMismatch),
+        generator.generate(&server, &peer, &psk)
+    );
+    assert_eq!(
+        Err(Error::MissingServerKey),
+        generator.generate(&Device::new("wg0"), &peer, &client_key)
+    );
+
+    peer.allowed_ips_mut().clear();
+    assert_eq!(
+        Err(Error::NoAddress),
+        generator.generate(&server, &peer, &client_key)
+    );
+}
+
+#[cfg(feature = "qr")]
+#[test]
+fn qr_rendering() {
+    use crate::qr::{Code, EcLevel, QUIET_ZONE};
+
+    let dir = temp_dir("qr_rendering");
+    let mut device = Device::new("wg0");
+    device.set_private_key(Key::from_bytes([1; 32]));
+
+    let mut peer = Peer::new(Key::from_bytes([2; 32]), None);
+    peer.add_allowed_ip("0.0.0.0/0".parse().unwrap());
+    device.add_peer(peer);
+
+    let config = config::Config::new(device);
+    let low = Code::from_config(&config, EcLevel::Low).unwrap();
+    let high = Code::from_config(&config, EcLevel::High).unwrap();
+
+    assert!(high.size() > low.size());
+    assert_eq!(0, (low.size() - 17) % 4);
+
+    // Finder patterns in the corners.
+    assert!(low.is_dark(0, 0) && low.is_dark(6, 6) && !low.is_dark(1, 1));
+    assert!(!low.is_dark(7, 7));
+
+    let side = low.size() + 2 * QUIET_ZONE;
+    let terminal = low.to_terminal();
+
+    assert_eq!(side.div_ceil(2), terminal.lines().count());
+    assert!(terminal.lines().all(|line| line.chars().count() == side));
+    assert!(terminal.starts_with(&"█".repeat(side)));
+
+    let svg = low.to_svg();
+    assert!(svg.contains(&format!("viewBox=\"0 0 {0} {0}\"", side)));
+    assert!(svg.contains(&format!("M{0},{0}h1v1h-1z", QUIET_ZONE)));
+
+    let png = low.to_png(4);
+    fs::write(dir.join("code.png"), &png).unwrap();
+
+    let mut decoder = png::Decoder::new(fs::File::open(dir.join("code.png")).unwrap());
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    let mut reader = decoder.read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+
+    assert_eq!((side * 4) as u32, info.width);
+    assert_eq!((side * 4) as u32, info.height);
+
+    for y in 0..side * 4 {
+        for x in 0..side * 4 {
+            let dark = x >= QUIET_ZONE * 4
+                && y >= QUIET_ZONE * 4
+                && low.is_dark(x / 4 - QUIET_ZONE, y / 4 - QUIET_ZONE);
+
+            assert_eq!(dark, pixels[y * info.line_size + x] == 0);
+        }
+    }
+
+    assert_eq!(Ok(EcLevel::Quartile), "q".parse());
+
+    let err = Code::encode(&"x".repeat(3000), EcLevel::High).unwrap_err();
+    assert_eq!(3000, err.bytes());
+    assert_eq!(EcLevel::High, err.level());
+    assert!(Code::encode(&"x".repeat(2900), EcLevel::Low).is_ok());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mesh_generation() {
+    use crate::topology::{Error, Mesh, Node};
+
+    let mut mesh = Mesh::new();
+    mesh.set_preshared_keys(true);
+    mesh.set_persistent_keepalive(25);
+
+    for (i, name) in ["c", "a", "b"].iter().enumerate() {
+        let mut node = Node::new(*name, format!("10.10.0.{}/24", i + 1).parse().unwrap());
+
+        if *name != "c" {
+            node.set_endpoint((IpAddr::V4(Ipv4Addr::new(198, 51, 100, i as u8)), 51820));
+        }
+
+        mesh.add_node(node);
+    }
+
+    let existing = Key::from_bytes([7; 32]);
+    let mut node = Node::new("d", "10.10.0.4/24".parse().unwrap());
+    node.set_private_key(existing.clone());
+
+    let configs = mesh.generate().unwrap();
+    let public = |name: &str| {
+        mesh.node(name)
+            .and_then(|node| node.private_key())
+            .map(Key::derive_public)
+    };
+
+    assert_eq!(vec!["a", "b", "c"], configs.keys().collect::<Vec<_>>());
+
+    let a = configs["a"].device();
+    assert_eq!(Some(51820), a.listen_port());
+    assert_eq!(None, configs["c"].device().listen_port());
+    assert_eq!(
+        &["10.10.0.2/24".parse::<Address>().unwrap()],
+        configs["a"].addresses()
+    );
+    assert_eq!(
+        vec![public("b").as_ref(), public("c").as_ref()],
+        a.peers().iter().map(Peer::public_key).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        &["10.10.0.1/32".parse::<AllowedIp>().unwrap()],
+        a.peers()[1].allowed_ips()
+    );
+    assert_eq!(None, a.peers()[1].endpoint());
+    assert_eq!(Some(25), a.peers()[1].persistent_keepalive());
+    assert_eq!(Some("c"), a.peers()[1].metadata().and_then(|m| m.name()));
+
+    // Both ends of a pair share its preshared key.
+    let c = configs["c"].device();
+    assert_eq!(
+        a.peers()[1].preshared_key(),
+        c.peers()
+            .iter()
+            .find(|p| p.public_key() == public("a").as_ref())
+            .unwrap()
+            .preshared_key()
+    );
+    assert_ne!(a.peers()[0].preshared_key(), a.peers()[1].preshared_key());
+
+    // Adding a node keeps the keys and the peers of the other nodes.
+    mesh.add_node(node);
+
+    let regenerated = mesh.generate().unwrap();
+    let a2 = regenerated["a"].device();
+
+    assert_eq!(a.private_key(), a2.private_key());
+    assert_eq!(a.peers(), &a2.peers()[..2]);
+    assert_eq!(Some(&existing.derive_public()), a2.peers()[2].public_key());
+    assert_eq!(3, regenerated["d"].device().peers().len());
+    assert_eq!(regenerated, mesh.generate().unwrap());
+
+    mesh.add_node(Node::new("e", "10.10.0.4/24".parse().unwrap()));
+    assert_eq!(
+        Err(Error::DuplicateAddress("e".to_owned())),
+        mesh.generate()
+    );
+}
+
+#[test]
+fn hub_generation() {
+    use crate::topology::{Error, Hub, Node};
+
+    let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+
+    assert!(ip("10.0.0.0/8").overlaps(&ip("10.1.2.0/24")));
+    assert!(ip("10.1.2.0/24").overlaps(&ip("10.0.0.0/8")));
+    assert!(!ip("10.1.2.0/24").overlaps(&ip("10.1.3.0/24")));
+    assert!(!ip("0.0.0.0/0").overlaps(&ip("::/0")));
+
+    let mut center = Node::new("hub", "10.0.0.1/24".parse().unwrap());
+    center.add_subnet(ip("192.168.0.0/24"));
+
+    let mut hub = Hub::new(center.clone());
+    assert_eq!(
+        Err(Error::MissingEndpoint("hub".to_owned())),
+        hub.generate()
+    );
+
+    center.set_endpoint((IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), 51820));
+
+    let mut hub = Hub::new(center);
+    hub.set_preshared_keys(true);
+    hub.set_persistent_keepalive(25);
+
+    for i in 1..=2 {
+        let mut spoke = Node::new(
+            format!("site{}", i),
+            format!("10.0.0.{}/24", i + 1).parse().unwrap(),
+        );
+        spoke.add_subnet(ip(&format!("192.168.{}.0/24", i)));
+        hub.add_spoke(spoke);
+    }
+
+    let configs = hub.generate().unwrap();
+    assert_eq!(
+        vec!["hub", "site1", "site2"],
+        configs.keys().collect::<Vec<_>>()
+    );
+
+    let center = configs["hub"].device();
+    assert_eq!(Some(51820), center.listen_port());
+    assert_eq!(2, center.peers().len());
+    assert_eq!(
+        &[ip("10.0.0.2/32"), ip("192.168.1.0/24")],
+        center.peers()[0].allowed_ips()
+    );
+    assert_eq!(None, center.peers()[0].persistent_keepalive());
+
+    let site1 = configs["site1"].device();
+    let to_hub = &site1.peers()[0];
+
+    assert_eq!(1, site1.peers().len());
+    assert_eq!(center.public_key().as_ref(), to_hub.public_key());
+    assert_eq!(
+        &[
+            ip("10.0.0.0/24"),
+            ip("192.168.0.0/24"),
+            ip("192.168.2.0/24")
+        ],
+        to_hub.allowed_ips()
+    );
+    assert_eq!(Some(25), to_hub.persistent_keepalive());
+    assert_eq!(
+        Some(&(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), 51820)),
+        to_hub.endpoint()
+    );
+    assert_eq!(hub.preshared_key("site1"), to_hub.preshared_key());
+    assert_eq!(center.peers()[0].preshared_key(), to_hub.preshared_key());
+
+    let mut overlapping = Node::new("site3", "10.0.0.4/24".parse().unwrap());
+    overlapping.add_subnet(ip("192.168.1.128/25"));
+    hub.add_spoke(overlapping);
+
+    assert_eq!(
+        Err(Error::OverlappingSubnet(
+            "site1".to_owned(),
+            "site3".to_owned()
+        )),
+        hub.generate()
+    );
+
+    hub.remove_spoke("site3");
+
+    let mut overlapping = Node::new("site4", "10.0.1.1/24".parse().unwrap());
+    overlapping.add_subnet(ip("10.0.0.0/16"));
+    hub.add_spoke(overlapping);
+
+    assert_eq!(
+        Err(Error::OverlappingSubnet(
+            "hub".to_owned(),
+            "site4".to_owned()
+        )),
+        hub.generate()
+    );
+}
+
+#[test]
+fn network_checks() {
+    use crate::check::{Checker, Diagnostic, Problem, Severity};
+    use crate::topology::{Mesh, Node};
+
+    let dir = temp_dir("network_checks");
+    let mut mesh = Mesh::new();
+    mesh.set_persistent_keepalive(25);
+
+    for (i, name) in ["a", "b", "c"].iter().enumerate() {
+        let mut node = Node::new(*name, format!("10.10.0.{}/24", i + 1).parse().unwrap());
+
+        if *name != "c" {
+            node.set_endpoint((IpAddr::V4(Ipv4Addr::new(198, 51, 100, i as u8)), 51820));
+        }
+
+        mesh.add_node(node);
+    }
+
+    let configs = mesh.generate().unwrap();
+    let problems = |checker: &Checker| {
+        checker
+            .check()
+            .iter()
+            .map(|d| (d.node().to_owned(), d.problem().clone()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut checker = Checker::new();
+
+    for name in &["a", "b", "c"] {
+        let path = dir.join(format!("{}.conf", name));
+        config::write(&configs[*name], fs::File::create(&path).unwrap()).unwrap();
+
+        checker.add_file(&path).unwrap();
+    }
+
+    assert_eq!(Vec::<Diagnostic>::new(), checker.check());
+
+    // b moved to another port, lost its keepalives, and lists c with an old key and a stray
+    // peer.
+    let key = |name: &str| {
+        mesh.node(name)
+            .unwrap()
+            .private_key()
+            .unwrap()
+            .derive_public()
+    };
+    let old = Key::from_bytes([9; 32]);
+    let stray = Key::from_bytes([10; 32]);
+
+    let mut b = configs["b"].clone();
+    b.device_mut().set_listen_port(51821);
+
+    for peer in b.device_mut().peers_mut() {
+        peer.set_persistent_keepalive(0);
+
+        if peer.public_key() == Some(&key("c")) {
+            peer.set_public_key(old.clone());
+            peer.metadata_mut().set_name("laptop");
+        }
+    }
+
+    let mut peer = Peer::new(stray.clone(), None);
+    peer.add_allowed_ip("10.10.0.1/32".parse().unwrap());
+    b.device_mut().add_peer(peer);
+
+    let mut c = configs["c"].clone();
+    c.device_mut().peers_mut()[0].set_persistent_keepalive(0);
+
+    // d and c only know each other, without endpoints.
+    let d_key = Key::from_bytes([11; 32]);
+    let mut d = Device::new("wg0");
+    d.set_private_key(d_key.clone());
+    d.add_peer(Peer::new(key("c"), None));
+    c.device_mut()
+        .add_peer(Peer::new(d_key.derive_public(), None));
+
+    let mut checker = Checker::new();
+    checker.add_config("a", &configs["a"]);
+    checker.add_config("b", &b);
+    checker.add_config("c", &c);
+    checker.add_device("d", d);
+
+    let b_port = Problem::EndpointPortMismatch {
+        peer: "b".to_owned(),
+        port: 51820,
+        listen_port: Some(51821),
+    };
+
+    assert_eq!(
+        vec![
+            ("a".to_owned(), b_port.clone()),
+            (
+                "b".to_owned(),
+                Problem::KeyMismatch {
+                    peer: "c".to_owned(),
+                    key: old.clone(),
+                }
+            ),
+            (
+                "b".to_owned(),
+                Problem::OverlappingAllowedIps {
+                    first: key("a"),
+                    second: stray.clone(),
+                    ip: "10.10.0.1/32".parse().unwrap(),
+                }
+            ),
+            ("b".to_owned(), Problem::UnknownKey { key: stray.clone() }),
+            (
+                "c".to_owned(),
+                Problem::MissingKeepalive {
+                    peer: "a".to_owned()
+                }
+            ),
+            ("c".to_owned(), b_port),
+            (
+                "c".to_owned(),
+                Problem::AsymmetricPeering {
+                    peer: "b".to_owned()
+                }
+            ),
+            (
+                "c".to_owned(),
+                Problem::Unreachable {
+                    peer: "d".to_owned()
+                }
+            ),
+        ],
+        problems(&checker)
+    );
+
+    let diagnostics = checker.check();
+
+    assert_eq!(Severity::Error, diagnostics[0].severity());
+    assert_eq!(Severity::Warning, diagnostics[4].severity());
+    assert_eq!(
+        "error: a: endpoint port 51820 of peer b is not its listen port 51821",
+        diagnostics[0].to_string()
+    );
 }
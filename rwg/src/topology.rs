@@ -0,0 +1,578 @@
+//! Generation of the configurations of whole networks.
+//!
+//! A `Mesh` connects every node to every other node, while a `Hub` connects spokes to a hub which
+//! forwards their traffic to each other. Nodes are given as a name, a tunnel address and an
+//! optional endpoint and private key, along with the subnets routed to them, such as the LAN of a
+//! site. Missing keys are generated and kept, so that generating again after adding a node only
+//! adds that node to the existing configurations.
+
+use std::collections::{BTreeMap, HashSet};
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::config::Config;
+use crate::device::Device;
+use crate::key::Key;
+use crate::link::Address;
+use crate::peer::{AllowedIp, Endpoint, Peer};
+
+/// Name of the devices of the generated configurations when none is given.
+pub const DEFAULT_INTERFACE: &str = "wg0";
+
+/// A machine of a network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    name: String,
+    address: Address,
+    endpoint: Option<Endpoint>,
+    private_key: Option<Key>,
+    subnets: Vec<AllowedIp>,
+}
+
+impl Node {
+    /// Create a node with its address inside the tunnel, such as `10.0.0.1/24`, unreachable from
+    /// the other nodes until given an endpoint.
+    pub fn new<S: Into<String>>(name: S, address: Address) -> Node {
+        Node {
+            name: name.into(),
+            address: address,
+            endpoint: None,
+            private_key: None,
+            subnets: Vec::new(),
+        }
+    }
+
+    /// Set the public IP address and port at which the other nodes reach this one. The port is
+    /// also the listen port of its device.
+    pub fn set_endpoint(&mut self, endpoint: Endpoint) {
+        self.endpoint = Some(endpoint);
+    }
+
+    /// Set the private key of the node, instead of having one generated.
+    pub fn set_private_key(&mut self, key: Key) {
+        self.private_key = Some(key);
+    }
+
+    /// Route a subnet to the node, such as the LAN of the site it connects.
+    pub fn add_subnet(&mut self, subnet: AllowedIp) {
+        self.subnets.push(subnet);
+    }
+
+    /// Get the name of the node.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the address of the node inside the tunnel.
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Get the endpoint of the node, if reachable.
+    pub fn endpoint(&self) -> Option<&Endpoint> {
+        self.endpoint.as_ref()
+    }
+
+    /// Get the private key of the node, if given or generated.
+    pub fn private_key(&self) -> Option<&Key> {
+        self.private_key.as_ref()
+    }
+
+    /// Get the subnets routed to the node.
+    pub fn subnets(&self) -> &[AllowedIp] {
+        &self.subnets
+    }
+
+    /// Get the allowed IP routing the tunnel address of the node to it.
+    fn host(&self) -> AllowedIp {
+        let mask = match self.address.addr() {
+            addr if addr.is_ipv4() => 32,
+            _ => 128,
+        };
+
+        AllowedIp::new(*self.address.addr(), mask)
+    }
+
+    /// Generate the private key of the node if it has none.
+    fn generate_key(&mut self) {
+        self.private_key.get_or_insert_with(Key::generate_private);
+    }
+
+    /// Make the device of the node, without peers.
+    fn device(&self, interface: &str) -> Device {
+        let mut device = Device::new(interface);
+
+        if let Some(key) = &self.private_key {
+            device.set_private_key(key.clone());
+        }
+
+        if let Some((_, port)) = self.endpoint {
+            device.set_listen_port(port);
+        }
+
+        device
+    }
+
+    /// Make the peer of the node on the devices of other nodes, named after it. The key of the
+    /// node must have been generated.
+    fn peer(&self) -> Peer {
+        let key = self.private_key.as_ref().expect("node without a key");
+
+        let mut peer = Peer::new(key.derive_public(), self.endpoint);
+        peer.metadata_mut().set_name(self.name.as_str());
+
+        peer
+    }
+}
+
+/// A network in which every node is a peer of every other node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mesh {
+    interface: String,
+    nodes: Vec<Node>,
+    preshared_keys: bool,
+    pairs: BTreeMap<(String, String), Key>,
+    persistent_keepalive: Option<u16>,
+}
+
+impl Default for Mesh {
+    fn default() -> Mesh {
+        Mesh::new()
+    }
+}
+
+impl Mesh {
+    /// Create an empty mesh, without preshared keys nor keepalives.
+    pub fn new() -> Mesh {
+        Mesh {
+            interface: DEFAULT_INTERFACE.to_owned(),
+            nodes: Vec::new(),
+            preshared_keys: false,
+            pairs: BTreeMap::new(),
+            persistent_keepalive: None,
+        }
+    }
+
+    /// Set the name of the devices of the generated configurations.
+    pub fn set_interface<S: Into<String>>(&mut self, name: S) {
+        self.interface = name.into();
+    }
+
+    /// Add a node to the mesh.
+    pub fn add_node(&mut self, node: Node) {
+        self.nodes.push(node);
+    }
+
+    /// Remove a node from the mesh, along with its preshared keys.
+    pub fn remove_node(&mut self, name: &str) -> Option<Node> {
+        let position = self.nodes.iter().position(|node| node.name == name)?;
+
+        self.pairs.retain(|(a, b), _| a != name && b != name);
+
+        Some(self.nodes.remove(position))
+    }
+
+    /// Choose whether every pair of nodes shares a preshared key. Missing keys are generated and
+    /// kept. Defaults to `false`.
+    pub fn set_preshared_keys(&mut self, enabled: bool) {
+        self.preshared_keys = enabled;
+    }
+
+    /// Set the preshared key of a pair of nodes, instead of having one generated.
+    pub fn set_preshared_key(&mut self, a: &str, b: &str, key: Key) {
+        self.pairs.insert(pair(a, b), key);
+    }
+
+    /// Set the interval in seconds at which every node sends keepalive packets to the others. An
+    /// interval of zero disables them.
+    pub fn set_persistent_keepalive(&mut self, interval: u16) {
+        self.persistent_keepalive = match interval {
+            0 => None,
+            interval => Some(interval),
+        };
+    }
+
+    /// Get the name of the devices of the generated configurations.
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Get the nodes of the mesh, in the order they were added.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Get a node by name.
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.name == name)
+    }
+
+    /// Whether every pair of nodes shares a preshared key.
+    pub fn preshared_keys(&self) -> bool {
+        self.preshared_keys
+    }
+
+    /// Get the preshared key of a pair of nodes, if given or generated.
+    pub fn preshared_key(&self, a: &str, b: &str) -> Option<&Key> {
+        self.pairs.get(&pair(a, b))
+    }
+
+    /// Get the keepalive interval of the nodes, if enabled.
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+
+    /// Generate the configuration of every node, by name. Peers are sorted by name, so that the
+    /// same mesh always gives the same configurations.
+    pub fn generate(&mut self) -> Result<BTreeMap<String, Config>, Error> {
+        check_nodes(&self.nodes)?;
+
+        for node in &mut self.nodes {
+            node.generate_key();
+        }
+
+        let mut sorted = self.nodes.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.name.cmp(&b.name));
+
+        if self.preshared_keys {
+            for (i, a) in sorted.iter().enumerate() {
+                for b in &sorted[i + 1..] {
+                    self.pairs
+                        .entry(pair(&a.name, &b.name))
+                        .or_insert_with(Key::generate_preshared);
+                }
+            }
+        }
+
+        let mut configs = BTreeMap::new();
+
+        for node in &sorted {
+            let mut device = node.device(&self.interface);
+
+            for other in sorted.iter().filter(|other| other.name != node.name) {
+                let mut allowed_ips = vec![other.host()];
+                allowed_ips.extend(other.subnets.iter().cloned());
+
+                device.add_peer(link(
+                    &self.pairs,
+                    self.persistent_keepalive,
+                    node,
+                    other,
+                    allowed_ips,
+                ));
+            }
+
+            let mut config = Config::new(device);
+            config.add_address(node.address.clone());
+
+            configs.insert(node.name.clone(), config);
+        }
+
+        Ok(configs)
+    }
+}
+
+/// A network in which spokes are peers of a hub only, and reach each other through it. The hub
+/// must forward packets between its peers, which `net.ipv4.ip_forward` and its IPv6 counterpart
+/// enable on Linux.
+///
+/// The spokes route the network of the tunnel address of the hub to it, along with the subnets of
+/// the hub and of the other spokes. A hub with a single spoke connects two sites.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hub {
+    interface: String,
+    hub: Node,
+    spokes: Vec<Node>,
+    preshared_keys: bool,
+    pairs: BTreeMap<(String, String), Key>,
+    persistent_keepalive: Option<u16>,
+}
+
+impl Hub {
+    /// Create a network around a hub, which needs an endpoint for the spokes to reach it. Its
+    /// tunnel address gives the network of the tunnel, such as `10.0.0.1/24`.
+    pub fn new(hub: Node) -> Hub {
+        Hub {
+            interface: DEFAULT_INTERFACE.to_owned(),
+            hub: hub,
+            spokes: Vec::new(),
+            preshared_keys: false,
+            pairs: BTreeMap::new(),
+            persistent_keepalive: None,
+        }
+    }
+
+    /// Set the name of the devices of the generated configurations.
+    pub fn set_interface<S: Into<String>>(&mut self, name: S) {
+        self.interface = name.into();
+    }
+
+    /// Add a spoke to the network.
+    pub fn add_spoke(&mut self, spoke: Node) {
+        self.spokes.push(spoke);
+    }
+
+    /// Remove a spoke from the network, along with its preshared key.
+    pub fn remove_spoke(&mut self, name: &str) -> Option<Node> {
+        let position = self.spokes.iter().position(|spoke| spoke.name == name)?;
+
+        self.pairs.remove(&pair(&self.hub.name, name));
+
+        Some(self.spokes.remove(position))
+    }
+
+    /// Choose whether every spoke shares a preshared key with the hub. Missing keys are generated
+    /// and kept. Defaults to `false`.
+    pub fn set_preshared_keys(&mut self, enabled: bool) {
+        self.preshared_keys = enabled;
+    }
+
+    /// Set the preshared key of a spoke, instead of having one generated.
+    pub fn set_preshared_key(&mut self, spoke: &str, key: Key) {
+        self.pairs.insert(pair(&self.hub.name, spoke), key);
+    }
+
+    /// Set the interval in seconds at which the spokes send keepalive packets to the hub, which
+    /// keeps open the NAT mappings of the spokes behind NAT. An interval of zero disables them.
+    pub fn set_persistent_keepalive(&mut self, interval: u16) {
+        self.persistent_keepalive = match interval {
+            0 => None,
+            interval => Some(interval),
+        };
+    }
+
+    /// Get the name of the devices of the generated configurations.
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Get the hub.
+    pub fn hub(&self) -> &Node {
+        &self.hub
+    }
+
+    /// Get the spokes, in the order they were added.
+    pub fn spokes(&self) -> &[Node] {
+        &self.spokes
+    }
+
+    /// Get a spoke by name.
+    pub fn spoke(&self, name: &str) -> Option<&Node> {
+        self.spokes.iter().find(|spoke| spoke.name == name)
+    }
+
+    /// Whether every spoke shares a preshared key with the hub.
+    pub fn preshared_keys(&self) -> bool {
+        self.preshared_keys
+    }
+
+    /// Get the preshared key of a spoke, if given or generated.
+    pub fn preshared_key(&self, spoke: &str) -> Option<&Key> {
+        self.pairs.get(&pair(&self.hub.name, spoke))
+    }
+
+    /// Get the keepalive interval of the spokes, if enabled.
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+
+    /// Generate the configuration of the hub and of every spoke, by name. Peers are sorted by
+    /// name, so that the same network always gives the same configurations.
+    pub fn generate(&mut self) -> Result<BTreeMap<String, Config>, Error> {
+        if self.hub.endpoint.is_none() {
+            return Err(Error::MissingEndpoint(self.hub.name.clone()));
+        }
+
+        check_nodes(Some(&self.hub).into_iter().chain(&self.spokes))?;
+
+        let network =
+            AllowedIp::new(*self.hub.address.addr(), self.hub.address.prefix_len()).network();
+
+        for node in Some(&self.hub).into_iter().chain(&self.spokes) {
+            if node.subnets.iter().any(|subnet| subnet.overlaps(&network)) {
+                return Err(Error::OverlappingSubnet(
+                    node.name.clone(),
+                    self.hub.name.clone(),
+                ));
+            }
+        }
+
+        self.hub.generate_key();
+
+        for spoke in &mut self.spokes {
+            spoke.generate_key();
+        }
+
+        let mut spokes = self.spokes.iter().collect::<Vec<_>>();
+        spokes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        if self.preshared_keys {
+            for spoke in &spokes {
+                self.pairs
+                    .entry(pair(&self.hub.name, &spoke.name))
+                    .or_insert_with(Key::generate_preshared);
+            }
+        }
+
+        let mut hub = self.hub.device(&self.interface);
+        let mut configs = BTreeMap::new();
+
+        for spoke in &spokes {
+            let mut allowed_ips = vec![spoke.host()];
+            allowed_ips.extend(spoke.subnets.iter().cloned());
+
+            hub.add_peer(link(&self.pairs, None, &self.hub, spoke, allowed_ips));
+
+            let mut allowed_ips = vec![network.clone()];
+
+            for other in spokes.iter().filter(|other| other.name != spoke.name) {
+                if !network.contains(other.address.addr()) {
+                    allowed_ips.push(other.host());
+                }
+            }
+
+            allowed_ips.extend(self.hub.subnets.iter().cloned());
+
+            for other in spokes.iter().filter(|other| other.name != spoke.name) {
+                allowed_ips.extend(other.subnets.iter().cloned());
+            }
+
+            let mut device = spoke.device(&self.interface);
+            device.add_peer(link(
+                &self.pairs,
+                self.persistent_keepalive,
+                spoke,
+                &self.hub,
+                allowed_ips,
+            ));
+
+            let mut config = Config::new(device);
+            config.add_address(spoke.address.clone());
+
+            configs.insert(spoke.name.clone(), config);
+        }
+
+        let mut config = Config::new(hub);
+        config.add_address(self.hub.address.clone());
+
+        configs.insert(self.hub.name.clone(), config);
+
+        Ok(configs)
+    }
+}
+
+/// Make the peer of `other` on the device of `node`, routing `allowed_ips` to it.
+fn link(
+    pairs: &BTreeMap<(String, String), Key>,
+    persistent_keepalive: Option<u16>,
+    node: &Node,
+    other: &Node,
+    allowed_ips: Vec<AllowedIp>,
+) -> Peer {
+    let mut peer = other.peer();
+
+    for ip in allowed_ips {
+        peer.add_allowed_ip(ip);
+    }
+
+    if let Some(key) = pairs.get(&pair(&node.name, &other.name)) {
+        peer.set_preshared_key(key.clone());
+    }
+
+    if let Some(interval) = persistent_keepalive {
+        peer.set_persistent_keepalive(interval);
+    }
+
+    peer
+}
+
+/// Check that the names and addresses of nodes are unique, and so are their keys if given.
+/// Subnets must not overlap the addresses and subnets of other nodes.
+fn check_nodes<'a, I: IntoIterator<Item = &'a Node>>(nodes: I) -> Result<(), Error> {
+    let nodes = nodes.into_iter().collect::<Vec<_>>();
+    let (mut names, mut addresses, mut keys) = (HashSet::new(), HashSet::new(), HashSet::new());
+
+    for node in &nodes {
+        if !names.insert(node.name.as_str()) {
+            return Err(Error::DuplicateName(node.name.clone()));
+        }
+
+        if !addresses.insert(*node.address.addr()) {
+            return Err(Error::DuplicateAddress(node.name.clone()));
+        }
+
+        if let Some(key) = &node.private_key {
+            if !keys.insert(key.clone()) {
+                return Err(Error::DuplicateKey(node.name.clone()));
+            }
+        }
+    }
+
+    for (i, node) in nodes.iter().enumerate() {
+        for other in nodes.iter().skip(i + 1) {
+            let overlap = |a: &Node, b: &Node| {
+                a.subnets.iter().any(|subnet| {
+                    subnet.overlaps(&b.host()) || b.subnets.iter().any(|s| s.overlaps(subnet))
+                })
+            };
+
+            if overlap(node, other) || overlap(other, node) {
+                return Err(Error::OverlappingSubnet(
+                    node.name.clone(),
+                    other.name.clone(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Identify a pair of nodes regardless of their order.
+fn pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_owned(), b.to_owned())
+    } else {
+        (b.to_owned(), a.to_owned())
+    }
+}
+
+/// Errors that can happen when generating the configurations of a network. Nodes are given by
+/// name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Two nodes have the same name.
+    DuplicateName(String),
+    /// The node has the tunnel address of a previous node.
+    DuplicateAddress(String),
+    /// The node has the private key of a previous node.
+    DuplicateKey(String),
+    /// A subnet of the first node overlaps an address or subnet of the second, or the network of
+    /// the tunnel when the second is a hub.
+    OverlappingSubnet(String, String),
+    /// The node needs an endpoint to be reached by the others.
+    MissingEndpoint(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DuplicateName(name) => write!(f, "duplicate node name: {}", name),
+            Error::DuplicateAddress(name) => write!(f, "{}: duplicate tunnel address", name),
+            Error::DuplicateKey(name) => write!(f, "{}: duplicate private key", name),
+            Error::OverlappingSubnet(name, other) => {
+                write!(f, "{}: subnet overlapping the addresses of {}", name, other)
+            }
+            Error::MissingEndpoint(name) => write!(f, "{}: no endpoint", name),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
@@ -0,0 +1,385 @@
+//! Cross-platform userspace API (UAPI) protocol.
+//!
+//! Userspace WireGuard implementations such as wireguard-go and boringtun are configured through
+//! a line-based `key=value` text protocol where keys are hex-encoded. A `set=1` operation applies
+//! changes to a device, and a `get=1` operation dumps its current state. Both are answered with an
+//! `errno=` line followed by an empty line.
+
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::device::Device;
+use crate::key::Key;
+use crate::peer::{AllowedIp, Peer};
+
+/// The only protocol version currently defined by WireGuard.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Write a `get=1` request.
+pub fn write_get<W: Write>(mut w: W) -> io::Result<()> {
+    w.write_all(b"get=1\n\n")?;
+    w.flush()
+}
+
+/// Write a `set=1` request that applies the changes described by `device`: its private key,
+/// listen port and firewall mark when they are set, then every peer along with its
+/// `replace_peers`, `remove`, `update_only` and `replace_allowed_ips` flags.
+pub fn write_set<W: Write>(device: &Device, mut w: W) -> io::Result<()> {
+    let mut buf = String::from("set=1\n");
+
+    if let Some(key) = device.private_key() {
+        buf.push_str(&format!("private_key={}\n", key.to_hex()));
+    }
+
+    if let Some(port) = device.listen_port() {
+        buf.push_str(&format!("listen_port={}\n", port));
+    }
+
+    if let Some(fwmark) = device.fwmark() {
+        buf.push_str(&format!("fwmark={}\n", fwmark));
+    }
+
+    if device.replace_peers() {
+        buf.push_str("replace_peers=true\n");
+    }
+
+    for peer in device.peers() {
+        let public_key = peer.public_key().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "peer without a public key")
+        })?;
+
+        buf.push_str(&format!("public_key={}\n", public_key.to_hex()));
+
+        if peer.remove() {
+            buf.push_str("remove=true\n");
+            continue;
+        }
+
+        if peer.update_only() {
+            buf.push_str("update_only=true\n");
+        }
+
+        if let Some(key) = peer.preshared_key() {
+            buf.push_str(&format!("preshared_key={}\n", key.to_hex()));
+        }
+
+        if let Some(&(addr, port)) = peer.endpoint() {
+            buf.push_str(&format!("endpoint={}\n", SocketAddr::new(addr, port)));
+        }
+
+        if let Some(interval) = peer.persistent_keepalive() {
+            buf.push_str(&format!("persistent_keepalive_interval={}\n", interval));
+        }
+
+        if peer.replace_allowed_ips() {
+            buf.push_str("replace_allowed_ips=true\n");
+        }
+
+        for ip in peer.allowed_ips() {
+            buf.push_str(&format!("allowed_ip={}/{}\n", ip.addr(), ip.mask()));
+        }
+    }
+
+    buf.push('\n');
+
+    w.write_all(buf.as_bytes())?;
+    w.flush()
+}
+
+/// Read the response to a `set=1` request.
+pub fn read_set<R: BufRead>(r: R) -> Result<(), Error> {
+    let mut errno = None;
+
+    for line in Lines::new(r) {
+        let (key, value) = line?;
+
+        match key.as_str() {
+            "errno" => errno = Some(parse(&key, &value)?),
+            _ => return Err(Error::UnknownKey(key)),
+        }
+    }
+
+    check_errno(errno)
+}
+
+/// Read the response to a `get=1` request into a `Device` with the given name.
+pub fn read_get<R: BufRead>(name: &str, r: R) -> Result<Device, Error> {
+    let mut device = Device::new(name);
+    let mut peer: Option<Peer> = None;
+    let mut errno = None;
+    let mut handshake_sec = 0u64;
+    let mut handshake_nsec = 0u32;
+
+    for line in Lines::new(r) {
+        let (key, value) = line?;
+
+        if errno.is_some() {
+            return Err(Error::UnexpectedKey(key));
+        }
+
+        match (key.as_str(), peer.as_mut()) {
+            ("errno", _) => errno = Some(parse(&key, &value)?),
+
+            ("public_key", _) => {
+                if let Some(peer) = peer.take() {
+                    device.add_peer(finish_peer(peer, handshake_sec, handshake_nsec));
+                }
+
+                handshake_sec = 0;
+                handshake_nsec = 0;
+                peer = Some(Peer::new(parse_key(&key, &value)?, None));
+            }
+
+            ("private_key", None) => {
+                let private_key = parse_key(&key, &value)?;
+
+                if !private_key.is_zero() {
+                    device.set_private_key(private_key);
+                }
+            }
+
+            ("listen_port", None) => device.set_listen_port(parse(&key, &value)?),
+            ("fwmark", None) => {
+                let fwmark = parse(&key, &value)?;
+
+                if fwmark > 0 {
+                    device.set_fwmark(fwmark);
+                }
+            }
+
+            ("preshared_key", Some(peer)) => {
+                let preshared_key = parse_key(&key, &value)?;
+
+                if !preshared_key.is_zero() {
+                    peer.set_preshared_key(preshared_key);
+                }
+            }
+
+            ("endpoint", Some(peer)) => {
+                let addr = parse_endpoint(&value)
+                    .ok_or_else(|| Error::InvalidValue(key.clone(), value.clone()))?;
+
+                peer.set_endpoint((addr.ip(), addr.port()));
+            }
+
+            ("persistent_keepalive_interval", Some(peer)) => {
+                let interval = parse(&key, &value)?;
+
+                if interval > 0 {
+                    peer.set_persistent_keepalive(interval);
+                }
+            }
+
+            ("allowed_ip", Some(peer)) => {
+                let ip = value
+                    .parse::<AllowedIp>()
+                    .map_err(|_| Error::InvalidValue(key.clone(), value.clone()))?;
+
+                peer.add_allowed_ip(ip);
+            }
+
+            ("last_handshake_time_sec", Some(_)) => handshake_sec = parse(&key, &value)?,
+            ("last_handshake_time_nsec", Some(_)) => handshake_nsec = parse(&key, &value)?,
+
+            ("rx_bytes", Some(peer)) => {
+                let tx_bytes = peer.tx_bytes();
+                peer.set_transfer(parse(&key, &value)?, tx_bytes);
+            }
+
+            ("tx_bytes", Some(peer)) => {
+                let rx_bytes = peer.rx_bytes();
+                peer.set_transfer(rx_bytes, parse(&key, &value)?);
+            }
+
+            ("protocol_version", Some(_)) => {
+                if parse::<u32>(&key, &value)? != PROTOCOL_VERSION {
+                    return Err(Error::InvalidValue(key, value));
+                }
+            }
+
+            ("private_key", Some(_))
+            | ("listen_port", Some(_))
+            | ("fwmark", Some(_))
+            | ("preshared_key", None)
+            | ("endpoint", None)
+            | ("persistent_keepalive_interval", None)
+            | ("allowed_ip", None)
+            | ("last_handshake_time_sec", None)
+            | ("last_handshake_time_nsec", None)
+            | ("rx_bytes", None)
+            | ("tx_bytes", None)
+            | ("protocol_version", None) => return Err(Error::UnexpectedKey(key)),
+
+            _ => return Err(Error::UnknownKey(key)),
+        }
+    }
+
+    check_errno(errno)?;
+
+    if let Some(peer) = peer.take() {
+        device.add_peer(finish_peer(peer, handshake_sec, handshake_nsec));
+    }
+
+    Ok(device)
+}
+
+/// Apply the handshake time collected from the separate second and nanosecond keys.
+fn finish_peer(mut peer: Peer, sec: u64, nsec: u32) -> Peer {
+    if sec > 0 || nsec > 0 {
+        peer.set_last_handshake(Some(UNIX_EPOCH + Duration::new(sec, nsec)));
+    }
+
+    peer
+}
+
+/// Turn the value of the final `errno=` line into a result.
+fn check_errno(errno: Option<i32>) -> Result<(), Error> {
+    match errno {
+        Some(0) => Ok(()),
+        Some(errno) => Err(Error::Errno(errno)),
+        None => Err(Error::MissingErrno),
+    }
+}
+
+/// Parse a value with its `FromStr` implementation.
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidValue(key.to_owned(), value.to_owned()))
+}
+
+/// Parse a hex-encoded key.
+fn parse_key(key: &str, value: &str) -> Result<Key, Error> {
+    Key::from_hex(value).map_err(|_| Error::InvalidValue(key.to_owned(), value.to_owned()))
+}
+
+/// Parse an endpoint of the form `1.2.3.4:51820` or `[fe80::1%eth0]:51820`. The IPv6 scope
+/// identifier, if any, is dropped.
+pub(crate) fn parse_endpoint(value: &str) -> Option<SocketAddr> {
+    match (value.find('%'), value.find(']')) {
+        (Some(start), Some(end)) if start < end => {
+            let mut stripped = String::from(&value[..start]);
+            stripped.push_str(&value[end..]);
+
+            stripped.parse().ok()
+        }
+
+        _ => value.parse().ok(),
+    }
+}
+
+/// Iterator over the `key=value` lines of a response, ending at the first empty line.
+struct Lines<R> {
+    reader: R,
+    done: bool,
+}
+
+impl<R: BufRead> Lines<R> {
+    fn new(reader: R) -> Lines<R> {
+        Lines {
+            reader: reader,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = Result<(String, String), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut line = String::new();
+
+        match self.reader.read_line(&mut line) {
+            Ok(0) => {
+                self.done = true;
+                return Some(Err(Error::Io(io::ErrorKind::UnexpectedEof.into())));
+            }
+
+            Ok(_) => {}
+
+            Err(err) => {
+                self.done = true;
+                return Some(Err(Error::Io(err)));
+            }
+        }
+
+        let line = line.trim_end_matches('\n');
+
+        if line.is_empty() {
+            self.done = true;
+            return None;
+        }
+
+        let mut parts = line.splitn(2, '=');
+
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.is_empty() => {
+                Some(Ok((key.to_owned(), value.to_owned())))
+            }
+
+            _ => {
+                self.done = true;
+                Some(Err(Error::Malformed(line.to_owned())))
+            }
+        }
+    }
+}
+
+/// Errors that can happen when speaking the userspace API protocol.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error occured while reading the response.
+    Io(io::Error),
+    /// A line was not of the form `key=value`.
+    Malformed(String),
+    /// A key that is not part of the protocol.
+    UnknownKey(String),
+    /// A known key that is not valid at this point of the response.
+    UnexpectedKey(String),
+    /// A value that could not be parsed for the given key.
+    InvalidValue(String, String),
+    /// The response did not end with an `errno=` line.
+    MissingErrno,
+    /// The operation failed with the given error number.
+    Errno(i32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Malformed(line) => write!(f, "malformed line: {:?}", line),
+            Error::UnknownKey(key) => write!(f, "unknown key: {}", key),
+            Error::UnexpectedKey(key) => write!(f, "unexpected key: {}", key),
+            Error::InvalidValue(key, value) => write!(f, "invalid value for {}: {:?}", key, value),
+            Error::MissingErrno => write!(f, "missing errno in response"),
+            Error::Errno(errno) => write!(f, "{}", io::Error::from_raw_os_error(*errno)),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::Errno(errno) => io::Error::from_raw_os_error(errno),
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Polling of devices for changes of their peers.
+//!
+//! The kernel module sends no notifications, so a `Watcher` retrieves a device at a regular
+//! interval and compares it with the previous retrieval. It can be used as a blocking iterator of
+//! events, or as an async stream with the `tokio` feature.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "tokio")]
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+
+#[cfg(feature = "tokio")]
+use futures_core::Stream;
+
+#[cfg(feature = "tokio")]
+use crate::backend::AsyncBackend;
+use crate::backend::{Backend, System};
+use crate::device::Device;
+use crate::health;
+use crate::key::Key;
+use crate::peer::{Endpoint, Peer};
+
+/// Interval between two retrievals of the device, unless configured otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Age after which a handshake is considered stale, unless configured otherwise. This is
+/// `REJECT_AFTER_TIME`, after which the session keys of the handshake are no longer accepted.
+pub const DEFAULT_STALE_AFTER: Duration = health::REJECT_AFTER_TIME;
+
+/// A change noticed between two retrievals of a device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A peer appeared on the device.
+    PeerAdded(Peer),
+    /// A peer disappeared from the device, with its last known state.
+    PeerRemoved(Peer),
+    /// The endpoint of a peer changed, because it roamed or was reconfigured.
+    EndpointRoamed {
+        peer: Key,
+        from: Option<Endpoint>,
+        to: Endpoint,
+    },
+    /// A new handshake with a peer completed.
+    HandshakeCompleted { peer: Key, time: SystemTime },
+    /// The latest handshake with a peer became older than the stale threshold. Sent once, until
+    /// the next handshake.
+    HandshakeStale { peer: Key, last: SystemTime },
+    /// The transfer counters of a peer went backward, because it was removed and added back or
+    /// the device was recreated. Holds the counters before the reset.
+    CountersReset {
+        peer: Key,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    },
+}
+
+impl Event {
+    /// Get the public key of the peer this event is about.
+    pub fn public_key(&self) -> Option<&Key> {
+        match self {
+            Event::PeerAdded(peer) | Event::PeerRemoved(peer) => peer.public_key(),
+
+            Event::EndpointRoamed { peer, .. }
+            | Event::HandshakeCompleted { peer, .. }
+            | Event::HandshakeStale { peer, .. }
+            | Event::CountersReset { peer, .. } => Some(peer),
+        }
+    }
+}
+
+/// Polls a device and turns the differences between two retrievals into events.
+///
+/// The first retrieval only records the state of the device, so that events describe changes
+/// made while watching. Handshakes that are already stale are reported by it, though.
+#[derive(Debug)]
+pub struct Watcher<B = System> {
+    backend: B,
+    name: String,
+    interval: Duration,
+    stale_after: Duration,
+    peers: Option<HashMap<Key, Peer>>,
+    stale: HashSet<Key>,
+    pending: VecDeque<Event>,
+    next_poll: Option<Instant>,
+}
+
+impl Watcher {
+    /// Watch a device of this machine, kernel or userspace.
+    pub fn new<S: Into<String>>(name: S) -> Watcher {
+        Watcher::with_backend(System::new(), name)
+    }
+}
+
+impl<B> Watcher<B> {
+    /// Watch a device managed by the specified backend.
+    pub fn with_backend<S: Into<String>>(backend: B, name: S) -> Watcher<B> {
+        Watcher {
+            backend: backend,
+            name: name.into(),
+            interval: DEFAULT_INTERVAL,
+            stale_after: DEFAULT_STALE_AFTER,
+            peers: None,
+            stale: HashSet::new(),
+            pending: VecDeque::new(),
+            next_poll: None,
+        }
+    }
+
+    /// Set the interval between two retrievals when iterating. Defaults to one second.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Set the age after which a handshake is reported as stale. Defaults to three minutes.
+    pub fn set_stale_after(&mut self, stale_after: Duration) {
+        self.stale_after = stale_after;
+    }
+
+    /// Get the name of the watched device.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the interval between two retrievals when iterating.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Get the age after which a handshake is reported as stale.
+    pub fn stale_after(&self) -> Duration {
+        self.stale_after
+    }
+
+    /// Compare a new retrieval of the device with the previous one, at the time `now`.
+    pub fn update(&mut self, device: &Device, now: SystemTime) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut current = HashMap::with_capacity(device.peers().len());
+
+        for peer in device.peers() {
+            let key = match peer.public_key() {
+                Some(key) => key.clone(),
+                None => continue,
+            };
+
+            match self.peers.as_mut().map(|peers| peers.remove(&key)) {
+                Some(None) => events.push(Event::PeerAdded(peer.clone())),
+                Some(Some(previous)) => self.compare(&key, &previous, peer, &mut events),
+                None => {}
+            }
+
+            if let Some(last) = peer.last_handshake() {
+                let age = now.duration_since(last).unwrap_or_default();
+
+                if age > self.stale_after && self.stale.insert(key.clone()) {
+                    events.push(Event::HandshakeStale {
+                        peer: key.clone(),
+                        last: last,
+                    });
+                }
+            }
+
+            current.insert(key, peer.clone());
+        }
+
+        if let Some(removed) = self.peers.replace(current) {
+            for (key, peer) in removed {
+                self.stale.remove(&key);
+                events.push(Event::PeerRemoved(peer));
+            }
+        }
+
+        events
+    }
+
+    /// Compare two retrievals of the same peer.
+    fn compare(&mut self, key: &Key, previous: &Peer, peer: &Peer, events: &mut Vec<Event>) {
+        if let Some(&to) = peer.endpoint() {
+            if previous.endpoint() != Some(&to) {
+                events.push(Event::EndpointRoamed {
+                    peer: key.clone(),
+                    from: previous.endpoint().cloned(),
+                    to: to,
+                });
+            }
+        }
+
+        if let Some(time) = peer.last_handshake() {
+            if previous.last_handshake().is_none_or(|last| time > last) {
+                self.stale.remove(key);
+                events.push(Event::HandshakeCompleted {
+                    peer: key.clone(),
+                    time: time,
+                });
+            }
+        }
+
+        if peer.rx_bytes() < previous.rx_bytes() || peer.tx_bytes() < previous.tx_bytes() {
+            events.push(Event::CountersReset {
+                peer: key.clone(),
+                rx_bytes: previous.rx_bytes(),
+                tx_bytes: previous.tx_bytes(),
+            });
+        }
+    }
+
+    /// Get the time to wait for before the next retrieval, and schedule the one after it.
+    fn schedule(&mut self) -> Duration {
+        let now = Instant::now();
+        let wait = self
+            .next_poll
+            .map(|at| at.saturating_duration_since(now))
+            .unwrap_or_default();
+
+        self.next_poll = Some(now + wait + self.interval);
+
+        wait
+    }
+}
+
+impl<B: Backend> Watcher<B> {
+    /// Retrieve the device now and get the events since the previous retrieval.
+    pub fn poll(&mut self) -> io::Result<Vec<Event>> {
+        let device = self.backend.get(&self.name)?;
+        Ok(self.update(&device, SystemTime::now()))
+    }
+}
+
+/// Blocks until the next event. Failed retrievals are yielded as errors, and the iteration can go
+/// on after them.
+impl<B: Backend> Iterator for Watcher<B> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<io::Result<Event>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+
+            thread::sleep(self.schedule());
+
+            match self.poll() {
+                Ok(events) => self.pending.extend(events),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<B: AsyncBackend> Watcher<B> {
+    /// Retrieve the device now without blocking, and get the events since the previous retrieval.
+    pub async fn poll_async(&mut self) -> io::Result<Vec<Event>> {
+        let device = self.backend.get(&self.name).await?;
+        Ok(self.update(&device, SystemTime::now()))
+    }
+
+    /// Wait for the next event without blocking.
+    pub async fn next_async(&mut self) -> io::Result<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            tokio::time::sleep(self.schedule()).await;
+
+            let events = self.poll_async().await?;
+            self.pending.extend(events);
+        }
+    }
+
+    /// Turn the watcher into a stream of events.
+    pub fn into_stream(self) -> EventStream<B>
+    where
+        B: Send + Sync + 'static,
+    {
+        EventStream {
+            watcher: Some(self),
+            next: None,
+        }
+    }
+}
+
+/// The events of a `Watcher`, as an async stream. Like the iterator, it never ends and yields
+/// failed retrievals as errors.
+#[cfg(feature = "tokio")]
+pub struct EventStream<B> {
+    watcher: Option<Watcher<B>>,
+    #[allow(clippy::type_complexity)]
+    next: Option<Pin<Box<dyn Future<Output = (Watcher<B>, io::Result<Event>)> + Send>>>,
+}
+
+// The watcher is moved in and out of the boxed future, and never pinned in place.
+#[cfg(feature = "tokio")]
+impl<B> Unpin for EventStream<B> {}
+
+#[cfg(feature = "tokio")]
+impl<B: AsyncBackend + Send + Sync + 'static> Stream for EventStream<B> {
+    type Item = io::Result<Event>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.next.is_none() {
+            let mut watcher = self.watcher.take().expect("Watcher is missing");
+
+            self.next = Some(Box::pin(async move {
+                let event = watcher.next_async().await;
+                (watcher, event)
+            }));
+        }
+
+        match self.next.as_mut().map(|next| next.as_mut().poll(cx)) {
+            Some(Poll::Ready((watcher, event))) => {
+                self.next = None;
+                self.watcher = Some(watcher);
+
+                Poll::Ready(Some(event))
+            }
+
+            _ => Poll::Pending,
+        }
+    }
+}